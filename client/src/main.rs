@@ -1,11 +1,415 @@
 
 
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
-use ed25519_dalek::{Signer, SigningKey, Signature};
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use ed25519_dalek::{Signer, SigningKey, Signature, Verifier, VerifyingKey};
+use reqwest::{Client, RequestBuilder};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Number, Value};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use rand::{rngs::OsRng, RngCore};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const BASE: &str = "http://localhost:8080";
+const BASE_URL_ENV: &str = "POC_BASE_URL";
+// must match the server's POC_SIGNING_CONTEXT, else step 3 fails with invalid_signature
+const SIGNING_CONTEXT_ENV: &str = "POC_SIGNING_CONTEXT";
+const DEFAULT_BASE_URL: &str = "http://localhost:8080";
+
+// all four steps hit the same host, one idle connection is enough to reuse
+const POOL_MAX_IDLE_PER_HOST: usize = 1;
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+// per-request timeout, overridable with --timeout
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const PREFERENCES_SIGNATURE_HEADER: &str = "X-Preferences-Signature";
+// echoed by the server in responses, error bodies and its log
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+// detached Ed25519 signature over the response body and the kid of its key in /api/jwks,
+// sent when the server runs with POC_SIGN_RESPONSES=true
+const BODY_SIGNATURE_HEADER: &str = "X-Body-Signature";
+const BODY_SIGNATURE_KID_HEADER: &str = "X-Body-Signature-Kid";
+
+// --verify-responses: the server's response keys (kid, key), fetched once at startup.
+// Set before the first verified request, so send_json can check every response.
+static RESPONSE_KEYS: OnceLock<Vec<(String, VerifyingKey)>> = OnceLock::new();
+
+// must match the server: sorted keys, normalized numbers, no whitespace
+fn canonical_json(value: &Value) -> String {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            let fields: Vec<String> = keys
+                .into_iter()
+                .map(|k| format!("{}:{}", Value::String(k.clone()), canonical_json(&map[k])))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        }
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(canonical_json).collect();
+            format!("[{}]", items.join(","))
+        }
+        Value::Number(n) => canonical_number(n),
+        other => other.to_string(),
+    }
+}
+
+// Equal numbers print the same: 1, 1.0 and 1e0 all become "1" (and -0.0 becomes "0").
+fn canonical_number(n: &Number) -> String {
+    match n.as_f64() {
+        // exactly representable integers; larger ones keep their own digits
+        Some(f) if n.is_f64() && f.fract() == 0.0 && f.abs() < 9_007_199_254_740_992.0 => {
+            (f as i64).to_string()
+        }
+        Some(f) if n.is_f64() => f.to_string(),
+        _ => n.to_string(),
+    }
+}
+
+// -------- errors --------
+
+#[derive(Debug, thiserror::Error)]
+enum ClientError {
+    // connect, TLS, timeout, ...
+    #[error("request failed: {0}")]
+    Http(reqwest::Error),
+    // 2xx with a body that isn't the expected JSON
+    #[error("unexpected response body: {0}")]
+    Decode(String),
+    // non-2xx; `body` is the server's error JSON as received
+    #[error("server answered {code}: {body}")]
+    Status { code: u16, body: String },
+    // the issued private key could not be turned into a signing key
+    #[error("crypto: {0}")]
+    Crypto(String),
+}
+
+impl ClientError {
+    // stable name for JSON output
+    fn kind(&self) -> &'static str {
+        match self {
+            ClientError::Http(_) => "http",
+            ClientError::Decode(_) => "decode",
+            ClientError::Status { .. } => "status",
+            ClientError::Crypto(_) => "crypto",
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            ClientError::Decode(e.to_string())
+        } else {
+            ClientError::Http(e)
+        }
+    }
+}
+
+// Sends the request and parses a 2xx JSON body; other statuses keep the body for the error.
+// With --record the exchange is also appended to the transcript.
+async fn send_json<T: DeserializeOwned>(
+    transcript: Option<&Transcript>,
+    req: RequestBuilder,
+) -> Result<T, ClientError> {
+    let (http, request) = req.build_split();
+    let request = request?;
+    let recorded = transcript.map(|t| t.request(&request));
+
+    let resp = http.execute(request).await?;
+    let status = resp.status();
+    let header = |name: &str| {
+        resp.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let (body_sig, body_kid) = (header(BODY_SIGNATURE_HEADER), header(BODY_SIGNATURE_KID_HEADER));
+    let body = resp.bytes().await?;
+    if let (Some(t), Some(entry)) = (transcript, recorded) {
+        t.write(entry, status.as_u16(), &body);
+    }
+    if let Some(keys) = RESPONSE_KEYS.get() {
+        verify_body(keys, body_sig.as_deref(), body_kid.as_deref(), &body)?;
+    }
+
+    if !status.is_success() {
+        return Err(ClientError::Status {
+            code: status.as_u16(),
+            body: String::from_utf8_lossy(&body).into_owned(),
+        });
+    }
+    serde_json::from_slice(&body).map_err(|e| ClientError::Decode(e.to_string()))
+}
+
+// Checks X-Body-Signature against the key named by X-Body-Signature-Kid. Error bodies are
+// checked too: an intermediary could rewrite those as well.
+fn verify_body(
+    keys: &[(String, VerifyingKey)],
+    signature: Option<&str>,
+    kid: Option<&str>,
+    body: &[u8],
+) -> Result<(), ClientError> {
+    let (Some(signature), Some(kid)) = (signature, kid) else {
+        return Err(ClientError::Crypto("response is not signed".into()));
+    };
+    let key = keys
+        .iter()
+        .find(|(k, _)| k == kid)
+        .map(|(_, key)| key)
+        .ok_or_else(|| ClientError::Crypto(format!("response signed with unknown key {kid}")))?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .ok()
+        .and_then(|b| Signature::from_slice(&b).ok())
+        .ok_or_else(|| ClientError::Crypto("response signature is malformed".into()))?;
+    key.verify(body, &signature)
+        .map_err(|_| ClientError::Crypto("response body does not match its signature".into()))
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kid: String,
+    x: String,
+}
+
+// The keys are trusted as first fetched: use --pin or a verified TLS chain so this request
+// itself can't be tampered with.
+async fn fetch_response_keys(http: &Client, args: &Args) -> Result<Vec<(String, VerifyingKey)>, ClientError> {
+    let jwks: Jwks = send_json(
+        args.transcript.as_ref(),
+        http.get(format!("{}/api/jwks", args.base_url)),
+    )
+    .await?;
+    if jwks.keys.is_empty() {
+        return Err(ClientError::Crypto(
+            "server does not sign responses (POC_SIGN_RESPONSES is off)".into(),
+        ));
+    }
+    jwks.keys
+        .into_iter()
+        .map(|jwk| {
+            let key = URL_SAFE_NO_PAD
+                .decode(&jwk.x)
+                .ok()
+                .and_then(|b| <[u8; 32]>::try_from(b).ok())
+                .and_then(|b| VerifyingKey::from_bytes(&b).ok())
+                .ok_or_else(|| ClientError::Crypto(format!("invalid key {} in /api/jwks", jwk.kid)))?;
+            Ok((jwk.kid, key))
+        })
+        .collect()
+}
+
+// -------- transcript --------
+
+// One line of a --record file. Bodies are kept as the exact text sent/received,
+// except that `credential_private` in responses is redacted.
+#[derive(Serialize, Deserialize)]
+struct TranscriptEntry {
+    // since the first request of the run
+    at_ms: f64,
+    method: String,
+    // relative to the base URL, so --replay can target another server
+    path: String,
+    headers: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    status: u16,
+    response: String,
+}
+
+struct Transcript {
+    file: Mutex<File>,
+    base_url: String,
+    started: Instant,
+}
+
+impl Transcript {
+    fn create(path: &str, base_url: &str) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("--record {path}: {e}"))?;
+        Ok(Transcript {
+            file: Mutex::new(file),
+            base_url: base_url.to_string(),
+            started: Instant::now(),
+        })
+    }
+
+    fn request(&self, request: &reqwest::Request) -> TranscriptEntry {
+        let url = request.url().as_str();
+        TranscriptEntry {
+            at_ms: elapsed_ms(self.started),
+            method: request.method().to_string(),
+            path: url.strip_prefix(&self.base_url).unwrap_or(url).to_string(),
+            headers: request
+                .headers()
+                .iter()
+                .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+                .collect(),
+            body: request
+                .body()
+                .and_then(|b| b.as_bytes())
+                .map(|b| String::from_utf8_lossy(b).into_owned()),
+            status: 0,
+            response: String::new(),
+        }
+    }
+
+    fn write(&self, mut entry: TranscriptEntry, status: u16, body: &[u8]) {
+        entry.status = status;
+        entry.response = redact_private(body);
+        let Ok(line) = serde_json::to_string(&entry) else {
+            return;
+        };
+        let mut file = self.file.lock().expect("transcript mutex poisoned");
+        if let Err(e) = writeln!(file, "{line}") {
+            eprintln!("warning: transcript write failed: {e}");
+        }
+    }
+}
+
+fn redact_private(body: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Object(mut map)) if map.contains_key("credential_private") => {
+            map.insert("credential_private".into(), Value::from("[redacted]"));
+            Value::Object(map).to_string()
+        }
+        _ => String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+// Re-sends every recorded request to the current base URL and compares statuses.
+// Tokens in the transcript are from the original run, so later steps usually fail
+// unless the server state matches; the point is reproducing the exact bytes.
+async fn replay(http: &Client, args: &Args, path: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("--replay {path}: {e}"))?;
+    let mut previous_at: Option<f64> = None;
+    let mut all_matched = true;
+
+    for (i, line) in text.lines().filter(|l| !l.trim().is_empty()).enumerate() {
+        let entry: TranscriptEntry =
+            serde_json::from_str(line).map_err(|e| format!("--replay {path}: entry {}: {e}", i + 1))?;
+
+        if args.replay_delays
+            && let Some(prev) = previous_at
+        {
+            let wait = (entry.at_ms - prev).max(0.0) / 1000.0;
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+        }
+        previous_at = Some(entry.at_ms);
+
+        let method = reqwest::Method::from_bytes(entry.method.as_bytes())?;
+        let mut req = http.request(method, format!("{}{}", args.base_url, entry.path));
+        for (name, value) in &entry.headers {
+            req = req.header(name, value);
+        }
+        if let Some(body) = &entry.body {
+            req = req.body(body.clone());
+        }
+
+        let status = match req.send().await {
+            Ok(resp) => resp.status().as_u16(),
+            Err(e) => {
+                println!("{} {} -> error: {e}", entry.method, entry.path);
+                all_matched = false;
+                continue;
+            }
+        };
+        let matched = status == entry.status;
+        all_matched &= matched;
+        if args.pretty() {
+            let note = if matched { "" } else { " (differs)" };
+            println!(
+                "{} {} -> {status}, recorded {}{note}",
+                entry.method, entry.path, entry.status
+            );
+        } else {
+            println!(
+                "{}",
+                serde_json::json!({
+                    "method": entry.method,
+                    "path": entry.path,
+                    "status": status,
+                    "recorded_status": entry.status,
+                })
+            );
+        }
+    }
+    Ok(all_matched)
+}
+
+// signed payload for step 3; the server verifies its canonical_json
+const PAYLOAD_VERSION: u64 = 1;
+const PAYLOAD_TTL: Duration = Duration::from_secs(60);
+
+// `audience`: the server's POC_AUDIENCE, from /api/capabilities
+fn enter_payload(credential_id: &str, audience: Option<&str>) -> Value {
+    let mut nonce = [0u8; 16];
+    OsRng.fill_bytes(&mut nonce);
+    let exp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| (d + PAYLOAD_TTL).as_secs())
+        .unwrap_or(0);
+    let mut payload = serde_json::json!({
+        "v": PAYLOAD_VERSION,
+        "purpose": "enter",
+        "credential_id": credential_id,
+        "nonce": URL_SAFE_NO_PAD.encode(nonce),
+        "exp": exp,
+    });
+    if let Some(aud) = audience {
+        payload["aud"] = Value::from(aud);
+    }
+    payload
+}
+
+// context || 0x00 || message when POC_SIGNING_CONTEXT is set, as the server verifies it
+fn with_signing_context(message: &[u8]) -> Vec<u8> {
+    let context = std::env::var(SIGNING_CONTEXT_ENV).unwrap_or_default();
+    if context.is_empty() {
+        return message.to_vec();
+    }
+    let mut out = Vec::with_capacity(context.len() + 1 + message.len());
+    out.extend_from_slice(context.as_bytes());
+    out.push(0);
+    out.extend_from_slice(message);
+    out
+}
+
+// Smallest counter nonce whose SHA-256(challenge ":" nonce) has `difficulty` leading zero bits.
+fn solve_pow(challenge: &str, difficulty: u32) -> String {
+    (0u64..)
+        .map(|n| n.to_string())
+        .find(|nonce| {
+            let digest = Sha256::new()
+                .chain_update(challenge.as_bytes())
+                .chain_update(b":")
+                .chain_update(nonce.as_bytes())
+                .finalize();
+            let mut bits = 0;
+            for b in digest {
+                bits += b.leading_zeros();
+                if b != 0 {
+                    break;
+                }
+            }
+            bits >= difficulty
+        })
+        .expect("nonce space exhausted")
+}
 
 // -------- DTO клиента --------
 
@@ -13,6 +417,23 @@ const BASE: &str = "http://localhost:8080";
 struct VerifyUserRequest {
     username: String,
     code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pow_challenge: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pow_nonce: Option<String>,
+}
+
+// Only what the flow needs from /api/capabilities.
+#[derive(Deserialize)]
+struct Capabilities {
+    #[serde(default)]
+    audience: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PowChallengeResponse {
+    challenge: String,
+    difficulty: u32,
 }
 
 #[derive(Deserialize)]
@@ -43,82 +464,707 @@ struct EnterSessionResponse {
     session_token: String,
 }
 
+// -------- CLI --------
+
+#[derive(Clone, Copy, PartialEq)]
+enum OutputFormat {
+    // human-readable lines while the flow runs (default)
+    Pretty,
+    // one JSON object on stdout at the end, for scripts and CI
+    Json,
+}
+
+struct Args {
+    // --base-url, else POC_BASE_URL, else DEFAULT_BASE_URL; no trailing slash
+    base_url: String,
+    output: OutputFormat,
+    // include credential_private in JSON output
+    show_private: bool,
+    // print the per-step latency breakdown in pretty mode
+    timings: bool,
+    runs: Runs,
+    // pause between --loop iterations
+    interval: Duration,
+    // --pin sha256:<hex>: SHA-256 of the server's DER certificate
+    pin: Option<[u8; 32]>,
+    timeout: Duration,
+    // --check: only probe that the server answers
+    check: bool,
+    // --record <file>: append every exchange to this transcript
+    transcript: Option<Transcript>,
+    // --replay <file>: re-send a transcript instead of running the flow
+    replay: Option<String>,
+    // --replay-delays: keep the recorded gaps between requests
+    replay_delays: bool,
+    // --pow: solve the server's proof-of-work challenge before step 1 (POC_POW)
+    pow: bool,
+    // --sign-only: sign --message locally and print the signature, no server calls
+    sign_only: Option<SignOnly>,
+    // --verify-responses: require a valid X-Body-Signature on every response
+    verify_responses: bool,
+}
+
+struct SignOnly {
+    // --message <text>, signed as its UTF-8 bytes
+    message: String,
+    key: KeySource,
+}
+
+enum KeySource {
+    // --private <base64url seed>
+    Private(String),
+    // --credential-file <file>: JSON with a credential_private field, e.g. the step 2
+    // response or the report from --output json --show-private
+    File(String),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Runs {
+    Once,
+    // --loop N
+    Times(u64),
+    // --loop without a count, until Ctrl-C
+    Forever,
+}
+
+const DEFAULT_LOOP_INTERVAL: Duration = Duration::from_secs(1);
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut base_url = std::env::var(BASE_URL_ENV).ok();
+        let mut args = Args {
+            base_url: String::new(),
+            output: OutputFormat::Pretty,
+            show_private: false,
+            timings: false,
+            runs: Runs::Once,
+            interval: DEFAULT_LOOP_INTERVAL,
+            pin: None,
+            timeout: DEFAULT_TIMEOUT,
+            check: false,
+            transcript: None,
+            replay: None,
+            replay_delays: false,
+            pow: false,
+            sign_only: None,
+            verify_responses: false,
+        };
+        let mut record = None;
+        let (mut sign_only, mut message, mut key) = (false, None, None);
+        let mut it = std::env::args().skip(1).peekable();
+        while let Some(arg) = it.next() {
+            match arg.as_str() {
+                "--output" => {
+                    args.output = match it.next().as_deref() {
+                        Some("pretty") => OutputFormat::Pretty,
+                        Some("json") => OutputFormat::Json,
+                        other => return Err(format!("--output expects pretty or json, got {other:?}")),
+                    }
+                }
+                "--show-private" => args.show_private = true,
+                "--timings" => args.timings = true,
+                "--loop" => {
+                    args.runs = match it.peek().and_then(|n| n.parse::<u64>().ok()) {
+                        Some(n) => {
+                            it.next();
+                            Runs::Times(n)
+                        }
+                        None => Runs::Forever,
+                    }
+                }
+                "--interval" => {
+                    let secs = it
+                        .next()
+                        .and_then(|s| s.parse::<f64>().ok())
+                        .filter(|s| s.is_finite() && *s >= 0.0)
+                        .ok_or("--interval expects a number of seconds")?;
+                    args.interval = Duration::from_secs_f64(secs);
+                }
+                "--timeout" => {
+                    let secs = it
+                        .next()
+                        .and_then(|s| s.parse::<f64>().ok())
+                        .filter(|s| s.is_finite() && *s > 0.0)
+                        .ok_or("--timeout expects a positive number of seconds")?;
+                    args.timeout = Duration::from_secs_f64(secs);
+                }
+                "--check" => args.check = true,
+                "--record" => record = Some(it.next().ok_or("--record expects a file")?),
+                "--replay" => args.replay = Some(it.next().ok_or("--replay expects a file")?),
+                "--replay-delays" => args.replay_delays = true,
+                "--pow" => args.pow = true,
+                "--verify-responses" => args.verify_responses = true,
+                "--sign-only" => sign_only = true,
+                "--message" => message = Some(it.next().ok_or("--message expects a text")?),
+                "--private" | "--credential-file" => {
+                    if key.is_some() {
+                        return Err("give only one of --private and --credential-file".into());
+                    }
+                    let value = it.next().ok_or_else(|| format!("{arg} expects a value"))?;
+                    key = Some(match arg.as_str() {
+                        "--private" => KeySource::Private(value),
+                        _ => KeySource::File(value),
+                    });
+                }
+                "--base-url" => base_url = Some(it.next().ok_or("--base-url expects a URL")?),
+                "--pin" => {
+                    let pin = it.next().ok_or("--pin expects sha256:<hex>")?;
+                    args.pin = Some(parse_pin(&pin)?);
+                }
+                other => return Err(format!("unknown argument {other:?}")),
+            }
+        }
+        match (sign_only, message, key) {
+            (true, Some(message), Some(key)) => args.sign_only = Some(SignOnly { message, key }),
+            (true, None, _) => return Err("--sign-only needs --message".into()),
+            (true, _, None) => return Err("--sign-only needs --private or --credential-file".into()),
+            (false, None, None) => {}
+            (false, ..) => {
+                return Err("--message, --private and --credential-file are for --sign-only".into())
+            }
+        }
+        args.base_url = validate_base_url(base_url.as_deref().unwrap_or(DEFAULT_BASE_URL))?;
+        if let Some(path) = record {
+            args.transcript = Some(Transcript::create(&path, &args.base_url)?);
+        }
+        Ok(args)
+    }
+
+    fn pretty(&self) -> bool {
+        self.output == OutputFormat::Pretty
+    }
+
+    // step-by-step lines; --loop only prints one line per iteration
+    fn verbose(&self) -> bool {
+        self.pretty() && self.runs == Runs::Once
+    }
+}
+
+// Must be an absolute http(s) URL. Plain http to anything but loopback is allowed with a
+// warning: step 2 sends the credential private key in that response.
+fn validate_base_url(raw: &str) -> Result<String, String> {
+    let url = reqwest::Url::parse(raw.trim())
+        .map_err(|e| format!("base URL {raw:?} is not a valid URL: {e}"))?;
+    match url.scheme() {
+        "https" => {}
+        "http" => {
+            let host = url.host_str().unwrap_or_default();
+            if !matches!(host, "localhost" | "127.0.0.1" | "[::1]") {
+                eprintln!(
+                    "warning: {url} is plain http; the credential private key travels unencrypted"
+                );
+            }
+        }
+        other => return Err(format!("base URL must use http or https, not {other:?}")),
+    }
+    Ok(url.as_str().trim_end_matches('/').to_string())
+}
+
+// -------- TLS pinning --------
+
+// Accepts `sha256:` followed by 64 hex digits, optionally colon-separated
+// (the `openssl x509 -noout -fingerprint -sha256` format).
+fn parse_pin(raw: &str) -> Result<[u8; 32], String> {
+    let hex: String = raw
+        .strip_prefix("sha256:")
+        .ok_or("--pin must start with sha256:")?
+        .chars()
+        .filter(|c| *c != ':')
+        .collect();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err("--pin expects 32 bytes of hex after sha256:".into());
+    }
+    let mut pin = [0u8; 32];
+    for (i, byte) in pin.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("--pin: invalid hex {:?}", &hex[i * 2..i * 2 + 2]))?;
+    }
+    Ok(pin)
+}
+
+// With --pin the fingerprint is the only trust anchor: a certificate that chains to a
+// public CA but has another fingerprint is refused, and a self-signed one with the
+// right fingerprint is accepted. Handshake signatures are still checked against it.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    pin: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint: [u8; 32] = Sha256::digest(end_entity.as_ref()).into();
+        if fingerprint == self.pin {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "server certificate does not match --pin".into(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn pinned_tls_config(pin: [u8; 32]) -> Result<rustls::ClientConfig, rustls::Error> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    Ok(rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier { pin, provider }))
+        .with_no_client_auth())
+}
+
+// Everything the flow produced, printed as-is in --output json mode.
+#[derive(Serialize)]
+struct FlowReport {
+    verification_token: String,
+    credential_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credential_private: Option<String>,
+    session_token: String,
+    preferences: Value,
+    timings: Timings,
+    request_ids: RequestIds,
+}
+
+// X-Request-Id sent with each call, to find the matching server log lines.
+#[derive(Serialize, Default)]
+struct RequestIds {
+    verify: String,
+    issue_credentials: String,
+    enter: String,
+    preferences: String,
+}
+
+fn new_request_id() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// Wall-clock milliseconds per call; `signing` covers both Ed25519 signatures.
+#[derive(Serialize, Default)]
+struct Timings {
+    verify_ms: f64,
+    issue_credentials_ms: f64,
+    enter_ms: f64,
+    preferences_ms: f64,
+    signing_ms: f64,
+    total_ms: f64,
+}
+
+fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
+impl Timings {
+    fn print(&self, ids: &RequestIds) {
+        println!("\ntimings:");
+        println!("  step1 verify            {:8.1} ms  [{}]", self.verify_ms, ids.verify);
+        println!("  step2 issue-credentials {:8.1} ms  [{}]", self.issue_credentials_ms, ids.issue_credentials);
+        println!("  step3 enter             {:8.1} ms  [{}]", self.enter_ms, ids.enter);
+        println!("  step4 preferences       {:8.1} ms  [{}]", self.preferences_ms, ids.preferences);
+        println!("  signing                 {:8.1} ms", self.signing_ms);
+        println!("  total                   {:8.1} ms", self.total_ms);
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let http = Client::new();
+    let args = Args::parse()?;
+    if let Some(job) = &args.sign_only {
+        return sign_only(job, args.output);
+    }
+    let mut builder = Client::builder()
+        .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .timeout(args.timeout);
+    // without a pin the default TLS stack verifies the chain as usual
+    if let Some(pin) = args.pin {
+        builder = builder.use_preconfigured_tls(pinned_tls_config(pin)?);
+    }
+    let http = builder.build()?;
+
+    if args.verify_responses {
+        let keys = fetch_response_keys(&http, &args).await?;
+        let _ = RESPONSE_KEYS.set(keys);
+    }
+
+    if let Some(path) = &args.replay {
+        if !replay(&http, &args, path).await? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if args.check {
+        if !health_check(&http, &args).await {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if args.runs != Runs::Once {
+        let summary = run_loop(&http, &args).await;
+        summary.print(&args);
+        if summary.failed > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let report = run_flow(&http, &args).await?;
+
+    if args.pretty() {
+        if args.timings {
+            report.timings.print(&report.request_ids);
+        }
+        println!("\nFlow complete ✅");
+    } else {
+        println!("{}", serde_json::to_string(&report)?);
+    }
+    Ok(())
+}
+
+// reconstruct SigningKey from seed(32 bytes)
+fn signing_key_from_private(private: &str) -> Result<SigningKey, ClientError> {
+    let seed_bytes = URL_SAFE_NO_PAD
+        .decode(private.trim().as_bytes())
+        .map_err(|e| ClientError::Crypto(format!("private key is not base64url: {e}")))?;
+    let seed: [u8; 32] = seed_bytes.try_into().map_err(|b: Vec<u8>| {
+        ClientError::Crypto(format!("invalid private key length: {} bytes, expected 32", b.len()))
+    })?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+// -------- sign only --------
+
+// Signs the message exactly as step 3 signs its payload (including POC_SIGNING_CONTEXT),
+// so another verifier can be checked against this output. Never contacts the server.
+fn sign_only(job: &SignOnly, output: OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    if job.message.is_empty() {
+        return Err("--message must not be empty".into());
+    }
+    let private = match &job.key {
+        KeySource::Private(private) => private.clone(),
+        KeySource::File(path) => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| format!("--credential-file {path}: {e}"))?;
+            let value: Value = serde_json::from_str(&text)
+                .map_err(|e| format!("--credential-file {path}: {e}"))?;
+            value
+                .get("credential_private")
+                .and_then(Value::as_str)
+                .ok_or_else(|| format!("--credential-file {path}: no credential_private string"))?
+                .to_string()
+        }
+    };
+    let signing_key = signing_key_from_private(&private)?;
+    let sig: Signature = signing_key.sign(&with_signing_context(job.message.as_bytes()));
+    let sig_b64 = URL_SAFE_NO_PAD.encode(sig.to_bytes());
+    match output {
+        OutputFormat::Pretty => println!("{sig_b64}"),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::json!({
+                "signature": sig_b64,
+                "public_key": URL_SAFE_NO_PAD.encode(signing_key.verifying_key().to_bytes()),
+            })
+        ),
+    }
+    Ok(())
+}
+
+// -------- health check --------
+
+// The server has no dedicated /health route; /api/version is cheap and touches no state.
+async fn health_check(http: &Client, args: &Args) -> bool {
+    let base = &args.base_url;
+    let started = Instant::now();
+    let result = send_json::<Value>(args.transcript.as_ref(), http.get(format!("{base}/api/version"))).await;
+    let latency_ms = elapsed_ms(started);
+
+    match (&result, args.pretty()) {
+        (Ok(_), true) => println!("{base}: reachable ({latency_ms:.1} ms)"),
+        (Err(e), true) => println!("{base}: unreachable ({latency_ms:.1} ms): {e}"),
+        (Ok(_), false) => println!(
+            "{}",
+            serde_json::json!({ "reachable": true, "latency_ms": latency_ms })
+        ),
+        (Err(e), false) => println!(
+            "{}",
+            serde_json::json!({
+                "reachable": false,
+                "latency_ms": latency_ms,
+                "kind": e.kind(),
+                "error": e.to_string(),
+            })
+        ),
+    }
+    result.is_ok()
+}
+
+// -------- soak loop --------
+
+#[derive(Default, Serialize)]
+struct LoopSummary {
+    runs: u64,
+    ok: u64,
+    failed: u64,
+    interrupted: bool,
+}
+
+impl LoopSummary {
+    fn print(&self, args: &Args) {
+        if args.pretty() {
+            let note = if self.interrupted { " (interrupted)" } else { "" };
+            println!(
+                "\n{} runs: {} ok, {} failed{note}",
+                self.runs, self.ok, self.failed
+            );
+        } else {
+            println!("{}", serde_json::json!({ "summary": self }));
+        }
+    }
+}
+
+// Runs the flow until the count is reached or Ctrl-C; either way the summary is returned.
+async fn run_loop(http: &Client, args: &Args) -> LoopSummary {
+    let mut summary = LoopSummary::default();
+    summary.interrupted = tokio::select! {
+        _ = soak(http, args, &mut summary) => false,
+        _ = tokio::signal::ctrl_c() => true,
+    };
+    summary
+}
+
+async fn soak(http: &Client, args: &Args, summary: &mut LoopSummary) {
+    loop {
+        if let Runs::Times(n) = args.runs
+            && summary.runs >= n
+        {
+            return;
+        }
+        if summary.runs > 0 {
+            tokio::time::sleep(args.interval).await;
+        }
+
+        summary.runs += 1;
+        match run_flow(http, args).await {
+            Ok(report) => {
+                summary.ok += 1;
+                if args.pretty() {
+                    println!("run {}: ok ({:.1} ms)", summary.runs, report.timings.total_ms);
+                } else {
+                    println!("{}", serde_json::to_string(&report).unwrap_or_default());
+                }
+            }
+            Err(e) => {
+                summary.failed += 1;
+                if args.pretty() {
+                    println!("run {}: failed: {e}", summary.runs);
+                } else {
+                    println!(
+                        "{}",
+                        serde_json::json!({ "run": summary.runs, "kind": e.kind(), "error": e.to_string() })
+                    );
+                }
+            }
+        }
+    }
+}
+
+async fn run_flow(http: &Client, args: &Args) -> Result<FlowReport, ClientError> {
+    let base = &args.base_url;
+    let mut timings = Timings::default();
+    let ids = RequestIds {
+        verify: new_request_id(),
+        issue_credentials: new_request_id(),
+        enter: new_request_id(),
+        preferences: new_request_id(),
+    };
+    let flow_start = Instant::now();
+
+    // the audience to sign for, if the server has one
+    let caps: Capabilities = send_json(
+        args.transcript.as_ref(),
+        http.get(format!("{base}/api/capabilities")),
+    )
+    .await?;
 
     // 1) verify
-    let v: VerifyUserResponse = http
-        .post(format!("{BASE}/api/step1/verify"))
-        .json(&VerifyUserRequest {
-            username: "alice".into(),
-            code: "123456".into(),
-        })
-        .send()
-        .await?
-        .error_for_status()?
-        .json()
+    let started = Instant::now();
+    let (pow_challenge, pow_nonce) = if args.pow {
+        let pow: PowChallengeResponse = send_json(
+            args.transcript.as_ref(),
+            http.get(format!("{base}/api/step1/pow"))
+                .header(REQUEST_ID_HEADER, &ids.verify),
+        )
         .await?;
+        let nonce = solve_pow(&pow.challenge, pow.difficulty);
+        (Some(pow.challenge), Some(nonce))
+    } else {
+        (None, None)
+    };
+    let v: VerifyUserResponse = send_json(
+        args.transcript.as_ref(),
+        http
+            .post(format!("{base}/api/step1/verify"))
+            .header(REQUEST_ID_HEADER, &ids.verify)
+            .json(&VerifyUserRequest {
+                username: "alice".into(),
+                code: "123456".into(),
+                pow_challenge,
+                pow_nonce,
+            }),
+    )
+    .await?;
+    timings.verify_ms = elapsed_ms(started);
 
-    println!("verification_token: {}", v.verification_token);
+    if args.verbose() {
+        println!("verification_token: {}", v.verification_token);
+    }
 
     // 2) issue temporary credentials
-    let c: IssueTemporaryCredentialsResponse = http
-        .post(format!("{BASE}/api/step2/issue-credentials"))
-        .json(&IssueTemporaryCredentialsRequest {
-            verification_token: v.verification_token.clone(),
-        })
-        .send()
-        .await?
-        .error_for_status()?
-        .json()
-        .await?;
+    let started = Instant::now();
+    let c: IssueTemporaryCredentialsResponse = send_json(
+        args.transcript.as_ref(),
+        http
+            .post(format!("{base}/api/step2/issue-credentials"))
+            .header(REQUEST_ID_HEADER, &ids.issue_credentials)
+            .json(&IssueTemporaryCredentialsRequest {
+                verification_token: v.verification_token.clone(),
+            }),
+    )
+    .await?;
+    timings.issue_credentials_ms = elapsed_ms(started);
 
-    println!("credential_id: {}", c.credential_id);
-    println!("credential_private (client-held): {}", c.credential_private);
+    if args.verbose() {
+        println!("credential_id: {}", c.credential_id);
+        println!("credential_private (client-held): {}", c.credential_private);
+    }
 
-    // reconstruct SigningKey from seed(32 bytes)
-    let seed_bytes = URL_SAFE_NO_PAD.decode(c.credential_private.as_bytes())?;
-    let seed: [u8; 32] = seed_bytes
-        .try_into()
-        .map_err(|_| "invalid private key length")?;
-    let signing_key = SigningKey::from_bytes(&seed);
+    let signing_key = signing_key_from_private(&c.credential_private)?;
 
-    // 3) sign + enter session
-    let message = "hello-proof";
-    let sig: Signature = signing_key.sign(message.as_bytes());
+    // 3) sign + enter session, over a v1 payload in canonical form
+    let message = canonical_json(&enter_payload(&c.credential_id, caps.audience.as_deref()));
+    let started = Instant::now();
+    let sig: Signature = signing_key.sign(&with_signing_context(message.as_bytes()));
+    timings.signing_ms += elapsed_ms(started);
     let sig_b64 = URL_SAFE_NO_PAD.encode(sig.to_bytes());
 
-    let s: EnterSessionResponse = http
-        .post(format!("{BASE}/step3/enter"))
-        .json(&EnterSessionRequest {
-            credential_id: c.credential_id.clone(),
-            message: message.into(),
-            signature: sig_b64,
-        })
-        .send()
-        .await?
-        .error_for_status()?
-        .json()
-        .await?;
+    let started = Instant::now();
+    let s: EnterSessionResponse = send_json(
+        args.transcript.as_ref(),
+        http
+            .post(format!("{base}/step3/enter"))
+            .header(REQUEST_ID_HEADER, &ids.enter)
+            .json(&EnterSessionRequest {
+                credential_id: c.credential_id.clone(),
+                message,
+                signature: sig_b64,
+            }),
+    )
+    .await?;
+    timings.enter_ms = elapsed_ms(started);
 
-    println!("session_token: {}", s.session_token);
-
-    // 4) preferences
-    let pref = http
-        .post(format!("{BASE}/api/user/preferences"))
-        .json(&serde_json::json!({
-            "theme": "dark",
-            "notifications": true
-        }))
-        .send()
-        .await?
-        .error_for_status()?
-        .json::<serde_json::Value>()
-        .await?;
+    if args.verbose() {
+        println!("session_token: {}", s.session_token);
+    }
 
-    println!("preferences response: {pref}");
+    // 4) preferences, signed with the credential key
+    let prefs = serde_json::json!({
+        "theme": "dark",
+        "notifications": true
+    });
+    let started = Instant::now();
+    let prefs_sig: Signature = signing_key.sign(canonical_json(&prefs).as_bytes());
+    timings.signing_ms += elapsed_ms(started);
 
-    println!("\nFlow complete ✅");
-    Ok(())
+    let started = Instant::now();
+    let pref: Value = send_json(
+        args.transcript.as_ref(),
+        http
+            .post(format!("{base}/api/user/preferences"))
+            .header(REQUEST_ID_HEADER, &ids.preferences)
+            .bearer_auth(&s.session_token)
+            .header(
+                PREFERENCES_SIGNATURE_HEADER,
+                URL_SAFE_NO_PAD.encode(prefs_sig.to_bytes()),
+            )
+            .json(&prefs),
+    )
+    .await?;
+    timings.preferences_ms = elapsed_ms(started);
+    timings.total_ms = elapsed_ms(flow_start);
+
+    if args.verbose() {
+        println!("preferences response: {pref}");
+    }
+
+    Ok(FlowReport {
+        verification_token: v.verification_token,
+        credential_id: c.credential_id,
+        credential_private: args.show_private.then_some(c.credential_private),
+        session_token: s.session_token,
+        preferences: pref,
+        timings,
+        request_ids: ids,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed(key: &SigningKey, body: &[u8]) -> String {
+        URL_SAFE_NO_PAD.encode(key.sign(body).to_bytes())
+    }
+
+    #[test]
+    fn verify_body_accepts_only_the_signed_bytes_under_a_known_kid() {
+        let key = SigningKey::generate(&mut OsRng);
+        let keys = vec![("kid".to_string(), key.verifying_key())];
+        let body = br#"{"ok":true}"#;
+        let signature = signed(&key, body);
+
+        assert!(verify_body(&keys, Some(&signature), Some("kid"), body).is_ok());
+        assert!(verify_body(&keys, Some(&signature), Some("kid"), br#"{"ok":false}"#).is_err());
+        assert!(verify_body(&keys, Some(&signature), Some("other"), body).is_err());
+        assert!(verify_body(&keys, None, Some("kid"), body).is_err());
+        assert!(verify_body(&keys, Some("not base64!"), Some("kid"), body).is_err());
+
+        let stranger = SigningKey::generate(&mut OsRng);
+        assert!(verify_body(&keys, Some(&signed(&stranger, body)), Some("kid"), body).is_err());
+    }
 }
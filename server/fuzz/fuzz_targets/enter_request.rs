@@ -0,0 +1,28 @@
+// Step 3 body -> signature / public key decoding, as enter_session_with_credential does it.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use serde_json::Value;
+
+#[path = "../../src/parse.rs"]
+#[allow(dead_code)]
+mod parse;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(Value::Object(body)) = serde_json::from_slice::<Value>(data) {
+        for field in ["signature", "new_public_key", "message"] {
+            if let Some(text) = body.get(field).and_then(Value::as_str) {
+                let _ = parse::decode_signature(text);
+                let _ = parse::decode_public_key(text);
+            }
+        }
+    }
+
+    // and the raw bytes as a header-style value
+    if let Ok(text) = std::str::from_utf8(data) {
+        if let Ok(signature) = parse::decode_signature(text) {
+            assert_eq!(signature.to_bytes().len(), 64);
+        }
+        let _ = parse::decode_public_key(text);
+    }
+});
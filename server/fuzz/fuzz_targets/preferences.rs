@@ -0,0 +1,41 @@
+// Preferences validation and the canonical form used for signed preferences.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use serde_json::Value;
+
+#[path = "../../src/parse.rs"]
+#[allow(dead_code)]
+mod parse;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(value) = serde_json::from_slice::<Value>(data) else {
+        return;
+    };
+    let _ = parse::validate_preferences(&value);
+    let _ = parse::out_of_range_number(&value, parse::DEFAULT_MAX_PREFERENCE_NUMBER);
+
+    // canonical JSON must be valid JSON for the same value, and stable. `1.0` prints as
+    // `1`, so the reparsed value may hold an integer where the input held a float:
+    // numbers are compared by value, not by serde_json's integer/float distinction.
+    let canonical = parse::canonical_json(&value);
+    let reparsed: Value =
+        serde_json::from_str(&canonical).expect("canonical_json produced invalid JSON");
+    assert!(same_value(&reparsed, &value), "{canonical}");
+    assert_eq!(parse::canonical_json(&reparsed), canonical);
+});
+
+fn same_value(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64() == y.as_f64(),
+        (Value::Array(x), Value::Array(y)) => {
+            x.len() == y.len() && x.iter().zip(y).all(|(x, y)| same_value(x, y))
+        }
+        (Value::Object(x), Value::Object(y)) => {
+            x.len() == y.len()
+                && x.iter()
+                    .all(|(k, v)| y.get(k).is_some_and(|w| same_value(v, w)))
+        }
+        _ => a == b,
+    }
+}
@@ -0,0 +1,340 @@
+// Step 1 user verification backends.
+// `verify_user` only talks to `AuthBackend`; the concrete mode is picked at startup.
+// Checks on later requests live in `request` (sessions, admin) and `signature` (step 3).
+
+mod request;
+mod signature;
+
+pub(crate) use request::{
+    authenticated_session, csrf_cookie, require_admin, require_scope, session_cookie,
+};
+pub(crate) use signature::{
+    SignatureAlg, SignedData, credential_locked, decode_signature, decoy_verification, key_id,
+    verify_credential_signature, verify_signature,
+};
+
+use crate::{
+    session::{SESSION_SCOPES, unix_now},
+    store::Deadline,
+};
+use axum::{async_trait, http::StatusCode};
+use dashmap::DashMap;
+use serde::Deserialize;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Instant,
+};
+use subtle::ConstantTimeEq;
+
+pub(crate) const AUTH_MODE_ENV: &str = "POC_AUTH_MODE";
+pub(crate) const INVITE_CODES_ENV: &str = "POC_INVITE_CODES";
+
+// What a successful verification entitles the user to; carried through the
+// verification token and credential into the session.
+#[derive(Clone, Default)]
+pub(crate) struct Grant {
+    // None: every scope a session can hold (SESSION_SCOPES)
+    pub(crate) scopes: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+pub(crate) enum AuthError {
+    // wrong code/password/link for this user
+    InvalidCredential,
+    // same, from a backend that counts guesses; 0 once nothing can be guessed anymore
+    WrongCode { attempts_remaining: u32 },
+}
+
+impl AuthError {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            AuthError::InvalidCredential | AuthError::WrongCode { .. } => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            AuthError::InvalidCredential | AuthError::WrongCode { .. } => "invalid code",
+        }
+    }
+
+    pub(crate) fn attempts_remaining(&self) -> Option<u32> {
+        match self {
+            AuthError::InvalidCredential => None,
+            AuthError::WrongCode { attempts_remaining } => Some(*attempts_remaining),
+        }
+    }
+}
+
+#[async_trait]
+pub(crate) trait AuthBackend: Send + Sync {
+    // Reported in /api/capabilities.
+    fn mode(&self) -> &'static str;
+
+    // True when step 1 first mails a per-user code (see OneTimeCodeBackend).
+    fn sends_codes(&self) -> bool {
+        false
+    }
+
+    async fn verify(&self, username: &str, credential: &str) -> Result<Grant, AuthError>;
+}
+
+// Reference implementation: every user shares one fixed code.
+pub(crate) struct StaticCodeBackend {
+    code: String,
+}
+
+impl StaticCodeBackend {
+    pub(crate) fn new(code: impl Into<String>) -> Self {
+        StaticCodeBackend { code: code.into() }
+    }
+}
+
+#[async_trait]
+impl AuthBackend for StaticCodeBackend {
+    fn mode(&self) -> &'static str {
+        "static_code"
+    }
+
+    async fn verify(&self, _username: &str, credential: &str) -> Result<Grant, AuthError> {
+        if bool::from(credential.as_bytes().ct_eq(self.code.as_bytes())) {
+            Ok(Grant::default())
+        } else {
+            Err(AuthError::InvalidCredential)
+        }
+    }
+}
+
+// Wrong guesses allowed against one pending code before it is discarded.
+pub(crate) const MAX_CODE_ATTEMPTS: u32 = 5;
+
+// A random per-username code, generated by the server and delivered by a CodeNotifier.
+pub(crate) struct PendingCodeRecord {
+    pub(crate) code: String,
+    pub(crate) attempts: u32,
+    pub(crate) expires_at: Deadline<Self>,
+}
+
+pub(crate) struct OneTimeCodeBackend {
+    pending: Arc<DashMap<String, PendingCodeRecord>>,
+}
+
+impl OneTimeCodeBackend {
+    pub(crate) fn new(pending: Arc<DashMap<String, PendingCodeRecord>>) -> Self {
+        OneTimeCodeBackend { pending }
+    }
+}
+
+#[async_trait]
+impl AuthBackend for OneTimeCodeBackend {
+    fn mode(&self) -> &'static str {
+        "one_time_code"
+    }
+
+    fn sends_codes(&self) -> bool {
+        true
+    }
+
+    // A matching code is consumed atomically, so it can be used once. Expired codes
+    // and codes that reached MAX_CODE_ATTEMPTS wrong guesses are discarded.
+    // A wrong guess reports the guesses left on the pending code. With no pending code
+    // (never requested, expired or used up) that is 0, whether or not the username
+    // was ever seen.
+    async fn verify(&self, username: &str, credential: &str) -> Result<Grant, AuthError> {
+        let now = Instant::now();
+
+        let consumed = self
+            .pending
+            .remove_if(username, |_, p| {
+                now <= p.expires_at.instant()
+                    && bool::from(p.code.as_bytes().ct_eq(credential.as_bytes()))
+            })
+            .is_some();
+        if consumed {
+            return Ok(Grant::default());
+        }
+
+        let (burn, attempts_remaining) = match self.pending.get_mut(username) {
+            Some(mut p) => {
+                p.attempts += 1;
+                let left = if now > p.expires_at.instant() {
+                    0
+                } else {
+                    MAX_CODE_ATTEMPTS.saturating_sub(p.attempts)
+                };
+                (left == 0, left)
+            }
+            None => (false, 0),
+        };
+        if burn {
+            self.pending.remove(username);
+        }
+
+        Err(AuthError::WrongCode { attempts_remaining })
+    }
+}
+
+// One entry of POC_INVITE_CODES, e.g.
+// {"code": "team-2024", "scopes": ["preferences:read"], "max_uses": 10, "expires_at": 1735689600}
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct InviteCode {
+    pub(crate) code: String,
+    // omitted: the full session scopes
+    #[serde(default)]
+    pub(crate) scopes: Option<Vec<String>>,
+    // omitted: unlimited
+    #[serde(default)]
+    pub(crate) max_uses: Option<u32>,
+    // unix seconds; omitted: never
+    #[serde(default)]
+    pub(crate) expires_at: Option<u64>,
+}
+
+struct InviteCodeEntry {
+    invite: InviteCode,
+    // uses left when max_uses is set
+    remaining: Option<AtomicU32>,
+}
+
+// Any of a configured set of shared codes; each may limit scopes, uses and lifetime.
+// Used-up and expired codes fail like wrong ones, so probing can't tell them apart.
+pub(crate) struct InviteCodeBackend {
+    codes: Vec<InviteCodeEntry>,
+}
+
+impl InviteCodeBackend {
+    pub(crate) fn new(codes: Vec<InviteCode>) -> Self {
+        for invite in &codes {
+            if invite.code.is_empty() {
+                panic!("{INVITE_CODES_ENV}: empty code");
+            }
+            for scope in invite.scopes.iter().flatten() {
+                if !SESSION_SCOPES.contains(&scope.as_str()) {
+                    panic!("{INVITE_CODES_ENV}: unknown scope {scope:?}");
+                }
+            }
+        }
+        InviteCodeBackend {
+            codes: codes
+                .into_iter()
+                .map(|invite| InviteCodeEntry {
+                    remaining: invite.max_uses.map(AtomicU32::new),
+                    invite,
+                })
+                .collect(),
+        }
+    }
+
+    // POC_INVITE_CODES: a JSON array of InviteCode.
+    pub(crate) fn from_env() -> Self {
+        let raw = std::env::var(INVITE_CODES_ENV)
+            .unwrap_or_else(|_| panic!("{INVITE_CODES_ENV} must be set in invite_codes mode"));
+        let codes = serde_json::from_str(&raw)
+            .unwrap_or_else(|e| panic!("{INVITE_CODES_ENV}: invalid JSON: {e}"));
+        InviteCodeBackend::new(codes)
+    }
+}
+
+#[async_trait]
+impl AuthBackend for InviteCodeBackend {
+    fn mode(&self) -> &'static str {
+        "invite_codes"
+    }
+
+    async fn verify(&self, _username: &str, credential: &str) -> Result<Grant, AuthError> {
+        // compare against every code so timing doesn't reveal which one matched
+        let mut matched = None;
+        for entry in &self.codes {
+            if bool::from(credential.as_bytes().ct_eq(entry.invite.code.as_bytes())) {
+                matched = Some(entry);
+            }
+        }
+        let entry = matched.ok_or(AuthError::InvalidCredential)?;
+
+        if entry.invite.expires_at.is_some_and(|t| t <= unix_now()) {
+            return Err(AuthError::InvalidCredential);
+        }
+        if let Some(remaining) = &entry.remaining {
+            remaining
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1))
+                .map_err(|_| AuthError::InvalidCredential)?;
+        }
+
+        Ok(Grant {
+            scopes: entry.invite.scopes.clone(),
+        })
+    }
+}
+
+// POC_AUTH_MODE selects the backend; unknown modes stop startup rather than
+// silently falling back to the static code.
+pub(crate) fn backend_from_env(
+    static_code: &str,
+    pending_codes: Arc<DashMap<String, PendingCodeRecord>>,
+) -> Arc<dyn AuthBackend> {
+    let mode = std::env::var(AUTH_MODE_ENV).unwrap_or_else(|_| "static_code".into());
+    match mode.trim() {
+        "static_code" | "static" => Arc::new(StaticCodeBackend::new(static_code)),
+        "one_time_code" => Arc::new(OneTimeCodeBackend::new(pending_codes)),
+        "invite_codes" => Arc::new(InviteCodeBackend::from_env()),
+        other => panic!("{AUTH_MODE_ENV}: unsupported auth mode {other:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::PREFERENCES_READ;
+
+    fn invites(json: &str) -> InviteCodeBackend {
+        InviteCodeBackend::new(serde_json::from_str(json).unwrap())
+    }
+
+    #[tokio::test]
+    async fn each_invite_code_grants_its_own_scopes() {
+        let backend =
+            invites(r#"[{"code": "team"}, {"code": "readonly", "scopes": ["preferences:read"]}]"#);
+        assert_eq!(backend.verify("alice", "team").await.unwrap().scopes, None);
+        assert_eq!(
+            backend.verify("bob", "readonly").await.unwrap().scopes,
+            Some(vec![PREFERENCES_READ.to_string()])
+        );
+        assert!(matches!(
+            backend.verify("carol", "other").await,
+            Err(AuthError::InvalidCredential)
+        ));
+    }
+
+    #[tokio::test]
+    async fn used_up_and_expired_codes_fail_like_wrong_ones() {
+        let backend = invites(&format!(
+            r#"[{{"code": "twice", "max_uses": 2}}, {{"code": "old", "expires_at": {}}}]"#,
+            unix_now()
+        ));
+        assert!(backend.verify("alice", "twice").await.is_ok());
+        assert!(backend.verify("bob", "twice").await.is_ok());
+        assert!(matches!(
+            backend.verify("carol", "twice").await,
+            Err(AuthError::InvalidCredential)
+        ));
+        assert!(matches!(
+            backend.verify("alice", "old").await,
+            Err(AuthError::InvalidCredential)
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown scope")]
+    fn an_invite_with_an_unknown_scope_stops_startup() {
+        invites(r#"[{"code": "x", "scopes": ["admin"]}]"#);
+    }
+
+    #[test]
+    fn an_invite_with_an_unknown_field_does_not_parse() {
+        assert!(serde_json::from_str::<Vec<InviteCode>>(r#"[{"code": "x", "uses": 1}]"#).is_err());
+    }
+}
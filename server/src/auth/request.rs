@@ -0,0 +1,176 @@
+// Who is calling: session tokens (bearer or cookie), scopes and the admin token.
+
+use crate::{
+    config::{CSRF_COOKIE_NAME, CSRF_HEADER, SESSION_COOKIE_NAME, SESSION_TTL},
+    error::ApiError,
+    session::SessionClaims,
+    state::AppState,
+};
+use axum::http::{HeaderMap, StatusCode, header};
+use subtle::ConstantTimeEq;
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let token = value.strip_prefix("Bearer ")?.trim();
+    (!token.is_empty()).then_some(token)
+}
+
+fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|pair| pair.trim().strip_prefix(name)?.strip_prefix('='))
+        .filter(|v| !v.is_empty())
+}
+
+#[derive(PartialEq)]
+enum SessionSource {
+    Bearer,
+    Cookie,
+}
+
+// Authorization: Bearer wins; the session cookie is only a fallback in cookie mode.
+fn session_token<'a>(state: &AppState, headers: &'a HeaderMap) -> Option<(&'a str, SessionSource)> {
+    if let Some(token) = bearer_token(headers) {
+        return Some((token, SessionSource::Bearer));
+    }
+    if !state.config.session_cookie {
+        return None;
+    }
+    cookie_value(headers, SESSION_COOKIE_NAME).map(|t| (t, SessionSource::Cookie))
+}
+
+// The caller's live session. A cookie is sent by the browser automatically, so
+// cookie-authenticated requests must also echo the session's CSRF token in a header.
+pub(crate) fn authenticated_session(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> Result<SessionClaims, ApiError> {
+    let Some((token, source)) = session_token(state, headers) else {
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, "session_required"));
+    };
+
+    let session = match state.session_issuer.validate(token) {
+        Some(s) => s,
+        None => {
+            return Err(ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_or_expired_session",
+            ));
+        }
+    };
+
+    if source == SessionSource::Cookie {
+        let presented = headers
+            .get(CSRF_HEADER)
+            .map(|v| v.as_bytes())
+            .unwrap_or_default();
+        let expected = session.csrf_token.as_deref().unwrap_or_default();
+        if expected.is_empty() || !bool::from(presented.ct_eq(expected.as_bytes())) {
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "csrf_failed"));
+        }
+    }
+
+    Ok(session)
+}
+
+// Authorization on top of `authenticated_session`: the session or scoped token must hold `scope`.
+pub(crate) fn require_scope(session: &SessionClaims, scope: &'static str) -> Result<(), ApiError> {
+    if session.scopes.iter().any(|s| s == scope) {
+        return Ok(());
+    }
+    Err(ApiError {
+        scope: Some(scope),
+        ..ApiError::new(StatusCode::FORBIDDEN, "insufficient_scope")
+    })
+}
+
+pub(crate) fn session_cookie(token: &str) -> String {
+    format!(
+        "{SESSION_COOKIE_NAME}={token}; HttpOnly; Secure; SameSite=Strict; Path=/; Max-Age={}",
+        SESSION_TTL.as_secs()
+    )
+}
+
+// Not HttpOnly: the frontend reads it and echoes it in X-CSRF-Token.
+pub(crate) fn csrf_cookie(token: &str) -> String {
+    format!(
+        "{CSRF_COOKIE_NAME}={token}; Secure; SameSite=Strict; Path=/; Max-Age={}",
+        SESSION_TTL.as_secs()
+    )
+}
+
+pub(crate) fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(expected) = state.config.admin_token.as_deref() else {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "admin_disabled"));
+    };
+    let presented = bearer_token(headers).unwrap_or_default();
+    if bool::from(presented.as_bytes().ct_eq(expected.as_bytes())) {
+        Ok(())
+    } else {
+        Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "admin_token_invalid",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        session::{self, PREFERENCES_WRITE},
+        testing,
+    };
+    use axum::http::HeaderValue;
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {token}")).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn require_admin_without_a_configured_token_is_disabled() {
+        let state = testing::state();
+        let err = require_admin(&state, &bearer("anything")).unwrap_err();
+        assert_eq!(
+            (err.status, err.code),
+            (StatusCode::FORBIDDEN, "admin_disabled")
+        );
+    }
+
+    #[test]
+    fn require_admin_checks_the_bearer_token() {
+        let state = testing::state_with(testing::admin_config());
+        assert!(require_admin(&state, &bearer(testing::ADMIN_TOKEN)).is_ok());
+        let err = require_admin(&state, &bearer("wrong")).unwrap_err();
+        assert_eq!(
+            (err.status, err.code),
+            (StatusCode::UNAUTHORIZED, "admin_token_invalid")
+        );
+        let err = require_admin(&state, &HeaderMap::new()).unwrap_err();
+        assert_eq!(err.code, "admin_token_invalid");
+    }
+
+    #[test]
+    fn require_scope_names_the_missing_scope() {
+        let session = SessionClaims {
+            public_key: SigningKey::generate(&mut OsRng).verifying_key(),
+            credential_id: "c".into(),
+            csrf_token: None,
+            scopes: vec![session::PREFERENCES_READ.into()],
+        };
+        assert!(require_scope(&session, session::PREFERENCES_READ).is_ok());
+        let err = require_scope(&session, PREFERENCES_WRITE).unwrap_err();
+        assert_eq!(err.code, "insufficient_scope");
+        assert_eq!(err.scope, Some(PREFERENCES_WRITE));
+    }
+}
@@ -0,0 +1,164 @@
+// Signature checks by a credential's key (step 3, and step 2 rotation and delegation):
+// what a signature covers, verifying it off the async workers, and locking a
+// credential after repeated failures.
+
+use crate::{
+    config::{SIGNATURE_FAILURE_THRESHOLD, SIGNATURE_LOCK_BASE, SIGNATURE_LOCK_MAX},
+    error::ApiError,
+    parse, payload,
+    state::AppState,
+    store::{SignatureAttemptRecord, expired},
+};
+use axum::http::StatusCode;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256, Sha512};
+use std::time::Instant;
+
+pub(crate) fn decode_signature(encoded: &str) -> Result<Signature, ApiError> {
+    parse::decode_signature(encoded).map_err(|code| match code {
+        "signature_too_large" => ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, code),
+        _ => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, code),
+    })
+}
+
+// Short stable id for a public key: base64url of the first 8 bytes of its SHA-256.
+// The same key always gets the same kid, so clients can index their keystore by it.
+pub(crate) fn key_id(public_key: &VerifyingKey) -> String {
+    let digest = Sha256::digest(public_key.as_bytes());
+    URL_SAFE_NO_PAD.encode(&digest[..8])
+}
+
+pub(crate) fn credential_locked(state: &AppState, credential_id: &str) -> bool {
+    state
+        .signature_attempts
+        .get(credential_id)
+        .and_then(|a| a.locked_until)
+        .is_some_and(|t| !expired(t))
+}
+
+pub(crate) fn record_signature_failure(state: &AppState, credential_id: &str) {
+    let now = Instant::now();
+    let mut attempt = state
+        .signature_attempts
+        .entry(credential_id.to_string())
+        .or_insert(SignatureAttemptRecord {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+
+    // quiet for a full cooldown: start counting again
+    if now > attempt.last_failure + SIGNATURE_LOCK_MAX {
+        attempt.failures = 0;
+    }
+
+    attempt.failures += 1;
+    attempt.last_failure = now;
+
+    if attempt.failures >= SIGNATURE_FAILURE_THRESHOLD {
+        let doublings = (attempt.failures - SIGNATURE_FAILURE_THRESHOLD).min(5);
+        let lock = (SIGNATURE_LOCK_BASE * 2u32.pow(doublings)).min(SIGNATURE_LOCK_MAX);
+        attempt.locked_until = Some(Instant::now() + lock);
+    }
+}
+
+// What a step 3 signature covers, per the request's `alg`.
+pub(crate) enum SignedData {
+    // Ed25519: the exact bytes, POC_SIGNING_CONTEXT already prepended
+    Pure(Vec<u8>),
+    // Ed25519ph: SHA-512 of the payload; POC_SIGNING_CONTEXT is the ph context instead
+    // boxed: the hash state is several times the size of the other variant
+    Prehashed {
+        digest: Box<Sha512>,
+        context: Vec<u8>,
+    },
+}
+
+impl SignedData {
+    // `payload` as sent: the canonical v1 payload, the free-text message or the file.
+    pub(crate) fn new(alg: SignatureAlg, context: &str, payload: &[u8]) -> Self {
+        match alg {
+            SignatureAlg::Ed25519 => SignedData::Pure(payload::with_context(context, payload)),
+            SignatureAlg::Ed25519ph => SignedData::Prehashed {
+                digest: Box::new(Sha512::new().chain_update(payload)),
+                context: context.as_bytes().to_vec(),
+            },
+        }
+    }
+
+    pub(crate) fn verify(self, key: &VerifyingKey, signature: &Signature) -> bool {
+        match self {
+            SignedData::Pure(message) => key.verify(&message, signature).is_ok(),
+            SignedData::Prehashed { digest, context } => {
+                let context = (!context.is_empty()).then_some(context.as_slice());
+                key.verify_prehashed(*digest, context, signature).is_ok()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum SignatureAlg {
+    Ed25519,
+    // RFC 8032 Ed25519ph: the signer hashes the payload with SHA-512 and signs the digest
+    Ed25519ph,
+}
+
+impl SignatureAlg {
+    // The request's `alg`; omitted means plain Ed25519.
+    pub(crate) fn parse(raw: Option<&str>) -> Result<Self, ApiError> {
+        match raw.map(str::trim) {
+            None | Some("ed25519") => Ok(SignatureAlg::Ed25519),
+            Some("ed25519ph") => Ok(SignatureAlg::Ed25519ph),
+            Some(_) => Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "alg_unsupported",
+            )),
+        }
+    }
+}
+
+// Same verify work as a real attempt, result discarded.
+pub(crate) async fn decoy_verification(state: &AppState, data: SignedData, signature: Signature) {
+    let result = verify_signature(state, state.decoy_key, data, signature).await;
+    std::hint::black_box(result);
+}
+
+// Verifies a signature by `credential_id`'s key, counting a failure toward its lockout.
+// Every check of a credential's signature goes through here; callers check
+// credential_locked before and clear signature_attempts once the request succeeds.
+pub(crate) async fn verify_credential_signature(
+    state: &AppState,
+    credential_id: &str,
+    key: VerifyingKey,
+    data: SignedData,
+    signature: Signature,
+) -> Result<(), ApiError> {
+    if verify_signature(state, key, data, signature).await {
+        return Ok(());
+    }
+    record_signature_failure(state, credential_id);
+    Err(ApiError::new(StatusCode::UNAUTHORIZED, "invalid_signature"))
+}
+
+// Ed25519 verification is CPU-bound; it runs on the blocking pool, at most
+// `verify_workers` at a time, so a burst of step 3 requests can't stall the async workers.
+pub(crate) async fn verify_signature(
+    state: &AppState,
+    key: VerifyingKey,
+    data: SignedData,
+    signature: Signature,
+) -> bool {
+    if state.config.verify_workers == 0 {
+        return data.verify(&key, &signature);
+    }
+    let _permit = state
+        .verify_permits
+        .acquire()
+        .await
+        .expect("verify semaphore is never closed");
+    tokio::task::spawn_blocking(move || data.verify(&key, &signature))
+        .await
+        .unwrap_or(false)
+}
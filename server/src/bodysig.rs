@@ -0,0 +1,249 @@
+// Response signing (POC_SIGN_RESPONSES=true). Every response gets X-Body-Signature, a
+// detached Ed25519 signature (base64url) over the exact body bytes, and X-Body-Signature-Kid
+// naming the key in GET /api/jwks. A client holding that key can tell whether a proxy or
+// other intermediary changed the body. Headers and status are not covered.
+//
+// The key comes from the `response_signing_key` secret (base64url 32-byte seed); without
+// it one is generated at startup, and clients must fetch /api/jwks again after a restart.
+//
+// The same key signs session receipts (POC_SESSION_RECEIPTS=true): step 3 returns a
+// compact JWS stating that this server created a session for a credential at a given
+// time. It names the session by hash only, so an audit log can keep it.
+
+use axum::{
+    body::{Body, to_bytes},
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use ed25519_dalek::{Signer, SigningKey};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+pub const SIGN_RESPONSES_ENV: &str = "POC_SIGN_RESPONSES";
+pub const SESSION_RECEIPTS_ENV: &str = "POC_SESSION_RECEIPTS";
+pub const BODY_SIGNATURE_HEADER: &str = "x-body-signature";
+pub const BODY_SIGNATURE_KID_HEADER: &str = "x-body-signature-kid";
+
+pub struct ResponseSigner {
+    key: SigningKey,
+    kid: String,
+}
+
+impl ResponseSigner {
+    pub fn new(key: SigningKey) -> Self {
+        ResponseSigner {
+            kid: crate::auth::key_id(&key.verifying_key()),
+            key,
+        }
+    }
+
+    // RFC 8037 OKP key, as listed by GET /api/jwks.
+    pub fn jwk(&self) -> Value {
+        json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "x": URL_SAFE_NO_PAD.encode(self.key.verifying_key().as_bytes()),
+            "kid": self.kid,
+            "use": "sig",
+            "alg": "EdDSA",
+        })
+    }
+
+    // EdDSA JWS; `kid` in the header names the key in GET /api/jwks. `iat` is unix seconds.
+    pub fn receipt(&self, credential_id: &str, session_token: &str, iat: u64) -> String {
+        let header = json!({ "alg": "EdDSA", "typ": "JWT", "kid": self.kid });
+        let payload = json!({
+            "credential_id": credential_id,
+            "session_token_hash": URL_SAFE_NO_PAD.encode(Sha256::digest(session_token.as_bytes())),
+            "iat": iat,
+        });
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(payload.to_string())
+        );
+        let signature = self.key.sign(signing_input.as_bytes());
+        format!(
+            "{signing_input}.{}",
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        )
+    }
+}
+
+// Outside localize_errors, so the localized error text is what gets signed. The body is
+// buffered to sign it; every response here is a small JSON document.
+pub async fn sign(State(signer): State<Arc<ResponseSigner>>, req: Request, next: Next) -> Response {
+    let (mut parts, body) = next.run(req).await.into_parts();
+    let bytes = match to_bytes(body, usize::MAX).await {
+        Ok(b) => b,
+        Err(_) => {
+            return crate::error::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "response_signing_failed",
+            );
+        }
+    };
+    let signature = URL_SAFE_NO_PAD.encode(signer.key.sign(&bytes).to_bytes());
+    parts.headers.insert(
+        BODY_SIGNATURE_HEADER,
+        HeaderValue::from_str(&signature).expect("base64url is a valid header value"),
+    );
+    parts.headers.insert(
+        BODY_SIGNATURE_KID_HEADER,
+        HeaderValue::from_str(&signer.kid).expect("base64url is a valid header value"),
+    );
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BODY_SIGNATURE_HEADER, BODY_SIGNATURE_KID_HEADER, ResponseSigner};
+    use crate::{
+        config::Config,
+        handlers::build_app,
+        parse::canonical_json,
+        session::unix_now,
+        state::{AppState, EntropySource, state_from},
+        testing::{self, credential, enter_payload, post_json, send},
+    };
+    use axum::{
+        body::{Body, Bytes, to_bytes},
+        extract::{ConnectInfo, Request},
+        http::{HeaderMap, StatusCode},
+    };
+    use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+    use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+    use rand::rngs::OsRng;
+    use serde_json::{Value, json};
+    use sha2::{Digest, Sha256};
+    use std::{net::SocketAddr, sync::Arc};
+    use tower::ServiceExt;
+
+    fn signing_state() -> AppState {
+        let mut config = testing::config();
+        config.sign_responses = true;
+        signer_state(config)
+    }
+
+    fn signer_state(config: Config) -> AppState {
+        let signer = ResponseSigner::new(SigningKey::generate(&mut OsRng));
+        state_from(
+            config,
+            Arc::new(EntropySource::Os),
+            None,
+            Some(Arc::new(signer)),
+        )
+    }
+
+    // testing::send parses the body; a signature covers the bytes as sent
+    async fn raw(state: &AppState, path: &str) -> (HeaderMap, Bytes) {
+        let mut req = Request::get(path).body(Body::empty()).unwrap();
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        let resp = build_app(state).oneshot(req).await.unwrap();
+        let headers = resp.headers().clone();
+        (
+            headers,
+            to_bytes(resp.into_body(), usize::MAX).await.unwrap(),
+        )
+    }
+
+    // what a client does: find the kid in /api/jwks, verify the body with its `x`
+    fn verify(jwks: &[u8], headers: &HeaderMap, body: &[u8]) -> bool {
+        let jwks: Value = serde_json::from_slice(jwks).unwrap();
+        let kid = headers[BODY_SIGNATURE_KID_HEADER].to_str().unwrap();
+        let jwk = jwks["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|k| k["kid"] == kid)
+            .unwrap();
+        let x = URL_SAFE_NO_PAD.decode(jwk["x"].as_str().unwrap()).unwrap();
+        let key = VerifyingKey::try_from(x.as_slice()).unwrap();
+        let signature = URL_SAFE_NO_PAD
+            .decode(headers[BODY_SIGNATURE_HEADER].to_str().unwrap())
+            .unwrap();
+        let signature = Signature::from_slice(&signature).unwrap();
+        key.verify(body, &signature).is_ok()
+    }
+
+    #[tokio::test]
+    async fn a_client_verifies_responses_against_the_jwks() {
+        let state = signing_state();
+        let (_, jwks) = raw(&state, "/api/jwks").await;
+        let (headers, body) = raw(&state, "/api/capabilities").await;
+        assert!(verify(&jwks, &headers, &body));
+
+        let mut tampered = body.to_vec();
+        tampered.push(b' ');
+        assert!(!verify(&jwks, &headers, &tampered));
+
+        // error bodies are signed as well
+        let (headers, body) = raw(&state, "/api/no-such-route").await;
+        assert!(verify(&jwks, &headers, &body));
+    }
+
+    #[tokio::test]
+    async fn a_third_party_verifies_a_session_receipt() {
+        let mut config = testing::config();
+        config.session_receipts = true;
+        let state = signer_state(config);
+        let key = SigningKey::generate(&mut OsRng);
+        let id = credential(&state, &key);
+        let message = canonical_json(&enter_payload(&id, None));
+        let signature = URL_SAFE_NO_PAD.encode(key.sign(message.as_bytes()).to_bytes());
+        let req = post_json(
+            "/api/step3/enter",
+            &json!({ "credential_id": id, "message": message, "signature": signature }),
+        );
+        let (status, _, body) = send(&state, req).await;
+        assert_eq!(status, StatusCode::OK);
+        let session = body["session_token"].as_str().unwrap();
+        let receipt = body["receipt"].as_str().unwrap();
+
+        let (_, jwks) = raw(&state, "/api/jwks").await;
+        let jwks: Value = serde_json::from_slice(&jwks).unwrap();
+        let parts: Vec<&str> = receipt.split('.').collect();
+        let [header, payload, signature] = parts[..] else {
+            panic!("not a compact JWS: {receipt}");
+        };
+        let decode = |part: &str| -> Value {
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).unwrap()).unwrap()
+        };
+        let header = decode(header);
+        assert_eq!(header["alg"], "EdDSA");
+        let jwk = &jwks["keys"][0];
+        assert_eq!(header["kid"], jwk["kid"]);
+        let x = URL_SAFE_NO_PAD.decode(jwk["x"].as_str().unwrap()).unwrap();
+        let server_key = VerifyingKey::try_from(x.as_slice()).unwrap();
+        let signature = Signature::from_slice(&URL_SAFE_NO_PAD.decode(signature).unwrap()).unwrap();
+        let signing_input = receipt.rsplit_once('.').unwrap().0;
+        assert!(
+            server_key
+                .verify(signing_input.as_bytes(), &signature)
+                .is_ok()
+        );
+
+        let claims = decode(payload);
+        assert_eq!(claims["credential_id"], id.as_str());
+        assert_eq!(
+            claims["session_token_hash"],
+            URL_SAFE_NO_PAD
+                .encode(Sha256::digest(session.as_bytes()))
+                .as_str()
+        );
+        assert!(claims["iat"].as_u64().unwrap().abs_diff(unix_now()) <= 1);
+    }
+
+    #[tokio::test]
+    async fn responses_are_unsigned_when_off() {
+        let state = testing::state();
+        let (headers, jwks) = raw(&state, "/api/jwks").await;
+        assert!(!headers.contains_key(BODY_SIGNATURE_HEADER));
+        assert_eq!(&jwks[..], br#"{"keys":[]}"#);
+    }
+}
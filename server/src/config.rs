@@ -0,0 +1,459 @@
+// Startup configuration. Every POC_* variable is read once, at startup, into `Config`;
+// an invalid value stops the server instead of falling back silently.
+
+use crate::{bodysig, parse::DEFAULT_MAX_PREFERENCE_NUMBER, profile, session::SessionEviction, ui};
+use axum::http::HeaderValue;
+use std::{net::SocketAddr, time::Duration};
+
+pub(crate) const HARCODED_CODE: &str = "123456";
+pub(crate) const VERIFICATION_TTL: Duration = Duration::from_secs(300); // 5 minutes
+pub(crate) const TEMP_CREDENTIAL_TTL: Duration = Duration::from_secs(300);
+pub(crate) const SESSION_TTL: Duration = Duration::from_secs(1800); // 30 minutes
+pub(crate) const SCOPED_TOKEN_TTL: Duration = Duration::from_secs(300);
+pub(crate) const PENDING_CODE_TTL: Duration = Duration::from_secs(300);
+pub(crate) const POW_CHALLENGE_TTL: Duration = Duration::from_secs(120);
+
+// Failed signatures per credential before it is locked; each further failure doubles the lock
+pub(crate) const SIGNATURE_FAILURE_THRESHOLD: u32 = 5;
+pub(crate) const SIGNATURE_LOCK_BASE: Duration = Duration::from_secs(30);
+pub(crate) const SIGNATURE_LOCK_MAX: Duration = Duration::from_secs(900); // also the failure cooldown
+
+// Env: dummy signature verification when the credential id is unknown (see Config)
+const CONSTANT_TIME_ENTER_ENV: &str = "POC_CONSTANT_TIME_ENTER";
+
+// NDJSON preferences batch: a single line may not exceed this, and at most this many lines per
+// request
+pub(crate) const MAX_BATCH_LINE_BYTES: usize = 64 * 1024;
+// Largest step 3 `message`; a v1 payload is well under 1 KiB.
+pub(crate) const MAX_MESSAGE_BYTES: usize = 16 * 1024;
+// Largest `payload` file part accepted by /api/step3/enter-multipart.
+pub(crate) const MAX_MULTIPART_PAYLOAD_BYTES: usize = 1024 * 1024;
+// alg=ed25519ph streams the file into SHA-512 instead of buffering it, so it may be larger
+pub(crate) const MAX_PREHASHED_PAYLOAD_BYTES: usize = 64 * 1024 * 1024;
+// headroom for the other parts and multipart framing on top of the file
+pub(crate) const MULTIPART_OVERHEAD_BYTES: usize = 64 * 1024;
+pub(crate) const MAX_BATCH_LINES: usize = 10_000;
+// GET /api/step2/credentials page size
+pub(crate) const DEFAULT_CREDENTIAL_PAGE: usize = 50;
+pub(crate) const MAX_CREDENTIAL_PAGE: usize = 200;
+
+// Env: also deliver the session token as a cookie (see Config::session_cookie)
+pub(crate) const SESSION_COOKIE_ENV: &str = "POC_SESSION_COOKIE";
+pub(crate) const SESSION_COOKIE_NAME: &str = "poc_session";
+
+// Cookie mode only: double-submit CSRF token (readable cookie + echoed header)
+pub(crate) const CSRF_COOKIE_NAME: &str = "poc_csrf";
+pub(crate) const CSRF_HEADER: &str = "x-csrf-token";
+
+// Env: comma-separated origins allowed to make credentialed (cookie) CORS requests
+pub(crate) const CORS_ORIGINS_ENV: &str = "POC_CORS_ORIGINS";
+
+// Env: serve credential public keys without the admin token (see Config)
+pub(crate) const PUBLIC_KEY_LOOKUP_OPEN_ENV: &str = "POC_PUBLIC_KEY_LOOKUP_OPEN";
+
+// Env: caps on stored entries per map; at the cap new inserts get 503 capacity_reached
+const MAX_VERIFICATION_TOKENS_ENV: &str = "POC_MAX_VERIFICATION_TOKENS";
+const MAX_CREDENTIALS_ENV: &str = "POC_MAX_CREDENTIALS";
+const MAX_SESSIONS_ENV: &str = "POC_MAX_SESSIONS";
+const DEFAULT_MAX_ENTRIES: usize = 100_000;
+// Env: how many accepted step 3 signatures to remember for replay detection (0 = off)
+pub(crate) const SIGNATURE_CACHE_SIZE_ENV: &str = "POC_SIGNATURE_CACHE_SIZE";
+// Env: seconds a successful step 3 verification is remembered, so a repeat skips Ed25519
+// (0 = off), and how many are kept. Only used with the replay cache off (see state.rs).
+pub(crate) const VERIFY_CACHE_SECS_ENV: &str = "POC_VERIFY_CACHE_SECS";
+const VERIFY_CACHE_SIZE_ENV: &str = "POC_VERIFY_CACHE_SIZE";
+const DEFAULT_VERIFY_CACHE_SIZE: usize = 10_000;
+// Env: comma-separated route groups to serve (see ROUTE_GROUPS); others fall through to 404.
+// Unset: everything.
+pub(crate) const ENABLED_ROUTES_ENV: &str = "POC_ENABLED_ROUTES";
+const ROUTE_GROUPS: &[&str] = &["verify", "issue", "enter", "token", "preferences", "admin"];
+
+// Env: nosniff / frame / CSP headers on every response (on unless set to false)
+const SECURITY_HEADERS_ENV: &str = "POC_SECURITY_HEADERS";
+// Env: also send Strict-Transport-Security; only when clients reach the server over TLS
+pub(crate) const HSTS_ENV: &str = "POC_HSTS";
+pub(crate) const HSTS_VALUE: &str = "max-age=31536000; includeSubDomains";
+// The API only serves JSON, so nothing may be loaded or framed.
+pub(crate) const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; frame-ancestors 'none'";
+
+// Env: indent JSON response bodies for reading with curl (compact unless set)
+const PRETTY_JSON_ENV: &str = "POC_PRETTY_JSON";
+
+// Env: most request headers accepted (count), and their total size in bytes
+const MAX_REQUEST_HEADERS_ENV: &str = "POC_MAX_REQUEST_HEADERS";
+const DEFAULT_MAX_REQUEST_HEADERS: usize = 64;
+const MAX_HEADER_BYTES_ENV: &str = "POC_MAX_HEADER_BYTES";
+const DEFAULT_MAX_HEADER_BYTES: usize = 16 * 1024;
+
+// Env: requests handled at once; more are shed with 503 overloaded (0 = no limit)
+const MAX_CONCURRENT_REQUESTS_ENV: &str = "POC_MAX_CONCURRENT_REQUESTS";
+const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 512;
+// Env: seconds a request may take before it is cut off with 503 (0 = no limit)
+const REQUEST_TIMEOUT_ENV: &str = "POC_REQUEST_TIMEOUT_SECS";
+const DEFAULT_REQUEST_TIMEOUT_SECS: usize = 30;
+
+// Env: largest magnitude allowed for any number inside preferences
+const PREFERENCES_MAX_NUMBER_ENV: &str = "POC_PREFERENCES_MAX_NUMBER";
+
+// Env: step 1 verify needs a proof-of-work solution from GET /api/step1/pow
+const POW_ENV: &str = "POC_POW";
+// Env: leading zero bits required of SHA-256(challenge ":" nonce)
+const POW_DIFFICULTY_ENV: &str = "POC_POW_DIFFICULTY";
+const DEFAULT_POW_DIFFICULTY: usize = 20;
+// Env: POST /api/step2/issue-anonymous mints credentials without step 1 (needs POC_POW)
+const ALLOW_ANONYMOUS_ENV: &str = "POC_ALLOW_ANONYMOUS";
+
+// Env: minimum response time in ms for verify, credential issuance and step 3 (0 = off)
+const AUTH_MIN_LATENCY_ENV: &str = "POC_AUTH_MIN_LATENCY_MS";
+
+// Env: mount every route under this path, e.g. /auth -> /auth/api/step1/verify
+const PATH_PREFIX_ENV: &str = "POC_PATH_PREFIX";
+
+// Env: this instance's name (e.g. its hostname); step 3 payloads must carry it as `aud`
+const AUDIENCE_ENV: &str = "POC_AUDIENCE";
+
+// Env: seconds from a successful verify to entering a session, across every step (0 = off)
+const FLOW_DEADLINE_ENV: &str = "POC_FLOW_DEADLINE_SECS";
+
+// Env: step 3 messages must be a v1 payload or base64url bytes, never free text
+const STRICT_MESSAGES_ENV: &str = "POC_STRICT_MESSAGES";
+
+// Env: domain-separation string prepended to step 3 messages before verifying (see Config)
+const SIGNING_CONTEXT_ENV: &str = "POC_SIGNING_CONTEXT";
+// Env: step 3 verifications running at once on the blocking pool (0 = inline on the
+// async worker). Default: one per CPU.
+const VERIFY_WORKERS_ENV: &str = "POC_VERIFY_WORKERS";
+// Env: address and port to listen on
+const BIND_ENV: &str = "POC_BIND";
+const DEFAULT_BIND: &str = "0.0.0.0:8080";
+// Seconds between the shutdown signal and the listener closing.
+const DRAIN_SECS_ENV: &str = "POC_DRAIN_SECS";
+const DEFAULT_DRAIN_SECS: usize = 10;
+
+// Test-only: when set, tokens and keys come from a ChaCha20 RNG seeded with this u64.
+// Honored only by builds with the `test-rng` feature; other builds refuse to start.
+pub(crate) const TEST_RNG_SEED_ENV: &str = "POC_TEST_RNG_SEED";
+
+// Optional Ed25519 signature (base64url) over the canonical preferences JSON
+pub(crate) const PREFERENCES_SIGNATURE_HEADER: &str = "x-preferences-signature";
+
+// Correlation id: taken from the request when sane, otherwise generated; always echoed back
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+pub(crate) const MAX_REQUEST_ID_LEN: usize = 128;
+
+pub(crate) struct Config {
+    // Without this, an unknown credential_id fails fast while a known one pays for
+    // Ed25519 verification, and the error code differs too, so both reveal which ids
+    // exist. With it, step 3 refusals cost one verification and all read enter_refused.
+    pub(crate) constant_time_enter: bool,
+    // bearer token for /api/admin/* (secret `admin_token`); admin endpoints are disabled when unset
+    pub(crate) admin_token: Option<String>,
+    // Step 3 sets an HttpOnly; Secure; SameSite=Strict cookie, and the cookie is
+    // accepted wherever a session token is read and no Authorization header is sent.
+    pub(crate) session_cookie: bool,
+    // Empty: CORS allows any origin without credentials (cookies are then same-origin only).
+    pub(crate) cors_origins: Vec<HeaderValue>,
+    // Bound memory under abuse of the issuance endpoints.
+    pub(crate) max_verification_tokens: usize,
+    pub(crate) max_temporary_credentials: usize,
+    pub(crate) max_sessions: usize,
+    pub(crate) signature_cache_size: usize,
+    // zero: no verification cache
+    pub(crate) verify_cache_ttl: Duration,
+    pub(crate) verify_cache_size: usize,
+    pub(crate) session_eviction: SessionEviction,
+    pub(crate) drain_window: Duration,
+    // Off: the public-key lookup needs the admin token, since a 200/404 tells anyone
+    // which credential ids exist. On: any verifier service may call it.
+    pub(crate) public_key_lookup_open: bool,
+    // Step 3 signatures cover `context || 0x00 || message`; clients must use the same
+    // string. Empty (default): the bare message, as before.
+    pub(crate) signing_context: String,
+    // subset of ROUTE_GROUPS that `api_routes` mounts
+    pub(crate) enabled_routes: Vec<&'static str>,
+    pub(crate) security_headers: bool,
+    pub(crate) verify_workers: usize,
+    // Floor on auth endpoint latency, so fast 4xx answers don't reveal why they failed.
+    pub(crate) auth_min_latency: Duration,
+    // "" or "/seg[/seg...]" without a trailing slash
+    pub(crate) path_prefix: String,
+    // Some(bits) when step 1 requires proof-of-work
+    pub(crate) pow_difficulty: Option<u32>,
+    // Proof of key possession without identity; each anonymous credential costs a PoW.
+    pub(crate) allow_anonymous: bool,
+    // The server itself speaks plain HTTP; set this when a TLS proxy is in front, never
+    // for local HTTP, or browsers would refuse plain http://localhost afterwards.
+    pub(crate) hsts: bool,
+    // POC_SERVE_UI: the browser demo at `/`
+    pub(crate) serve_ui: bool,
+    // bodysig: X-Body-Signature on every response, and signed receipts from step 3
+    pub(crate) sign_responses: bool,
+    pub(crate) session_receipts: bool,
+    // applied through error::set_pretty_json, since json_ok/json_error take no state
+    pub(crate) pretty_json: bool,
+    // checked by reject_dangerous_headers
+    pub(crate) max_request_headers: usize,
+    pub(crate) max_header_bytes: usize,
+    // numbers in preferences beyond this magnitude are refused
+    pub(crate) preferences_max_number: u64,
+    // Confused-deputy guard: a payload signed for another instance names that one.
+    pub(crate) audience: Option<String>,
+    // POC_STRICT_MESSAGES: step 3 refuses free-text messages (see payload::message_bytes)
+    pub(crate) strict_messages: bool,
+    // None: unlimited
+    pub(crate) request_timeout: Option<Duration>,
+    // POC_FLOW_DEADLINE_SECS; None: each token only has its own TTL
+    pub(crate) flow_deadline: Option<Duration>,
+    // 0: unlimited (AppState::request_permits is None)
+    pub(crate) max_concurrent_requests: usize,
+    // POC_ENV: production refuses to start with unsafe defaults (see profile)
+    pub(crate) profile: profile::Profile,
+    // POC_BIND; a loopback address needs no TLS proxy in production
+    pub(crate) bind: SocketAddr,
+}
+
+impl Config {
+    pub(crate) fn from_env() -> Self {
+        Config {
+            constant_time_enter: env_flag(CONSTANT_TIME_ENTER_ENV),
+            // filled from the secret source in main
+            admin_token: None,
+            session_cookie: env_flag(SESSION_COOKIE_ENV),
+            cors_origins: std::env::var(CORS_ORIGINS_ENV)
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|o| !o.is_empty())
+                .map(|o| {
+                    HeaderValue::from_str(o)
+                        .unwrap_or_else(|_| panic!("{CORS_ORIGINS_ENV}: invalid origin {o}"))
+                })
+                .collect(),
+            max_verification_tokens: env_usize(MAX_VERIFICATION_TOKENS_ENV, DEFAULT_MAX_ENTRIES),
+            max_temporary_credentials: env_usize(MAX_CREDENTIALS_ENV, DEFAULT_MAX_ENTRIES),
+            max_sessions: env_usize(MAX_SESSIONS_ENV, DEFAULT_MAX_ENTRIES),
+            signature_cache_size: env_usize(SIGNATURE_CACHE_SIZE_ENV, DEFAULT_MAX_ENTRIES),
+            verify_cache_ttl: Duration::from_secs(env_usize(VERIFY_CACHE_SECS_ENV, 0) as u64),
+            verify_cache_size: env_usize(VERIFY_CACHE_SIZE_ENV, DEFAULT_VERIFY_CACHE_SIZE).max(1),
+            session_eviction: SessionEviction::from_env(),
+            drain_window: Duration::from_secs(env_usize(DRAIN_SECS_ENV, DEFAULT_DRAIN_SECS) as u64),
+            public_key_lookup_open: env_flag(PUBLIC_KEY_LOOKUP_OPEN_ENV),
+            signing_context: signing_context_from_env(),
+            enabled_routes: enabled_routes_from_env(),
+            security_headers: env_flag_or(SECURITY_HEADERS_ENV, true),
+            auth_min_latency: Duration::from_millis(env_usize(AUTH_MIN_LATENCY_ENV, 0) as u64),
+            path_prefix: path_prefix_from_env(),
+            pow_difficulty: env_flag(POW_ENV).then(|| {
+                let bits = env_usize(POW_DIFFICULTY_ENV, DEFAULT_POW_DIFFICULTY);
+                assert!(bits <= 256, "{POW_DIFFICULTY_ENV} must be at most 256");
+                bits as u32
+            }),
+            allow_anonymous: allow_anonymous_from_env(),
+            profile: profile::Profile::from_env(),
+            bind: bind_from_env(),
+            verify_workers: env_usize(
+                VERIFY_WORKERS_ENV,
+                std::thread::available_parallelism().map_or(1, |n| n.get()),
+            ),
+            hsts: env_flag(HSTS_ENV),
+            serve_ui: env_flag(ui::SERVE_UI_ENV),
+            sign_responses: env_flag(bodysig::SIGN_RESPONSES_ENV),
+            session_receipts: env_flag(bodysig::SESSION_RECEIPTS_ENV),
+            pretty_json: env_flag(PRETTY_JSON_ENV),
+            max_request_headers: env_usize(MAX_REQUEST_HEADERS_ENV, DEFAULT_MAX_REQUEST_HEADERS),
+            max_header_bytes: env_usize(MAX_HEADER_BYTES_ENV, DEFAULT_MAX_HEADER_BYTES),
+            max_concurrent_requests: env_usize(
+                MAX_CONCURRENT_REQUESTS_ENV,
+                DEFAULT_MAX_CONCURRENT_REQUESTS,
+            ),
+            flow_deadline: match env_usize(FLOW_DEADLINE_ENV, 0) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs as u64)),
+            },
+            request_timeout: match env_usize(REQUEST_TIMEOUT_ENV, DEFAULT_REQUEST_TIMEOUT_SECS) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs as u64)),
+            },
+            strict_messages: env_flag(STRICT_MESSAGES_ENV),
+            audience: std::env::var(AUDIENCE_ENV)
+                .ok()
+                .map(|a| a.trim().to_string())
+                .filter(|a| !a.is_empty()),
+            preferences_max_number: env_usize(
+                PREFERENCES_MAX_NUMBER_ENV,
+                DEFAULT_MAX_PREFERENCE_NUMBER as usize,
+            ) as u64,
+        }
+    }
+
+    pub(crate) fn route_enabled(&self, group: &str) -> bool {
+        match group {
+            // not a POC_ENABLED_ROUTES group: part of `issue`, and off unless allowed
+            "anonymous" => self.allow_anonymous && self.enabled_routes.contains(&"issue"),
+            _ => self.enabled_routes.contains(&group),
+        }
+    }
+}
+
+// Without proof-of-work nothing would slow down minting anonymous credentials.
+fn allow_anonymous_from_env() -> bool {
+    let allowed = env_flag(ALLOW_ANONYMOUS_ENV);
+    assert!(
+        !allowed || env_flag(POW_ENV),
+        "{ALLOW_ANONYMOUS_ENV} requires {POW_ENV}"
+    );
+    allowed
+}
+
+// Also the Ed25519ph context, which RFC 8032 caps at 255 bytes.
+fn signing_context_from_env() -> String {
+    let context = std::env::var(SIGNING_CONTEXT_ENV).unwrap_or_default();
+    assert!(
+        context.len() <= 255,
+        "{SIGNING_CONTEXT_ENV} must be at most 255 bytes"
+    );
+    context
+}
+
+// "/auth/" and "auth" both become "/auth"; "/" and unset mean no prefix.
+fn path_prefix_from_env() -> String {
+    path_prefix(&std::env::var(PATH_PREFIX_ENV).unwrap_or_default())
+}
+
+fn path_prefix(raw: &str) -> String {
+    let trimmed = raw.trim().trim_matches('/');
+    if trimmed.is_empty() {
+        return String::new();
+    }
+    if trimmed.contains(['?', '#', ':', '*']) || trimmed.contains("//") {
+        panic!("{PATH_PREFIX_ENV}: invalid path prefix {raw:?}");
+    }
+    format!("/{trimmed}")
+}
+
+fn bind_from_env() -> SocketAddr {
+    let raw = std::env::var(BIND_ENV).unwrap_or_else(|_| DEFAULT_BIND.into());
+    raw.trim().parse().unwrap_or_else(|_| {
+        panic!("{BIND_ENV}: invalid address {raw:?}, expected e.g. {DEFAULT_BIND}")
+    })
+}
+
+// Unknown group names stop startup, so a typo can't silently drop (or keep) a route.
+fn enabled_routes_from_env() -> Vec<&'static str> {
+    match std::env::var(ENABLED_ROUTES_ENV) {
+        Ok(raw) => route_groups(&raw),
+        Err(_) => ROUTE_GROUPS.to_vec(),
+    }
+}
+
+fn route_groups(raw: &str) -> Vec<&'static str> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|g| !g.is_empty())
+        .map(|g| {
+            ROUTE_GROUPS
+                .iter()
+                .copied()
+                .find(|known| *known == g)
+                .unwrap_or_else(|| panic!("{ENABLED_ROUTES_ENV}: unknown route group {g:?}"))
+        })
+        .collect()
+}
+
+pub(crate) fn env_flag(name: &str) -> bool {
+    env_flag_or(name, false)
+}
+
+fn env_flag_or(name: &str, default: bool) -> bool {
+    match std::env::var(name) {
+        Ok(v) => matches!(v.trim(), "1" | "true" | "TRUE" | "yes"),
+        Err(_) => default,
+    }
+}
+
+pub(crate) fn env_usize(name: &str, default: usize) -> usize {
+    match std::env::var(name) {
+        Ok(raw) => raw
+            .trim()
+            .parse()
+            .unwrap_or_else(|_| panic!("{name} must be a non-negative integer")),
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, post_json, send};
+    use axum::{body::Body, extract::Request, http::StatusCode};
+
+    #[test]
+    #[should_panic(expected = "unknown route group \"isue\"")]
+    fn a_misspelled_route_group_stops_startup() {
+        route_groups("verify, isue");
+    }
+
+    #[test]
+    fn anonymous_issuance_also_needs_the_issue_group() {
+        let mut config = testing::config();
+        config.allow_anonymous = true;
+        config.enabled_routes = route_groups("verify");
+        assert!(!config.route_enabled("anonymous"));
+        config.enabled_routes = route_groups("verify,issue");
+        assert!(config.route_enabled("anonymous"));
+    }
+
+    #[test]
+    fn path_prefixes_are_normalized() {
+        for (raw, expected) in [
+            ("", ""),
+            ("/", ""),
+            ("auth", "/auth"),
+            ("/auth/", "/auth"),
+            (" /a/b ", "/a/b"),
+        ] {
+            assert_eq!(path_prefix(raw), expected, "{raw:?}");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid path prefix")]
+    fn a_path_prefix_with_a_query_stops_startup() {
+        path_prefix("/auth?x=1");
+    }
+
+    #[tokio::test]
+    async fn every_route_moves_under_the_prefix() {
+        let mut config = testing::config();
+        config.path_prefix = path_prefix("/auth/");
+        let state = testing::state_with(config);
+        let get = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+
+        assert_eq!(
+            send(&state, get("/auth/api/capabilities")).await.0,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(&state, get("/api/capabilities")).await.0,
+            StatusCode::NOT_FOUND
+        );
+        let verify = post_json("/auth/api/step1/verify", &serde_json::json!({}));
+        assert_ne!(send(&state, verify).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn a_group_left_out_is_not_mounted() {
+        let mut config = testing::config();
+        config.enabled_routes = route_groups(" verify ,");
+        let state = testing::state_with(config);
+
+        let issue = post_json("/api/step2/issue-credentials", &serde_json::json!({}));
+        assert_eq!(send(&state, issue).await.0, StatusCode::NOT_FOUND);
+        let verify = post_json("/api/step1/verify", &serde_json::json!({}));
+        assert_ne!(send(&state, verify).await.0, StatusCode::NOT_FOUND);
+        let capabilities = Request::get("/api/capabilities")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&state, capabilities).await.0, StatusCode::OK);
+    }
+}
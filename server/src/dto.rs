@@ -0,0 +1,395 @@
+// Request and response bodies.
+
+use crate::snapshot;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct VerifyUseRequest {
+    pub(crate) username: String,
+    pub(crate) code: String,
+    // POC_POW only: a challenge from GET /api/step1/pow and its solution
+    #[serde(default)]
+    pub(crate) pow_challenge: Option<String>,
+    #[serde(default)]
+    pub(crate) pow_nonce: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct PowChallengeResponse {
+    pub(crate) challenge: String,
+    pub(crate) difficulty: u32,
+    pub(crate) expires_in_seconds: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RequestCodeRequest {
+    pub(crate) username: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct VerifyUserResponse {
+    pub(crate) verification_token: String,
+    pub(crate) expires_in_seconds: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CodeSentResponse {
+    pub(crate) code_sent: bool,
+    pub(crate) expires_in_seconds: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct IssueTemporaryCredentialsRequest {
+    pub(crate) verification_token: String,
+    // e.g. "laptop"; see parse::validate_label
+    pub(crate) label: Option<String>,
+    // "raw" (default) or "pem"
+    pub(crate) format: Option<String>,
+}
+
+// POC_ALLOW_ANONYMOUS: a solved challenge from GET /api/step1/pow instead of a verification token
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct IssueAnonymousRequest {
+    #[serde(default)]
+    pub(crate) pow_challenge: Option<String>,
+    #[serde(default)]
+    pub(crate) pow_nonce: Option<String>,
+    pub(crate) label: Option<String>,
+    pub(crate) format: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct IssueTemporaryCredentialsResponse {
+    pub(crate) credential_id: String,
+    pub(crate) kid: String,
+    // raw: base64url 32-byte seed; pem: PKCS#8 PEM
+    pub(crate) credential_private: String,
+    // pem only: SPKI PEM of the public key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) public_key_pem: Option<String>,
+    pub(crate) expires_in_seconds: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RotateCredentialRequest {
+    pub(crate) credential_id: String,
+    pub(crate) new_public_key: String,
+    pub(crate) signature: String,
+}
+
+// The parent signs "delegate:" + canonical JSON of
+// {"parent_credential_id", "public_key", "scopes", "ttl_seconds"}, with fields as sent.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DelegateCredentialRequest {
+    pub(crate) parent_credential_id: String,
+    // the child's key, generated by the delegate; base64url, 32 bytes
+    pub(crate) public_key: String,
+    pub(crate) ttl_seconds: u64,
+    // None: the parent's scopes
+    pub(crate) scopes: Option<Vec<String>>,
+    pub(crate) label: Option<String>,
+    pub(crate) signature: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct DelegateCredentialResponse {
+    pub(crate) credential_id: String,
+    pub(crate) kid: String,
+    pub(crate) parent_credential_id: String,
+    pub(crate) scopes: Vec<String>,
+    pub(crate) expires_in_seconds: u64,
+}
+
+// POST /api/verify-signature: no credential involved, the caller names the key
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct VerifySignatureRequest {
+    pub(crate) public_key: String,
+    pub(crate) message: String,
+    pub(crate) signature: String,
+    // "ed25519" (default) or "ed25519ph"
+    pub(crate) alg: Option<String>,
+    // "utf8" (default) or "base64url"
+    pub(crate) message_encoding: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct VerifySignatureResponse {
+    pub(crate) valid: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct RotateCredentialResponse {
+    pub(crate) credential_id: String,
+    pub(crate) kid: String,
+    pub(crate) expires_in_seconds: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CredentialPublicKeyResponse {
+    pub(crate) credential_id: String,
+    pub(crate) kid: String,
+    pub(crate) algorithm: &'static str,
+    pub(crate) public_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) label: Option<String>,
+    pub(crate) expires_in_seconds: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CredentialListQuery {
+    // default DEFAULT_CREDENTIAL_PAGE, at most MAX_CREDENTIAL_PAGE
+    pub(crate) limit: Option<usize>,
+    #[serde(default)]
+    pub(crate) offset: usize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CredentialSummary {
+    pub(crate) credential_id: String,
+    pub(crate) kid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) label: Option<String>,
+    pub(crate) issued_at: u64,
+    pub(crate) expires_in_seconds: u64,
+    pub(crate) uses: u32,
+    // the credential behind the caller's session
+    pub(crate) current: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) parent_credential_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CredentialListResponse {
+    pub(crate) credentials: Vec<CredentialSummary>,
+    // before limit/offset
+    pub(crate) total: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct EnterSessionRequest {
+    pub(crate) credential_id: String,
+    // message + signature, or jws (with message only for a detached payload)
+    #[serde(default)]
+    pub(crate) message: String,
+    #[serde(default)]
+    pub(crate) signature: String,
+    pub(crate) jws: Option<String>,
+    // "ed25519" (default) or "ed25519ph"
+    pub(crate) alg: Option<String>,
+    // "utf8" (default) or "base64url"; see payload::message_bytes
+    pub(crate) message_encoding: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EnterSessionResponse {
+    pub(crate) session_token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) csrf_token: Option<String>,
+    pub(crate) expires_in_seconds: u64,
+    // how long the credential can still enter sessions, so clients know when to re-verify
+    pub(crate) credential_expires_in_seconds: u64,
+    // POC_SESSION_RECEIPTS: signed proof that this server created the session
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) receipt: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ScopedTokenRequest {
+    // space-separated, e.g. "preferences:read"
+    pub(crate) scope: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ScopedTokenResponse {
+    pub(crate) access_token: String,
+    pub(crate) token_type: &'static str,
+    pub(crate) scope: String,
+    pub(crate) expires_in_seconds: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PreferencesBatchRecord {
+    pub(crate) session_or_user: String,
+    pub(crate) preferences: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RevokeVerificationRequest {
+    pub(crate) verification_token: String,
+    // also drop the sessions entered with the derived credentials
+    #[serde(default)]
+    pub(crate) sessions: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RevokeCredentialRequest {
+    pub(crate) credential_id: String,
+    // also drop the sessions entered with it and its delegated credentials
+    #[serde(default)]
+    pub(crate) sessions: bool,
+}
+
+// The snapshot holds bearer tokens; `confirm` keeps a stray call from dumping or
+// overwriting them.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ExportStateRequest {
+    #[serde(default)]
+    pub(crate) confirm: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ImportStateRequest {
+    #[serde(default)]
+    pub(crate) confirm: bool,
+    pub(crate) snapshot: snapshot::Snapshot,
+}
+
+#[derive(Serialize)]
+pub(crate) struct RevokeVerificationResponse {
+    // false when the token was unknown or already expired and swept
+    pub(crate) verification_token_revoked: bool,
+    pub(crate) credentials_revoked: usize,
+    pub(crate) sessions_revoked: usize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct RevokeCredentialResponse {
+    // the credential itself plus every credential delegated from it
+    pub(crate) credentials_revoked: usize,
+    pub(crate) sessions_revoked: usize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct BatchLineResult {
+    line: usize,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+}
+
+#[derive(Serialize, Default)]
+pub(crate) struct ValidatePreferencesResponse {
+    valid: usize,
+    pub(crate) failed: usize,
+    pub(crate) results: Vec<BatchLineResult>,
+}
+
+impl ValidatePreferencesResponse {
+    pub(crate) fn push(&mut self, line: usize, result: Result<(), &'static str>) {
+        match result {
+            Ok(()) => self.valid += 1,
+            Err(_) => self.failed += 1,
+        }
+        self.results.push(BatchLineResult {
+            line,
+            ok: result.is_ok(),
+            error: result.err(),
+        });
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct MapStats {
+    pub(crate) count: usize,
+    pub(crate) cap: usize,
+    // expired entries removed by the cleanup since startup
+    pub(crate) reaped: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct AdminStatsResponse {
+    pub(crate) verification_tokens: MapStats,
+    pub(crate) temporary_credentials: MapStats,
+    pub(crate) sessions: MapStats,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Utilization {
+    pub(crate) used: usize,
+    pub(crate) limit: usize,
+    // used / limit, 0.0–1.0
+    pub(crate) ratio: f64,
+}
+
+impl Utilization {
+    pub(crate) fn new(used: usize, limit: usize) -> Self {
+        let ratio = match limit {
+            0 => 0.0,
+            _ => (used as f64 / limit as f64).min(1.0),
+        };
+        Utilization { used, limit, ratio }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct AdminCapacityResponse {
+    pub(crate) verification_tokens: Utilization,
+    pub(crate) temporary_credentials: Utilization,
+    pub(crate) sessions: Utilization,
+    // None when POC_MAX_CONCURRENT_REQUESTS is 0; includes this request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) requests: Option<Utilization>,
+    // the highest ratio above: how close the first limit is
+    pub(crate) pressure: f64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CapabilitiesResponse {
+    pub(crate) version: &'static str,
+    pub(crate) auth_modes: Vec<&'static str>,
+    pub(crate) signature_algorithms: &'static [&'static str],
+    pub(crate) server_minted_keys: bool,
+    // prepended to every endpoint path below
+    pub(crate) path_prefix: String,
+    // the `aud` step 3 payloads must carry (POC_AUDIENCE)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) audience: Option<String>,
+    // responses carry X-Body-Signature, verifiable with GET /api/jwks
+    pub(crate) response_signing: bool,
+    // step 3 returns a `receipt`, verifiable with GET /api/jwks
+    pub(crate) session_receipts: bool,
+    pub(crate) endpoints: Vec<&'static str>,
+    pub(crate) ttls: CapabilityTtls,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CapabilityTtls {
+    pub(crate) verification_seconds: u64,
+    pub(crate) temporary_credential_seconds: u64,
+    pub(crate) session_seconds: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct JwksResponse {
+    pub(crate) keys: Vec<Value>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ReadyResponse {
+    pub(crate) ready: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct VersionResponse {
+    pub(crate) version: &'static str,
+    pub(crate) git_commit: &'static str,
+    pub(crate) build_timestamp: &'static str,
+    pub(crate) rustc_version: &'static str,
+    pub(crate) path_prefix: String,
+}
@@ -0,0 +1,473 @@
+// Error responses and the JSON body extractor that produces API error codes.
+
+use crate::{auth::AuthError, messages, request_log::current_request_id};
+use axum::{
+    Json, async_trait,
+    body::Bytes,
+    extract::{FromRequest, Request, rejection::JsonRejection},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::{Serialize, de::DeserializeOwned};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// POC_PRETTY_JSON, set once from Config at startup; the helpers below have no state to read.
+static PRETTY_JSON: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_pretty_json(pretty: bool) {
+    PRETTY_JSON.store(pretty, Ordering::Relaxed);
+}
+
+// Every JSON body the API writes goes through here, so POC_PRETTY_JSON covers all of them.
+// Same content-type as axum's Json; only the whitespace differs.
+pub(crate) fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response {
+    let encoded = if PRETTY_JSON.load(Ordering::Relaxed) {
+        serde_json::to_vec_pretty(body)
+    } else {
+        serde_json::to_vec(body)
+    };
+    match encoded {
+        Ok(bytes) => (
+            status,
+            [(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            )],
+            bytes,
+        )
+            .into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct ErrorResponse {
+    pub(crate) error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) message: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) field: Option<String>,
+    // insufficient_scope: the scope the token is missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) scope: Option<&'static str>,
+    // wrong one-time code: guesses left before the code is discarded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) attempts_remaining: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) request_id: Option<String>,
+}
+
+// An error body not yet rendered. Helpers return Result<_, ApiError> rather than
+// Result<_, Response>: it is a fraction of the size, and the handler renders it with
+// `into_response()` at its boundary.
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    pub(crate) status: StatusCode,
+    pub(crate) code: &'static str,
+    pub(crate) field: Option<String>,
+    pub(crate) scope: Option<&'static str>,
+    pub(crate) attempts_remaining: Option<u32>,
+}
+
+impl ApiError {
+    pub(crate) fn new(status: StatusCode, code: &'static str) -> Self {
+        ApiError {
+            status,
+            code,
+            field: None,
+            scope: None,
+            attempts_remaining: None,
+        }
+    }
+
+    pub(crate) fn field(mut self, field: &str) -> Self {
+        self.field = Some(field.into());
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        json_response(
+            self.status,
+            &ErrorResponse {
+                error: self.code.into(),
+                message: messages::message(messages::current_locale(), self.code),
+                field: self.field,
+                scope: self.scope,
+                attempts_remaining: self.attempts_remaining,
+                request_id: current_request_id(),
+            },
+        )
+    }
+}
+
+impl From<&AuthError> for ApiError {
+    fn from(e: &AuthError) -> Self {
+        ApiError {
+            attempts_remaining: e.attempts_remaining(),
+            ..ApiError::new(e.status(), e.code())
+        }
+    }
+}
+
+pub(crate) fn json_error(status: StatusCode, msg: &'static str) -> Response {
+    ApiError::new(status, msg).into_response()
+}
+
+pub(crate) fn json_error_field(status: StatusCode, msg: &'static str, field: &str) -> Response {
+    ApiError::new(status, msg).field(field).into_response()
+}
+
+pub(crate) fn auth_error(e: &AuthError) -> Response {
+    ApiError::from(e).into_response()
+}
+
+pub(crate) fn capacity_reached() -> Response {
+    json_error(StatusCode::SERVICE_UNAVAILABLE, "capacity_reached")
+}
+
+pub(crate) fn draining() -> Response {
+    json_error(StatusCode::SERVICE_UNAVAILABLE, "draining")
+}
+
+pub(crate) fn json_ok<T: Serialize>(status: StatusCode, body: T) -> Response {
+    json_response(status, &body)
+}
+
+// Json<T> whose DTOs deny unknown fields; a typo like `usernme` becomes
+// `unknown_field` naming the offender instead of a misleading `*_required`.
+// Bodies that are empty, not JSON-typed or not valid JSON also get API error codes.
+pub(crate) struct ApiJson<T>(pub(crate) T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for ApiJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let json_typed = is_json_content_type(req.headers());
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(|e| body_error(e.status()).into_response())?;
+
+        if body.is_empty() {
+            return Err(json_error(StatusCode::BAD_REQUEST, "empty_body"));
+        }
+        if !json_typed {
+            return Err(json_error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type",
+            ));
+        }
+
+        Json::<T>::from_bytes(&body)
+            .map(|Json(value)| ApiJson(value))
+            .map_err(|rejection| json_rejection(rejection).into_response())
+    }
+}
+
+// Every rejection gets an API code; none falls through to axum's text/plain body.
+fn json_rejection(rejection: JsonRejection) -> ApiError {
+    match rejection {
+        JsonRejection::JsonDataError(e) => data_error(&e.body_text()),
+        JsonRejection::JsonSyntaxError(_) => ApiError::new(StatusCode::BAD_REQUEST, "invalid_json"),
+        JsonRejection::MissingJsonContentType(_) => {
+            ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type")
+        }
+        JsonRejection::BytesRejection(e) => body_error(e.status()),
+        // non-exhaustive; anything new is still a body that didn't parse
+        _ => ApiError::new(StatusCode::BAD_REQUEST, "invalid_json"),
+    }
+}
+
+// A body that couldn't be read: over the route's DefaultBodyLimit (413), or a stream that
+// failed. axum would answer both in text/plain.
+pub(crate) fn body_error(status: StatusCode) -> ApiError {
+    match status {
+        StatusCode::PAYLOAD_TOO_LARGE => ApiError::new(status, "payload_too_large"),
+        _ => ApiError::new(StatusCode::BAD_REQUEST, "body_read_failed"),
+    }
+}
+
+// Valid JSON that doesn't fit the DTO. axum renders the error as
+// "Failed to deserialize ...: <path>: <serde message>", the path being "." at the top level.
+// An unexpected field is a malformed request (400); a missing or mistyped one is
+// rejected content (422), like the handlers' own `*_required` checks.
+fn data_error(text: &str) -> ApiError {
+    let detail = text.split_once("target type: ").map_or(text, |(_, d)| d);
+    let (path, msg) = match detail.split_once(": ") {
+        Some((path, msg)) if !path.contains(' ') => (path.trim_start_matches('.'), msg),
+        _ => ("", detail),
+    };
+    let join = |name: &str| match path {
+        "" => name.to_string(),
+        _ if path == name || path.ends_with(&format!(".{name}")) => path.to_string(),
+        _ => format!("{path}.{name}"),
+    };
+
+    if let Some(name) = backticked(msg, "unknown field `") {
+        return ApiError::new(StatusCode::BAD_REQUEST, "unknown_field").field(&join(name));
+    }
+    if let Some(name) = backticked(msg, "missing field `") {
+        return ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "field_required")
+            .field(&join(name));
+    }
+    let error = ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "field_invalid");
+    match path {
+        "" => error,
+        _ => error.field(path),
+    }
+}
+
+// application/json or any application/*+json, parameters ignored
+fn is_json_content_type(headers: &HeaderMap) -> bool {
+    let Some(value) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    let essence = value
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json"
+        || (essence.starts_with("application/") && essence.ends_with("+json"))
+}
+
+// serde names fields as "unknown field `name`, expected ..." or "missing field `name`"
+fn backticked<'a>(msg: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = msg.split(prefix).nth(1)?;
+    let end = rest.find('`')?;
+    Some(&rest[..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use serde::Deserialize;
+    use serde_json::Value;
+
+    async fn body_json(resp: Response) -> Value {
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn api_error_renders_the_json_error_body() {
+        let resp = ApiError::new(StatusCode::BAD_REQUEST, "unknown_field")
+            .field("usernme")
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            HeaderValue::from_static("application/json")
+        );
+        let body = body_json(resp).await;
+        assert_eq!(body["error"], "unknown_field");
+        assert_eq!(body["field"], "usernme");
+        assert!(body["message"].is_string());
+        assert!(body.get("scope").is_none());
+    }
+
+    #[tokio::test]
+    async fn auth_errors_carry_attempts_remaining() {
+        let e = AuthError::WrongCode {
+            attempts_remaining: 2,
+        };
+        let resp = ApiError::from(&e).into_response();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(body_json(resp).await["attempts_remaining"], 2);
+    }
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct Inner {
+        limit: u32,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct Dto {
+        name: String,
+        count: u64,
+        #[serde(default)]
+        inner: Option<Inner>,
+    }
+
+    // (status, error, field) for `body` posted as JSON
+    async fn reject(body: &str) -> (StatusCode, String, Value) {
+        let req = Request::post("/")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let Err(resp) = ApiJson::<Dto>::from_request(req, &()).await else {
+            panic!("{body} was accepted");
+        };
+        let status = resp.status();
+        let body = body_json(resp).await;
+        (
+            status,
+            body["error"].as_str().unwrap().into(),
+            body["field"].clone(),
+        )
+    }
+
+    #[tokio::test]
+    async fn every_json_rejection_gets_an_api_code() {
+        let cases = [
+            (
+                r#"{"name":"a","count":1,"nmae":"b"}"#,
+                StatusCode::BAD_REQUEST,
+                "unknown_field",
+                "nmae",
+            ),
+            (
+                r#"{"name":"a"}"#,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "field_required",
+                "count",
+            ),
+            (
+                r#"{"name":"a","count":"1"}"#,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "field_invalid",
+                "count",
+            ),
+            (
+                r#"{"name":"a","count":-1}"#,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "field_invalid",
+                "count",
+            ),
+            (
+                r#"{"name":"a","count":1,"inner":{}}"#,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "field_required",
+                "inner.limit",
+            ),
+            (
+                r#"{"name":"a","count":1,"inner":{"limit":true}}"#,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "field_invalid",
+                "inner.limit",
+            ),
+            (
+                r#"{"name":"a","count":1,"inner":{"limit":1,"x":1}}"#,
+                StatusCode::BAD_REQUEST,
+                "unknown_field",
+                "inner.x",
+            ),
+        ];
+        for (body, status, error, field) in cases {
+            assert_eq!(
+                reject(body).await,
+                (status, error.to_string(), Value::from(field)),
+                "{body}"
+            );
+        }
+
+        // no field to name
+        let (status, error, field) = reject(r#""text""#).await;
+        assert_eq!(
+            (status, error.as_str()),
+            (StatusCode::UNPROCESSABLE_ENTITY, "field_invalid")
+        );
+        assert!(field.is_null(), "{field}");
+        for body in ["{", r#"{"name":"a",}"#, "nope"] {
+            let (status, error, _) = reject(body).await;
+            assert_eq!(
+                (status, error.as_str()),
+                (StatusCode::BAD_REQUEST, "invalid_json"),
+                "{body}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_missing_step1_field_is_named() {
+        let state = crate::testing::state();
+        let req =
+            crate::testing::post_json("/api/step1/verify", &serde_json::json!({ "usernme": "a" }));
+        let (status, _, body) = crate::testing::send(&state, req).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "unknown_field");
+        assert_eq!(body["field"], "usernme");
+    }
+
+    #[tokio::test]
+    async fn a_body_that_is_not_json_typed_is_a_415() {
+        let state = crate::testing::state();
+        let body = r#"{"username":"alice","password":"p"}"#;
+        for content_type in [
+            Some("text/plain"),
+            Some("application/x-www-form-urlencoded"),
+            None,
+        ] {
+            let mut req = Request::post("/api/step1/verify");
+            if let Some(content_type) = content_type {
+                req = req.header(header::CONTENT_TYPE, content_type);
+            }
+            let req = req.body(Body::from(body)).unwrap();
+            let (status, _, body) = crate::testing::send(&state, req).await;
+            assert_eq!(
+                status,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "{content_type:?}"
+            );
+            assert_eq!(body["error"], "unsupported_media_type");
+        }
+
+        // a +json type or parameters are still JSON
+        for content_type in [
+            "application/vnd.api+json",
+            "application/json; charset=utf-8",
+        ] {
+            let req = Request::post("/api/step1/verify")
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(body))
+                .unwrap();
+            let (status, _, _) = crate::testing::send(&state, req).await;
+            assert_ne!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE, "{content_type}");
+        }
+    }
+
+    #[tokio::test]
+    async fn an_oversized_body_is_a_json_413() {
+        let state = crate::testing::state();
+        let padding = "x".repeat(3 * 1024 * 1024);
+        let body = serde_json::json!({ "username": padding, "password": "p" });
+        let req = crate::testing::post_json("/api/step1/verify", &body);
+        let (status, headers, body) = crate::testing::send(&state, req).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        assert_eq!(body["error"], "payload_too_large");
+    }
+
+    #[tokio::test]
+    async fn a_non_multipart_upload_is_a_json_error() {
+        let state = crate::testing::state();
+        let req = crate::testing::post_json("/api/step3/enter-multipart", &serde_json::json!({}));
+        let (status, _, body) = crate::testing::send(&state, req).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "multipart_invalid");
+    }
+
+    #[test]
+    fn api_error_stays_small() {
+        // under clippy::result_large_err's default threshold, which Response is not
+        assert!(std::mem::size_of::<ApiError>() < 128);
+        assert!(std::mem::size_of::<Response>() >= 128);
+    }
+}
@@ -0,0 +1,125 @@
+// Internal event bus. Handlers and the cleanup task publish what happened; hooks
+// (audit log, metrics, streaming) subscribe instead of being called from each handler.
+//
+// The channel is bounded. Publishing never waits: a subscriber that falls more than
+// EVENT_BUS_CAPACITY events behind loses the oldest ones and its next `recv` returns
+// `RecvError::Lagged(n)` with the number skipped, after which it continues from the
+// oldest event still buffered. Events carry ids, never tokens or keys.
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+pub const EVENT_BUS_CAPACITY: usize = 1024;
+pub const LOG_EVENTS_ENV: &str = "POC_LOG_EVENTS";
+
+// Serialized as {"event": "credential_issued", ...fields}, one JSON object per line.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    UserVerified {
+        username: String,
+    },
+    CredentialIssued {
+        credential_id: String,
+    },
+    CredentialRotated {
+        credential_id: String,
+    },
+    CredentialDelegated {
+        parent_credential_id: String,
+        credential_id: String,
+    },
+    SessionCreated {
+        credential_id: String,
+    },
+    ScopedTokenIssued {
+        scope: String,
+    },
+    // admin revocation of a verification token and what cascaded from it
+    VerificationRevoked {
+        credentials: usize,
+        sessions: usize,
+    },
+    // admin revocation of a credential; `credentials` includes everything delegated from it
+    CredentialRevoked {
+        credential_id: String,
+        credentials: usize,
+        sessions: usize,
+    },
+    // one cleanup pass that removed something
+    Expired {
+        verification_tokens: usize,
+        temporary_credentials: usize,
+        sessions: usize,
+    },
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        EventBus { tx }
+    }
+
+    // Dropped silently when nobody is subscribed.
+    pub fn publish(&self, event: Event) {
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+}
+
+// POC_LOG_EVENTS=true: prints every event as a JSON line, as a reference subscriber.
+pub async fn log_events(mut rx: broadcast::Receiver<Event>) {
+    loop {
+        match rx.recv().await {
+            Ok(event) => println!("event: {}", event_json(&event)),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                println!("event log fell behind, {skipped} events skipped")
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+pub fn event_json(event: &Event) -> String {
+    // only strings and integers inside, so serialization can't fail
+    serde_json::to_string(event).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_serialize_with_their_fields() {
+        let event = Event::CredentialDelegated {
+            parent_credential_id: "parent".into(),
+            credential_id: "child".into(),
+        };
+        assert_eq!(
+            event_json(&event),
+            r#"{"event":"credential_delegated","parent_credential_id":"parent","credential_id":"child"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_published_events() {
+        let bus = EventBus::new(4);
+        let mut rx = bus.subscribe();
+        bus.publish(Event::ScopedTokenIssued {
+            scope: "preferences:read".into(),
+        });
+        let event = rx.recv().await.unwrap();
+        assert_eq!(
+            event_json(&event),
+            r#"{"event":"scoped_token_issued","scope":"preferences:read"}"#
+        );
+    }
+}
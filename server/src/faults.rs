@@ -0,0 +1,114 @@
+// Failure injection for exercising client retry and backoff. Off unless
+// POC_FAULT_ENABLE=true; the other POC_FAULT_* variables without it stop startup, so a
+// leftover setting can't quietly turn it on in a real deployment.
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use std::{sync::Arc, time::Duration};
+
+pub const FAULT_ENABLE_ENV: &str = "POC_FAULT_ENABLE";
+// fraction of matching requests answered 503, 0.0..=1.0
+pub const FAULT_RATE_ENV: &str = "POC_FAULT_RATE";
+// delay added to every matching request
+pub const FAULT_LATENCY_ENV: &str = "POC_FAULT_LATENCY_MS";
+// comma-separated route paths, e.g. /api/step1/verify; unset means every route
+pub const FAULT_PATHS_ENV: &str = "POC_FAULT_PATHS";
+
+pub struct Faults {
+    rate: f64,
+    latency: Duration,
+    // empty: every route
+    paths: Vec<String>,
+}
+
+impl Faults {
+    // None when disabled.
+    pub fn from_env() -> Option<Arc<Self>> {
+        let enabled = matches!(
+            std::env::var(FAULT_ENABLE_ENV).as_deref().map(str::trim),
+            Ok("true")
+        );
+        let configured = [FAULT_RATE_ENV, FAULT_LATENCY_ENV, FAULT_PATHS_ENV]
+            .into_iter()
+            .find(|name| std::env::var(name).is_ok());
+        if !enabled {
+            if let Some(name) = configured {
+                panic!("{name} is set but failure injection is off; set {FAULT_ENABLE_ENV}=true");
+            }
+            return None;
+        }
+
+        let rate = match std::env::var(FAULT_RATE_ENV) {
+            Ok(raw) => raw
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|r| (0.0..=1.0).contains(r))
+                .unwrap_or_else(|| panic!("{FAULT_RATE_ENV} must be between 0 and 1")),
+            Err(_) => 0.0,
+        };
+        let latency = match std::env::var(FAULT_LATENCY_ENV) {
+            Ok(raw) => Duration::from_millis(
+                raw.trim()
+                    .parse()
+                    .unwrap_or_else(|_| panic!("{FAULT_LATENCY_ENV} must be a number of ms")),
+            ),
+            Err(_) => Duration::ZERO,
+        };
+        let paths = std::env::var(FAULT_PATHS_ENV)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                assert!(
+                    p.starts_with('/'),
+                    "{FAULT_PATHS_ENV}: {p:?} must start with /"
+                );
+                p.to_string()
+            })
+            .collect();
+
+        Some(Arc::new(Faults {
+            rate,
+            latency,
+            paths,
+        }))
+    }
+
+    pub fn describe(&self) -> String {
+        let scope = if self.paths.is_empty() {
+            "all routes".to_string()
+        } else {
+            self.paths.join(", ")
+        };
+        format!(
+            "rate {}, latency {} ms, {scope}",
+            self.rate,
+            self.latency.as_millis()
+        )
+    }
+
+    fn applies_to(&self, path: &str) -> bool {
+        self.paths.is_empty() || self.paths.iter().any(|p| p == path)
+    }
+}
+
+// Matches on the path without POC_PATH_PREFIX. The delay comes first, so an injected
+// 503 is also slow, like a real overloaded upstream.
+pub async fn inject(State(faults): State<Arc<Faults>>, req: Request, next: Next) -> Response {
+    if !faults.applies_to(req.uri().path()) {
+        return next.run(req).await;
+    }
+    if !faults.latency.is_zero() {
+        tokio::time::sleep(faults.latency).await;
+    }
+    if faults.rate > 0.0 && rand::random::<f64>() < faults.rate {
+        return crate::error::json_error(StatusCode::SERVICE_UNAVAILABLE, "fault_injected");
+    }
+    next.run(req).await
+}
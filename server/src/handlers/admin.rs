@@ -0,0 +1,529 @@
+// /api/admin/* and /api/verify-signature: every handler checks the admin token first.
+
+use crate::{
+    auth::{SignatureAlg, SignedData, decode_signature, require_admin, verify_signature},
+    config::{MAX_BATCH_LINE_BYTES, MAX_BATCH_LINES, MAX_MESSAGE_BYTES},
+    dto::{
+        AdminCapacityResponse, AdminStatsResponse, ExportStateRequest, ImportStateRequest,
+        MapStats, PreferencesBatchRecord, RevokeCredentialRequest, RevokeCredentialResponse,
+        RevokeVerificationRequest, RevokeVerificationResponse, Utilization,
+        ValidatePreferencesResponse, VerifySignatureRequest, VerifySignatureResponse,
+    },
+    error::{ApiJson, draining, json_error, json_ok},
+    events::Event,
+    parse::{decode_public_key, out_of_range_number, validate_preferences},
+    snapshot,
+    state::{AppState, is_draining},
+    store::{revoke_credentials, sweep_expired},
+};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use futures_util::StreamExt;
+use std::{sync::atomic::Ordering, time::Instant};
+
+fn validate_line(line: &[u8], max_number: u64) -> Result<(), &'static str> {
+    let record: PreferencesBatchRecord =
+        serde_json::from_slice(line).map_err(|_| "invalid_record")?;
+    if record.session_or_user.trim().is_empty() {
+        return Err("session_or_user_required");
+    }
+    validate_preferences(&record.preferences)?;
+    match out_of_range_number(&record.preferences, max_number) {
+        Some(_) => Err("invalid_preference_value"),
+        None => Ok(()),
+    }
+}
+
+pub(super) async fn admin_stats(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(e) = require_admin(&state, &headers) {
+        return e.into_response();
+    }
+
+    json_ok(
+        StatusCode::OK,
+        AdminStatsResponse {
+            verification_tokens: MapStats {
+                count: state.verification_tokens.len(),
+                cap: state.config.max_verification_tokens,
+                reaped: state.reaped.verification_tokens.load(Ordering::Relaxed),
+            },
+            temporary_credentials: MapStats {
+                count: state.temporary_credentials.len(),
+                cap: state.config.max_temporary_credentials,
+                reaped: state.reaped.temporary_credentials.load(Ordering::Relaxed),
+            },
+            sessions: MapStats {
+                count: state.sessions.len(),
+                cap: state.config.max_sessions,
+                reaped: state.reaped.sessions.load(Ordering::Relaxed),
+            },
+        },
+    )
+}
+
+// For autoscalers: how full each bounded resource is, and `pressure`, the fullest one.
+// Map counts include expired entries the cleanup hasn't reaped yet; those are swept
+// before a full map refuses anything, so pressure can read high a little early.
+pub(super) async fn admin_capacity(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(e) = require_admin(&state, &headers) {
+        return e.into_response();
+    }
+    let config = &state.config;
+    let verification_tokens = Utilization::new(
+        state.verification_tokens.len(),
+        config.max_verification_tokens,
+    );
+    let temporary_credentials = Utilization::new(
+        state.temporary_credentials.len(),
+        config.max_temporary_credentials,
+    );
+    let sessions = Utilization::new(state.sessions.len(), config.max_sessions);
+    let requests = state.request_permits.as_ref().map(|permits| {
+        let limit = config.max_concurrent_requests;
+        Utilization::new(limit.saturating_sub(permits.available_permits()), limit)
+    });
+    let pressure = [
+        Some(&verification_tokens),
+        Some(&temporary_credentials),
+        Some(&sessions),
+        requests.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|u| u.ratio)
+    .fold(0.0, f64::max);
+
+    json_ok(
+        StatusCode::OK,
+        AdminCapacityResponse {
+            verification_tokens,
+            temporary_credentials,
+            sessions,
+            requests,
+            pressure,
+        },
+    )
+}
+
+// Revokes a verification token and every credential issued from it, optionally with
+// their sessions. Credentials are revoked even when the token itself already expired.
+pub(super) async fn revoke_verification(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ApiJson(req): ApiJson<RevokeVerificationRequest>,
+) -> Response {
+    if let Err(e) = require_admin(&state, &headers) {
+        return e.into_response();
+    }
+    let token = req.verification_token.trim();
+    if token.is_empty() {
+        return json_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "verification_token_required",
+        );
+    }
+
+    let verification_token_revoked = state.verification_tokens.remove(token).is_some();
+
+    // collect first: no guard into the map may be held while removing from it
+    let credential_ids: Vec<String> = state
+        .temporary_credentials
+        .iter()
+        .filter(|c| c.verification_token == token)
+        .map(|c| c.key().clone())
+        .collect();
+
+    let (credentials_revoked, sessions_revoked) =
+        revoke_credentials(&state, credential_ids, req.sessions);
+
+    state.events.publish(Event::VerificationRevoked {
+        credentials: credentials_revoked,
+        sessions: sessions_revoked,
+    });
+
+    json_ok(
+        StatusCode::OK,
+        RevokeVerificationResponse {
+            verification_token_revoked,
+            credentials_revoked,
+            sessions_revoked,
+        },
+    )
+}
+
+// Revokes one credential and everything delegated from it, optionally with their sessions.
+pub(super) async fn revoke_credential(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ApiJson(req): ApiJson<RevokeCredentialRequest>,
+) -> Response {
+    if let Err(e) = require_admin(&state, &headers) {
+        return e.into_response();
+    }
+    let credential_id = req.credential_id.trim();
+    if credential_id.is_empty() {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "credential_id_required");
+    }
+
+    let (credentials_revoked, sessions_revoked) =
+        revoke_credentials(&state, vec![credential_id.to_string()], req.sessions);
+    if credentials_revoked == 0 {
+        return json_error(StatusCode::NOT_FOUND, "credential_not_found");
+    }
+
+    state.events.publish(Event::CredentialRevoked {
+        credential_id: credential_id.to_string(),
+        credentials: credentials_revoked,
+        sessions: sessions_revoked,
+    });
+
+    json_ok(
+        StatusCode::OK,
+        RevokeCredentialResponse {
+            credentials_revoked,
+            sessions_revoked,
+        },
+    )
+}
+
+pub(super) async fn export_state(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ApiJson(req): ApiJson<ExportStateRequest>,
+) -> Response {
+    if let Err(e) = require_admin(&state, &headers) {
+        return e.into_response();
+    }
+    if !req.confirm {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "confirmation_required");
+    }
+    json_ok(StatusCode::OK, snapshot::export(&state, Instant::now()))
+}
+
+pub(super) async fn import_state(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ApiJson(req): ApiJson<ImportStateRequest>,
+) -> Response {
+    if let Err(e) = require_admin(&state, &headers) {
+        return e.into_response();
+    }
+    if !req.confirm {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "confirmation_required");
+    }
+    if is_draining(&state) {
+        return draining();
+    }
+    match snapshot::import(&state, req.snapshot, Instant::now()) {
+        Ok(summary) => json_ok(StatusCode::OK, summary),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Runs the periodic sweep now, e.g. so a demo doesn't have to wait for it.
+pub(super) async fn admin_cleanup(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(e) = require_admin(&state, &headers) {
+        return e.into_response();
+    }
+    json_ok(StatusCode::OK, sweep_expired(&state))
+}
+
+// Checks many users' preferences at once, e.g. before a migration. Like the single-user
+// endpoint it stores nothing: the answer is which lines would be accepted.
+// NDJSON body, parsed as it streams in. Only the current line is buffered (up to
+// MAX_BATCH_LINE_BYTES); longer lines are skipped and reported as line_too_long.
+pub(super) async fn validate_preferences_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    if let Err(e) = require_admin(&state, &headers) {
+        return e.into_response();
+    }
+
+    let mut stream = body.into_data_stream();
+    let mut summary = ValidatePreferencesResponse::default();
+    let mut line_buf: Vec<u8> = Vec::new();
+    let mut line_no = 0;
+    let mut overflowed = false;
+
+    let mut finish_line = |buf: &mut Vec<u8>, overflowed: &mut bool| -> bool {
+        line_no += 1;
+        if *overflowed {
+            summary.push(line_no, Err("line_too_long"));
+        } else if !buf.iter().all(u8::is_ascii_whitespace) {
+            summary.push(
+                line_no,
+                validate_line(buf, state.config.preferences_max_number),
+            );
+        }
+        buf.clear();
+        *overflowed = false;
+        summary.results.len() <= MAX_BATCH_LINES
+    };
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(c) => c,
+            Err(_) => {
+                return json_error(StatusCode::BAD_REQUEST, "body_read_failed");
+            }
+        };
+
+        for (i, part) in chunk.split(|&b| b == b'\n').enumerate() {
+            // every part after the first starts a new line
+            if i > 0 && !finish_line(&mut line_buf, &mut overflowed) {
+                return json_error(StatusCode::PAYLOAD_TOO_LARGE, "batch_too_many_lines");
+            }
+            if overflowed {
+                continue;
+            }
+            if line_buf.len() + part.len() > MAX_BATCH_LINE_BYTES {
+                line_buf.clear();
+                overflowed = true;
+            } else {
+                line_buf.extend_from_slice(part);
+            }
+        }
+    }
+
+    if (overflowed || !line_buf.is_empty()) && !finish_line(&mut line_buf, &mut overflowed) {
+        return json_error(StatusCode::PAYLOAD_TOO_LARGE, "batch_too_many_lines");
+    }
+
+    json_ok(StatusCode::OK, summary)
+}
+
+// Stateless check of any Ed25519 signature, e.g. for tooling. Admin-only so it can't
+// serve as a free verification oracle. The message is signed as sent (no v1 payload
+// rules), with POC_SIGNING_CONTEXT applied exactly as in step 3.
+pub(super) async fn verify_external_signature(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ApiJson(req): ApiJson<VerifySignatureRequest>,
+) -> Response {
+    if let Err(e) = require_admin(&state, &headers) {
+        return e.into_response();
+    }
+    if req.public_key.trim().is_empty() {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "public_key_required");
+    }
+    if req.message.is_empty() {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "message_required");
+    }
+    if req.signature.is_empty() {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "signature_required");
+    }
+    if req.message.len() > MAX_MESSAGE_BYTES {
+        return json_error(StatusCode::PAYLOAD_TOO_LARGE, "message_too_large");
+    }
+
+    let Some(public_key) = decode_public_key(req.public_key.trim()) else {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "public_key_invalid");
+    };
+    let alg = match SignatureAlg::parse(req.alg.as_deref()) {
+        Ok(alg) => alg,
+        Err(e) => return e.into_response(),
+    };
+    let message = match req.message_encoding.as_deref().unwrap_or("utf8") {
+        "utf8" => req.message.into_bytes(),
+        "base64url" => match URL_SAFE_NO_PAD.decode(&req.message) {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                return json_error(StatusCode::UNPROCESSABLE_ENTITY, "message_not_base64url");
+            }
+        },
+        _ => {
+            return json_error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "message_encoding_unsupported",
+            );
+        }
+    };
+    let signature = match decode_signature(&req.signature) {
+        Ok(s) => s,
+        Err(e) => return e.into_response(),
+    };
+
+    let data = SignedData::new(alg, &state.config.signing_context, &message);
+    let valid = verify_signature(&state, public_key, data, signature).await;
+    json_ok(StatusCode::OK, VerifySignatureResponse { valid })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::MAX_BATCH_LINE_BYTES,
+        store::Deadline,
+        testing::{self, ADMIN_TOKEN, credential, post_json, send, session, with_bearer},
+    };
+    use axum::{
+        body::Body,
+        extract::Request,
+        http::{StatusCode, header},
+    };
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn admin_routes_refuse_with_json_errors() {
+        let state = testing::state_with(testing::admin_config());
+        let req = post_json("/api/admin/cleanup", &serde_json::json!({}));
+        let (status, _, body) = send(&state, with_bearer(req, "wrong")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"], "admin_token_invalid");
+
+        let req = post_json("/api/admin/cleanup", &serde_json::json!({}));
+        let (status, _, _) = send(&state, with_bearer(req, ADMIN_TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn cleanup_on_demand_reaps_and_reports() {
+        let state = testing::state_with(testing::admin_config());
+        let key = SigningKey::generate(&mut OsRng);
+        let live = credential(&state, &key);
+        let stale = credential(&state, &key);
+        state
+            .temporary_credentials
+            .get_mut(&stale)
+            .unwrap()
+            .expires_at = Deadline::at(Instant::now());
+
+        let req = post_json("/api/admin/cleanup", &serde_json::json!({}));
+        let (status, _, body) = send(&state, with_bearer(req, ADMIN_TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "verification_tokens": 0,
+                "temporary_credentials": 1,
+                "sessions": 0,
+            })
+        );
+        assert!(state.temporary_credentials.contains_key(&live));
+        assert!(!state.temporary_credentials.contains_key(&stale));
+    }
+
+    #[tokio::test]
+    async fn cleanup_is_off_without_an_admin_token() {
+        let state = testing::state();
+        let req = post_json("/api/admin/cleanup", &serde_json::json!({}));
+        let (status, _, body) = send(&state, with_bearer(req, ADMIN_TOKEN)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"], "admin_disabled");
+    }
+
+    #[tokio::test]
+    async fn pressure_rises_as_the_credentials_fill_up() {
+        let mut config = testing::admin_config();
+        config.max_temporary_credentials = 4;
+        let state = testing::state_with(config);
+        let key = SigningKey::generate(&mut OsRng);
+        let capacity = || async {
+            let req = Request::get("/api/admin/capacity")
+                .body(Body::empty())
+                .unwrap();
+            let (status, _, body) = send(&state, with_bearer(req, ADMIN_TOKEN)).await;
+            assert_eq!(status, StatusCode::OK);
+            body
+        };
+
+        let mut last = capacity().await["pressure"].as_f64().unwrap();
+        assert!(last < 0.25);
+        for used in 1..=4 {
+            credential(&state, &key);
+            let body = capacity().await;
+            assert_eq!(body["temporary_credentials"]["used"], used);
+            assert_eq!(body["temporary_credentials"]["limit"], 4);
+            let pressure = body["pressure"].as_f64().unwrap();
+            assert!(pressure > last, "{pressure} after {last}");
+            last = pressure;
+        }
+        assert_eq!(last, 1.0);
+        // the request asking counts toward the concurrency limit
+        assert_eq!(capacity().await["requests"]["used"], 1);
+    }
+
+    #[tokio::test]
+    async fn revoking_a_verification_cascades_to_credentials_and_sessions() {
+        let state = testing::state_with(testing::admin_config());
+        let token = testing::verification(&state);
+        let key = SigningKey::generate(&mut OsRng);
+        let derived = credential(&state, &key);
+        let other = credential(&state, &key);
+        state
+            .temporary_credentials
+            .get_mut(&other)
+            .unwrap()
+            .verification_token = "other-verification".into();
+        let derived_session = session(&state, &key, &derived);
+        let other_session = session(&state, &key, &other);
+
+        let req = post_json(
+            "/api/admin/revoke-verification",
+            &serde_json::json!({ "verification_token": token, "sessions": true }),
+        );
+        let (status, _, body) = send(&state, with_bearer(req, ADMIN_TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "verification_token_revoked": true,
+                "credentials_revoked": 1,
+                "sessions_revoked": 1,
+            })
+        );
+        assert!(!state.verification_tokens.contains_key(&token));
+        assert!(!state.temporary_credentials.contains_key(&derived));
+        assert!(state.session_issuer.validate(&derived_session).is_none());
+        // credentials and sessions from another verification are untouched
+        assert!(state.temporary_credentials.contains_key(&other));
+        assert!(state.session_issuer.validate(&other_session).is_some());
+    }
+
+    #[tokio::test]
+    async fn a_preferences_batch_reports_each_line() {
+        let state = testing::state_with(testing::admin_config());
+        let body = concat!(
+            "{\"session_or_user\":\"alice\",\"preferences\":{\"theme\":\"dark\"}}\n",
+            "{\"session_or_user\":\"bob\",\"preferences\":{}}\n",
+            "\n",
+            "not json\n",
+        );
+        let req = Request::post("/api/admin/preferences/validate")
+            .header(header::CONTENT_TYPE, "application/x-ndjson")
+            .body(Body::from(body))
+            .unwrap();
+        let (status, _, body) = send(&state, with_bearer(req, ADMIN_TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["valid"], 1);
+        assert_eq!(body["failed"], 2);
+        assert_eq!(body["results"][1]["error"], "preferences_empty");
+        assert_eq!(body["results"][2]["line"], 4);
+        assert_eq!(body["results"][2]["error"], "invalid_record");
+    }
+
+    #[tokio::test]
+    async fn a_line_over_the_limit_is_skipped_not_buffered() {
+        let state = testing::state_with(testing::admin_config());
+        let long = format!(
+            "{{\"session_or_user\":\"a\",\"preferences\":{{\"k\":\"{}\"}}}}\n",
+            "x".repeat(MAX_BATCH_LINE_BYTES)
+        );
+        let body = format!("{long}{{\"session_or_user\":\"b\",\"preferences\":{{\"k\":1}}}}");
+        let req = Request::post("/api/admin/preferences/validate")
+            .body(Body::from(body))
+            .unwrap();
+        let (status, _, body) = send(&state, with_bearer(req, ADMIN_TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["results"][0]["error"], "line_too_long");
+        assert_eq!(body["results"][1]["ok"], true);
+    }
+}
@@ -0,0 +1,187 @@
+// Discovery and health: capabilities, version, readiness, response signing keys.
+
+use crate::{
+    config::{SESSION_TTL, TEMP_CREDENTIAL_TTL, VERIFICATION_TTL},
+    dto::{CapabilitiesResponse, CapabilityTtls, JwksResponse, ReadyResponse, VersionResponse},
+    error::{draining, json_error, json_ok},
+    session::SessionClaims,
+    state::{AppState, is_draining, random_token},
+};
+use axum::{extract::State, http::StatusCode, response::Response};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use ed25519_dalek::{Signer, Verifier};
+use std::{sync::atomic::Ordering, time::Duration};
+
+// (route group, endpoint) as mounted by `api_routes`; "" is always on
+const ENDPOINTS: &[(&str, &str)] = &[
+    ("verify", "POST /api/step1/request-code"),
+    ("verify", "POST /api/step1/verify"),
+    ("verify", "GET /api/step1/pow"),
+    ("issue", "POST /api/step2/issue-credentials"),
+    ("issue", "POST /api/step2/rotate-credential"),
+    ("issue", "POST /api/step2/delegate"),
+    ("issue", "GET /api/step2/credential/{id}/public-key"),
+    ("issue", "GET /api/step2/credentials"),
+    ("anonymous", "POST /api/step2/issue-anonymous"),
+    ("enter", "POST /api/step3/enter"),
+    ("enter", "POST /api/step3/enter-multipart"),
+    ("token", "POST /api/session/token"),
+    ("preferences", "POST /api/user/preferences"),
+    ("", "GET /api/capabilities"),
+    ("", "GET /api/version"),
+    ("", "GET /api/ready"),
+    ("", "GET /api/jwks"),
+    ("admin", "POST /api/admin/preferences/validate"),
+    ("admin", "GET /api/admin/stats"),
+    ("admin", "GET /api/admin/capacity"),
+    ("admin", "POST /api/admin/cleanup"),
+    ("admin", "POST /api/admin/revoke-verification"),
+    ("admin", "POST /api/admin/revoke-credential"),
+    ("admin", "POST /api/admin/state/export"),
+    ("admin", "POST /api/admin/state/import"),
+    ("admin", "POST /api/verify-signature"),
+];
+
+pub(super) async fn capabilities(State(state): State<AppState>) -> Response {
+    json_ok(
+        StatusCode::OK,
+        CapabilitiesResponse {
+            version: env!("CARGO_PKG_VERSION"),
+            auth_modes: vec![state.auth.mode()],
+            signature_algorithms: &["ed25519", "ed25519ph"],
+            server_minted_keys: true,
+            path_prefix: state.config.path_prefix.clone(),
+            audience: state.config.audience.clone(),
+            response_signing: state.config.sign_responses,
+            session_receipts: state.config.session_receipts,
+            endpoints: ENDPOINTS
+                .iter()
+                .filter(|(group, _)| group.is_empty() || state.config.route_enabled(group))
+                .map(|(_, endpoint)| *endpoint)
+                .collect(),
+            ttls: CapabilityTtls {
+                verification_seconds: VERIFICATION_TTL.as_secs(),
+                temporary_credential_seconds: TEMP_CREDENTIAL_TTL.as_secs(),
+                session_seconds: SESSION_TTL.as_secs(),
+            },
+        },
+    )
+}
+
+pub(super) async fn version(State(state): State<AppState>) -> Response {
+    json_ok(
+        StatusCode::OK,
+        VersionResponse {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("BUILD_GIT_HASH"),
+            build_timestamp: env!("BUILD_TIMESTAMP"),
+            rustc_version: env!("BUILD_RUSTC_VERSION"),
+            path_prefix: state.config.path_prefix.clone(),
+        },
+    )
+}
+
+// Keys clients verify X-Body-Signature and session receipts with; empty when both are off.
+pub(super) async fn jwks(State(state): State<AppState>) -> Response {
+    json_ok(
+        StatusCode::OK,
+        JwksResponse {
+            keys: state.response_signer.iter().map(|s| s.jwk()).collect(),
+        },
+    )
+}
+
+// Load balancer readiness: 200 once the startup self-check passed, 503 while draining.
+pub(super) async fn ready(State(state): State<AppState>) -> Response {
+    if is_draining(&state) {
+        return draining();
+    }
+    if !state.ready.load(Ordering::Relaxed) {
+        return json_error(StatusCode::SERVICE_UNAVAILABLE, "not_ready");
+    }
+    json_ok(StatusCode::OK, ReadyResponse { ready: true })
+}
+
+// Runs once before binding. Exercises the same entropy, Ed25519 and session token code
+// the handlers use, so a broken RNG or crypto build stops startup instead of failing
+// every request later.
+pub(crate) fn self_check(state: &AppState) -> Result<(), String> {
+    let key = state
+        .rng
+        .signing_key()
+        .map_err(|_| "entropy source unavailable")?;
+    let message = b"staged-access self-check";
+    let signature = key.sign(message);
+    key.verifying_key()
+        .verify(message, &signature)
+        .map_err(|e| format!("Ed25519 signature did not verify: {e}"))?;
+    if key.verifying_key().verify(b"tampered", &signature).is_ok() {
+        return Err("Ed25519 accepted a signature over another message".into());
+    }
+
+    let token = random_token(&state.rng, 32).map_err(|_| "entropy source unavailable")?;
+    match URL_SAFE_NO_PAD.decode(&token) {
+        Ok(bytes) if bytes.len() == 32 => {}
+        _ => return Err("random token did not decode to 32 bytes".into()),
+    }
+
+    // a real id is 32 base64url characters, so this can't match one
+    let claims = SessionClaims {
+        public_key: key.verifying_key(),
+        credential_id: "self-check".into(),
+        csrf_token: None,
+        scopes: Vec::new(),
+    };
+    let (session_token, _) = state
+        .session_issuer
+        .issue(&claims, Duration::from_secs(1))
+        .map_err(|_| "entropy source unavailable")?;
+    let validated = state.session_issuer.validate(&session_token);
+    state
+        .session_issuer
+        .revoke_credential(&claims.credential_id);
+    match validated {
+        Some(c) if c.credential_id == claims.credential_id => Ok(()),
+        _ => Err("a freshly issued session token did not validate".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::self_check;
+    use crate::testing::{self, send};
+    use axum::{body::Body, extract::Request, http::StatusCode};
+    use std::sync::atomic::Ordering;
+
+    fn ready() -> Request {
+        Request::get("/api/ready").body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn the_self_check_passes_and_leaves_no_session_behind() {
+        let state = testing::state();
+        assert_eq!(self_check(&state), Ok(()));
+        assert!(state.sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn readiness_waits_for_the_self_check() {
+        let state = testing::state();
+        let (status, _, body) = send(&state, ready()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["error"], "not_ready");
+
+        // as main does once the check passed
+        self_check(&state).unwrap();
+        state.ready.store(true, Ordering::Relaxed);
+        let (status, _, body) = send(&state, ready()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["ready"], true);
+
+        state.draining.store(true, Ordering::SeqCst);
+        assert_eq!(
+            send(&state, ready()).await.0,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
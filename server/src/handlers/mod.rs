@@ -0,0 +1,216 @@
+// Route handlers, grouped by step, and the router that mounts them.
+
+mod admin;
+mod meta;
+mod step1;
+mod step2;
+mod step3;
+mod user;
+
+#[cfg(feature = "otel")]
+use crate::telemetry;
+use crate::{
+    bodysig,
+    config::{
+        CORS_ORIGINS_ENV, CSRF_HEADER, Config, MAX_PREHASHED_PAYLOAD_BYTES,
+        MULTIPART_OVERHEAD_BYTES, PREFERENCES_SIGNATURE_HEADER, REQUEST_ID_HEADER,
+        SESSION_COOKIE_ENV,
+    },
+    faults, ratelimit,
+    request_log::{
+        assign_request_id, limit_concurrency, localize_errors, log_peer, pad_latency,
+        reject_dangerous_headers, security_headers,
+    },
+    state::AppState,
+    ui,
+};
+use admin::{
+    admin_capacity, admin_cleanup, admin_stats, export_state, import_state, revoke_credential,
+    revoke_verification, validate_preferences_batch, verify_external_signature,
+};
+use axum::{
+    Router,
+    extract::DefaultBodyLimit,
+    http::{HeaderName, Method, header},
+    middleware,
+    routing::{get, post},
+};
+pub(crate) use meta::self_check;
+use meta::{capabilities, jwks, ready, version};
+use std::convert::Infallible;
+use step1::{pow_challenge, request_code, verify_user};
+use step2::{
+    credential_public_key, delegate_credential, issue_anonymous_credentials,
+    issue_temporary_credentials, list_credentials, rotate_credential,
+};
+use step3::{enter_session_multipart, enter_session_with_credential};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use user::{issue_scoped_token, submit_user_preferences};
+
+// Only the route groups in POC_ENABLED_ROUTES are mounted; capabilities and version always are.
+fn api_routes(config: &Config) -> Router<AppState> {
+    let pad = middleware::from_fn_with_state(config.auth_min_latency, pad_latency);
+    let mut app = Router::new()
+        .route("/api/capabilities", get(capabilities))
+        .route("/api/version", get(version))
+        .route("/api/ready", get(ready))
+        .route("/api/jwks", get(jwks));
+    if config.route_enabled("verify") {
+        app = app
+            .route("/api/step1/request-code", post(request_code))
+            .route("/api/step1/verify", post(verify_user).layer(pad.clone()));
+    }
+    // anonymous issuance needs a challenge too, even with the verify group off
+    if config.route_enabled("verify") || config.route_enabled("anonymous") {
+        app = app.route("/api/step1/pow", get(pow_challenge));
+    }
+    if config.route_enabled("issue") {
+        app = app
+            .route(
+                "/api/step2/issue-credentials",
+                post(issue_temporary_credentials).layer(pad.clone()),
+            )
+            .route("/api/step2/rotate-credential", post(rotate_credential))
+            .route("/api/step2/delegate", post(delegate_credential))
+            .route(
+                "/api/step2/credential/:id/public-key",
+                get(credential_public_key),
+            )
+            .route("/api/step2/credentials", get(list_credentials));
+    }
+    if config.route_enabled("anonymous") {
+        app = app.route(
+            "/api/step2/issue-anonymous",
+            post(issue_anonymous_credentials),
+        );
+    }
+    if config.route_enabled("enter") {
+        app = app
+            .route(
+                "/api/step3/enter",
+                post(enter_session_with_credential).layer(pad.clone()),
+            )
+            .route(
+                "/api/step3/enter-multipart",
+                // two stacked layers: the error type in between has to be named
+                post(enter_session_multipart)
+                    .layer::<_, Infallible>(pad)
+                    .layer(DefaultBodyLimit::max(
+                        MAX_PREHASHED_PAYLOAD_BYTES + MULTIPART_OVERHEAD_BYTES,
+                    )),
+            );
+    }
+    if config.route_enabled("token") {
+        app = app.route("/api/session/token", post(issue_scoped_token));
+    }
+    if config.route_enabled("preferences") {
+        app = app.route("/api/user/preferences", post(submit_user_preferences));
+    }
+    if config.route_enabled("admin") {
+        app = app
+            .route(
+                "/api/admin/preferences/validate",
+                post(validate_preferences_batch),
+            )
+            .route("/api/admin/stats", get(admin_stats))
+            .route("/api/admin/capacity", get(admin_capacity))
+            .route("/api/admin/cleanup", post(admin_cleanup))
+            .route("/api/admin/revoke-verification", post(revoke_verification))
+            .route("/api/admin/revoke-credential", post(revoke_credential))
+            .route("/api/admin/state/export", post(export_state))
+            .route("/api/admin/state/import", post(import_state))
+            .route("/api/verify-signature", post(verify_external_signature));
+    }
+    if config.serve_ui {
+        app = app
+            .route("/", get(ui::index))
+            .route("/ui/app.js", get(ui::script))
+            .route("/ui/style.css", get(ui::style));
+    }
+    app
+}
+
+// The routes with every middleware layer, ready to serve.
+pub(crate) fn build_app(state: &AppState) -> Router {
+    // Credentialed CORS can't use wildcards, so cookie clients need explicit origins and headers.
+    let cors = if state.config.cors_origins.is_empty() {
+        if state.config.session_cookie {
+            println!(
+                "{SESSION_COOKIE_ENV} is on without {CORS_ORIGINS_ENV}: cookies work same-origin only"
+            );
+        }
+        CorsLayer::new()
+            .allow_origin(Any)
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers(Any)
+            .expose_headers([
+                HeaderName::from_static(REQUEST_ID_HEADER),
+                HeaderName::from_static(bodysig::BODY_SIGNATURE_HEADER),
+                HeaderName::from_static(bodysig::BODY_SIGNATURE_KID_HEADER),
+            ])
+    } else {
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(state.config.cors_origins.clone()))
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers([
+                header::CONTENT_TYPE,
+                header::AUTHORIZATION,
+                header::ACCEPT_LANGUAGE,
+                HeaderName::from_static(PREFERENCES_SIGNATURE_HEADER),
+                HeaderName::from_static(CSRF_HEADER),
+                HeaderName::from_static(REQUEST_ID_HEADER),
+            ])
+            .expose_headers([
+                HeaderName::from_static(REQUEST_ID_HEADER),
+                HeaderName::from_static(bodysig::BODY_SIGNATURE_HEADER),
+                HeaderName::from_static(bodysig::BODY_SIGNATURE_KID_HEADER),
+            ])
+            .allow_credentials(true)
+    };
+
+    let mut routes = api_routes(&state.config);
+    if let Some(faults) = faults::Faults::from_env() {
+        println!("WARNING: failure injection is on ({})", faults.describe());
+        routes = routes.layer(middleware::from_fn_with_state(faults, faults::inject));
+    }
+    // wraps fault injection: a throttled request is refused before any injected delay
+    if let Some(limiter) = state.rate_limiter.clone() {
+        routes = routes.layer(middleware::from_fn_with_state(limiter, ratelimit::limit));
+    }
+    let app = match state.config.path_prefix.as_str() {
+        "" => routes,
+        prefix => Router::new().nest(prefix, routes),
+    };
+    let app = app
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            reject_dangerous_headers,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            limit_concurrency,
+        ))
+        .layer(middleware::from_fn(localize_errors));
+    // outside localize_errors, so the signature covers the localized text
+    let app = match state.response_signer.clone() {
+        Some(signer) if state.config.sign_responses => {
+            app.layer(middleware::from_fn_with_state(signer, bodysig::sign))
+        }
+        _ => app,
+    };
+    let app = app
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            security_headers,
+        ))
+        .layer(middleware::from_fn(log_peer))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            assign_request_id,
+        ))
+        .layer(cors)
+        .with_state(state.clone());
+    #[cfg(feature = "otel")]
+    let app = app.layer(middleware::from_fn(telemetry::trace_requests));
+    app
+}
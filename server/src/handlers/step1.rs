@@ -0,0 +1,318 @@
+// Step 1: verify the user.
+
+use crate::{
+    auth::PendingCodeRecord,
+    config::{PENDING_CODE_TTL, POW_CHALLENGE_TTL, VERIFICATION_TTL},
+    dto::{
+        CodeSentResponse, PowChallengeResponse, RequestCodeRequest, VerifyUseRequest,
+        VerifyUserResponse,
+    },
+    error::{ApiError, ApiJson, auth_error, capacity_reached, draining, json_error, json_ok},
+    events::Event,
+    state::{AppState, is_draining, random_code, random_token},
+    store::{VerificationTokenRecord, deadline, expired, has_capacity},
+};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+use std::time::Instant;
+
+// one_time_code mode only. A new request replaces any pending code for the user.
+pub(super) async fn request_code(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<RequestCodeRequest>,
+) -> Response {
+    if is_draining(&state) {
+        return draining();
+    }
+    let username = req.username.trim().to_string();
+    if username.is_empty() {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "username_required");
+    }
+
+    if !state.auth.sends_codes() {
+        return json_error(StatusCode::BAD_REQUEST, "one_time_code_disabled");
+    }
+
+    // shares the verification token cap, like PoW challenges; replacing a code needs no room
+    if !state.pending_codes.contains_key(&username)
+        && !has_capacity(
+            &state,
+            &state.pending_codes,
+            state.config.max_verification_tokens,
+        )
+    {
+        return capacity_reached();
+    }
+
+    let code = match random_code(&state.rng) {
+        Ok(c) => c,
+        Err(e) => return e.into_response(),
+    };
+    state.pending_codes.insert(
+        username.clone(),
+        PendingCodeRecord {
+            code: code.clone(),
+            attempts: 0,
+            expires_at: deadline::<PendingCodeRecord>(),
+        },
+    );
+    state.notifier.send(&username, &code).await;
+
+    json_ok(
+        StatusCode::ACCEPTED,
+        CodeSentResponse {
+            code_sent: true,
+            expires_in_seconds: PENDING_CODE_TTL.as_secs(),
+        },
+    )
+}
+
+pub(super) async fn verify_user(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<VerifyUseRequest>,
+) -> Response {
+    if is_draining(&state) {
+        return draining();
+    }
+    let username = req.username.trim().to_string();
+    if username.is_empty() {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "username_required");
+    }
+
+    // before the auth backend, so a bot pays for every code guess
+    if let Some(difficulty) = state.config.pow_difficulty
+        && let Err(e) = check_pow(
+            &state,
+            difficulty,
+            req.pow_challenge.as_deref(),
+            req.pow_nonce.as_deref(),
+        )
+    {
+        return e.into_response();
+    }
+
+    let grant = match state.auth.verify(&username, &req.code).await {
+        Ok(grant) => grant,
+        Err(e) => return auth_error(&e),
+    };
+
+    if !has_capacity(
+        &state,
+        &state.verification_tokens,
+        state.config.max_verification_tokens,
+    ) {
+        return capacity_reached();
+    }
+
+    let token = match random_token(&state.rng, 32) {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+
+    state.verification_tokens.insert(
+        token.clone(),
+        VerificationTokenRecord {
+            expires_at: deadline::<VerificationTokenRecord>(),
+            username: username.clone(),
+            scopes: grant.scopes,
+            flow_started: Instant::now(),
+        },
+    );
+    state.events.publish(Event::UserVerified { username });
+
+    json_ok(
+        StatusCode::OK,
+        VerifyUserResponse {
+            verification_token: token,
+            expires_in_seconds: VERIFICATION_TTL.as_secs(),
+        },
+    )
+}
+
+// A fresh challenge for POC_POW. The client searches for a nonce such that
+// SHA-256(challenge ":" nonce) starts with `difficulty` zero bits.
+pub(super) async fn pow_challenge(State(state): State<AppState>) -> Response {
+    let Some(difficulty) = state.config.pow_difficulty else {
+        return json_error(StatusCode::BAD_REQUEST, "pow_disabled");
+    };
+    if is_draining(&state) {
+        return draining();
+    }
+    if !has_capacity(
+        &state,
+        &state.pow_challenges,
+        state.config.max_verification_tokens,
+    ) {
+        return capacity_reached();
+    }
+
+    let challenge = match random_token(&state.rng, 16) {
+        Ok(c) => c,
+        Err(e) => return e.into_response(),
+    };
+    state
+        .pow_challenges
+        .insert(challenge.clone(), Instant::now() + POW_CHALLENGE_TTL);
+
+    json_ok(
+        StatusCode::OK,
+        PowChallengeResponse {
+            challenge,
+            difficulty,
+            expires_in_seconds: POW_CHALLENGE_TTL.as_secs(),
+        },
+    )
+}
+
+// The challenge is consumed before the hash is checked, so each one buys a single attempt.
+pub(super) fn check_pow(
+    state: &AppState,
+    difficulty: u32,
+    challenge: Option<&str>,
+    nonce: Option<&str>,
+) -> Result<(), ApiError> {
+    let (Some(challenge), Some(nonce)) = (challenge, nonce) else {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "pow_required",
+        ));
+    };
+    let live = state
+        .pow_challenges
+        .remove(challenge)
+        .is_some_and(|(_, expires_at)| !expired(expires_at));
+    let digest = Sha256::new()
+        .chain_update(challenge.as_bytes())
+        .chain_update(b":")
+        .chain_update(nonce.as_bytes())
+        .finalize();
+    if !live || leading_zero_bits(&digest) < difficulty {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "pow_invalid",
+        ));
+    }
+    Ok(())
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for b in bytes {
+        bits += b.leading_zeros();
+        if *b != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        auth::{MAX_CODE_ATTEMPTS, OneTimeCodeBackend},
+        config::Config,
+        state::AppState,
+        testing::{self, post_json, send},
+    };
+    use axum::http::StatusCode;
+    use serde_json::{Value, json};
+    use std::sync::Arc;
+
+    fn one_time_code_state(config: Config) -> AppState {
+        let mut state = testing::state_with(config);
+        state.auth = Arc::new(OneTimeCodeBackend::new(state.pending_codes.clone()));
+        state
+    }
+
+    async fn request_code(state: &AppState, username: &str) -> StatusCode {
+        let req = post_json("/api/step1/request-code", &json!({ "username": username }));
+        send(state, req).await.0
+    }
+
+    async fn verify(state: &AppState, username: &str, code: &str) -> (StatusCode, Value) {
+        let req = post_json(
+            "/api/step1/verify",
+            &json!({ "username": username, "code": code }),
+        );
+        let (status, _, body) = send(state, req).await;
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn a_requested_code_verifies_once() {
+        let state = one_time_code_state(testing::config());
+        assert_eq!(request_code(&state, "alice").await, StatusCode::ACCEPTED);
+        let code = state.pending_codes.get("alice").unwrap().code.clone();
+
+        let (status, body) = verify(&state, "alice", &code).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["verification_token"].is_string());
+        assert!(!state.pending_codes.contains_key("alice"));
+
+        let (status, _) = verify(&state, "alice", &code).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn a_wrong_code_counts_down_the_attempts() {
+        let state = one_time_code_state(testing::config());
+        assert_eq!(request_code(&state, "alice").await, StatusCode::ACCEPTED);
+        let code = state.pending_codes.get("alice").unwrap().code.clone();
+        let wrong = if code == "000000" { "000001" } else { "000000" };
+
+        let (status, body) = verify(&state, "alice", wrong).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["attempts_remaining"], 4);
+        // the right code still works after a miss
+        assert_eq!(verify(&state, "alice", &code).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn the_countdown_ends_in_lockout_like_an_unknown_user() {
+        let state = one_time_code_state(testing::config());
+        assert_eq!(request_code(&state, "alice").await, StatusCode::ACCEPTED);
+        let code = state.pending_codes.get("alice").unwrap().code.clone();
+        let wrong = if code == "000000" { "000001" } else { "000000" };
+
+        for left in (0..MAX_CODE_ATTEMPTS).rev() {
+            let (status, body) = verify(&state, "alice", wrong).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(body["attempts_remaining"], left);
+        }
+        // locked out: the code is burned and the reply matches a never-seen username
+        let (status, locked) = verify(&state, "alice", &code).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (_, unknown) = verify(&state, "mallory", wrong).await;
+        assert_eq!(locked["attempts_remaining"], 0);
+        assert_eq!(locked["error"], unknown["error"]);
+        assert_eq!(locked["attempts_remaining"], unknown["attempts_remaining"]);
+    }
+
+    #[tokio::test]
+    async fn pending_codes_are_capped() {
+        let mut config = testing::config();
+        config.max_verification_tokens = 2;
+        let state = one_time_code_state(config);
+        assert_eq!(request_code(&state, "alice").await, StatusCode::ACCEPTED);
+        assert_eq!(request_code(&state, "bob").await, StatusCode::ACCEPTED);
+        assert_eq!(
+            request_code(&state, "carol").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        // a user with a pending code may still replace it
+        assert_eq!(request_code(&state, "alice").await, StatusCode::ACCEPTED);
+    }
+
+    #[tokio::test]
+    async fn the_static_code_mode_sends_no_codes() {
+        let state = testing::state();
+        let req = post_json("/api/step1/request-code", &json!({ "username": "alice" }));
+        let (status, _, body) = send(&state, req).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "one_time_code_disabled");
+    }
+}
@@ -0,0 +1,932 @@
+// Step 3: enter a session with a signature from the temporary credential.
+
+use crate::{
+    auth::{
+        SignatureAlg, SignedData, credential_locked, csrf_cookie, decode_signature,
+        decoy_verification, session_cookie, verify_credential_signature,
+    },
+    config::{
+        MAX_MESSAGE_BYTES, MAX_MULTIPART_PAYLOAD_BYTES, MAX_PREHASHED_PAYLOAD_BYTES, SESSION_TTL,
+    },
+    dto::{EnterSessionRequest, EnterSessionResponse},
+    error::{ApiError, ApiJson, capacity_reached, draining, json_error, json_error_field, json_ok},
+    events::Event,
+    payload,
+    session::{SESSION_SCOPES, SessionClaims, unix_now},
+    state::{AppState, is_draining, random_token},
+    store::{
+        TemporaryCredentialRecord, VerifiedCache, flow_expired, get_if_live, has_capacity,
+        remaining_secs,
+    },
+};
+use axum::{
+    extract::{
+        Multipart, State,
+        multipart::{MultipartError, MultipartRejection},
+    },
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha512};
+use std::time::Instant;
+
+pub(super) async fn enter_session_with_credential(
+    State(state): State<AppState>,
+    ApiJson(req): ApiJson<EnterSessionRequest>,
+) -> Response {
+    let credential_id = req.credential_id.trim();
+    if credential_id.is_empty() {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "credential_id_required");
+    }
+    if let Some(jws) = &req.jws {
+        return enter_with_jws(&state, credential_id, jws, &req).await;
+    }
+    if req.message.is_empty() {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "message_required");
+    }
+    if req.signature.is_empty() {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "signature_required");
+    }
+    // before parsing; the signature length is checked by decode_signature
+    if req.message.len() > MAX_MESSAGE_BYTES {
+        return json_error(StatusCode::PAYLOAD_TOO_LARGE, "message_too_large");
+    }
+
+    let alg = match SignatureAlg::parse(req.alg.as_deref()) {
+        Ok(alg) => alg,
+        Err(e) => return e.into_response(),
+    };
+
+    let audience = state.config.audience.as_deref();
+    let message = match payload::message_bytes(
+        &req.message,
+        req.message_encoding.as_deref(),
+        state.config.strict_messages,
+        "enter",
+        credential_id,
+        audience,
+    ) {
+        Ok(m) => m,
+        Err((status, code)) => return json_error(status, code),
+    };
+    let data = SignedData::new(alg, &state.config.signing_context, &message);
+
+    enter_with_signature(&state, credential_id, data, &req.signature).await
+}
+
+// The JWS request shape. The signing input is verified as JOSE defines it, so
+// POC_SIGNING_CONTEXT does not apply here.
+async fn enter_with_jws(
+    state: &AppState,
+    credential_id: &str,
+    jws: &str,
+    req: &EnterSessionRequest,
+) -> Response {
+    if !req.signature.is_empty() {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "jws_with_signature");
+    }
+    // the JWS header names the algorithm, and its payload is always base64url
+    if req.alg.is_some() {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "alg_unsupported");
+    }
+    if req.message_encoding.is_some() {
+        return json_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "message_encoding_unsupported",
+        );
+    }
+    // base64url grows the payload by a third, plus the header and signature
+    if jws.len() > 2 * MAX_MESSAGE_BYTES || req.message.len() > MAX_MESSAGE_BYTES {
+        return json_error(StatusCode::PAYLOAD_TOO_LARGE, "message_too_large");
+    }
+    let detached = (!req.message.is_empty()).then_some(req.message.as_str());
+    let audience = state.config.audience.as_deref();
+    let (signing_input, signature) = match payload::jws_signing_input(
+        jws,
+        detached,
+        state.config.strict_messages,
+        credential_id,
+        audience,
+    ) {
+        Ok(parts) => parts,
+        Err((status, code)) => return json_error(status, code),
+    };
+
+    enter_with_signature(
+        state,
+        credential_id,
+        SignedData::Pure(signing_input),
+        &signature,
+    )
+    .await
+}
+
+// Same as step 3, but the signed message is an uploaded file. Parts: `credential_id`,
+// `signature`, optional `alg` (text) and `payload` (file, read chunk by chunk up to
+// MAX_MULTIPART_PAYLOAD_BYTES). With alg=ed25519ph the file is hashed as it arrives and
+// never held in memory; `alg` must then come before `payload`.
+// A file has no place for `aud`, so with POC_AUDIENCE set this path is refused outright,
+// like free text and base64url messages.
+pub(super) async fn enter_session_multipart(
+    State(state): State<AppState>,
+    form: Result<Multipart, MultipartRejection>,
+) -> Response {
+    if state.config.audience.is_some() {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "audience_mismatch");
+    }
+    // not multipart/form-data, or no boundary
+    let Ok(mut form) = form else {
+        return json_error(StatusCode::BAD_REQUEST, "multipart_invalid");
+    };
+    let mut credential_id = String::new();
+    let mut signature = String::new();
+    let mut alg: Option<SignatureAlg> = None;
+    let mut data: Option<SignedData> = None;
+
+    loop {
+        let mut field = match form.next_field().await {
+            Ok(Some(f)) => f,
+            Ok(None) => break,
+            Err(e) => return multipart_error(e),
+        };
+        let name = field.name().unwrap_or_default().to_string();
+
+        match name.as_str() {
+            "credential_id" | "signature" => {
+                let text = match field.text().await {
+                    Ok(t) => t,
+                    Err(e) => return multipart_error(e),
+                };
+                if name == "credential_id" {
+                    credential_id = text;
+                } else {
+                    signature = text;
+                }
+            }
+            "alg" => {
+                // the payload was already read for the previous choice
+                if data.is_some() {
+                    return json_error_field(StatusCode::BAD_REQUEST, "multipart_invalid", "alg");
+                }
+                let text = match field.text().await {
+                    Ok(t) => t,
+                    Err(e) => return multipart_error(e),
+                };
+                alg = match SignatureAlg::parse(Some(&text)) {
+                    Ok(alg) => Some(alg),
+                    Err(e) => return e.into_response(),
+                };
+            }
+            "payload" => {
+                let alg = *alg.get_or_insert(SignatureAlg::Ed25519);
+                let limit = match alg {
+                    SignatureAlg::Ed25519 => MAX_MULTIPART_PAYLOAD_BYTES,
+                    SignatureAlg::Ed25519ph => MAX_PREHASHED_PAYLOAD_BYTES,
+                };
+                let mut buf = Vec::new();
+                let mut digest = Sha512::new();
+                let mut len = 0;
+                loop {
+                    match field.chunk().await {
+                        Ok(Some(chunk)) => {
+                            len += chunk.len();
+                            if len > limit {
+                                return json_error(
+                                    StatusCode::PAYLOAD_TOO_LARGE,
+                                    "payload_too_large",
+                                );
+                            }
+                            match alg {
+                                SignatureAlg::Ed25519 => buf.extend_from_slice(&chunk),
+                                SignatureAlg::Ed25519ph => digest.update(&chunk),
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(e) => return multipart_error(e),
+                    }
+                }
+                data = Some(match alg {
+                    SignatureAlg::Ed25519 => {
+                        SignedData::new(alg, &state.config.signing_context, &buf)
+                    }
+                    SignatureAlg::Ed25519ph => SignedData::Prehashed {
+                        digest: Box::new(digest),
+                        context: state.config.signing_context.as_bytes().to_vec(),
+                    },
+                });
+            }
+            other => return json_error_field(StatusCode::BAD_REQUEST, "unknown_field", other),
+        }
+    }
+
+    let credential_id = credential_id.trim();
+    if credential_id.is_empty() {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "credential_id_required");
+    }
+    let Some(data) = data else {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "payload_required");
+    };
+    let signature = signature.trim();
+    if signature.is_empty() {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "signature_required");
+    }
+
+    enter_with_signature(&state, credential_id, data, signature).await
+}
+
+// The body limit is hit while reading parts, so it surfaces here rather than as a rejection.
+fn multipart_error(e: MultipartError) -> Response {
+    match e.status() {
+        StatusCode::PAYLOAD_TOO_LARGE => {
+            json_error(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large")
+        }
+        _ => json_error(StatusCode::BAD_REQUEST, "multipart_invalid"),
+    }
+}
+
+// Shared tail of the step 3 variants: lockout, signature check over `data`, session issue.
+async fn enter_with_signature(
+    state: &AppState,
+    credential_id: &str,
+    data: SignedData,
+    signature: &str,
+) -> Response {
+    if is_draining(state) {
+        return draining();
+    }
+    let cred = match authorize_entry(state, credential_id, data, signature).await {
+        Ok(cred) => cred,
+        // one answer for every refusal, so the code can't tell which ids exist either
+        Err(_) if state.config.constant_time_enter => {
+            return json_error(StatusCode::UNAUTHORIZED, "enter_refused");
+        }
+        Err(e) => return e.into_response(),
+    };
+
+    // Only a verified entry may evict: checked earlier, any junk request at the cap would
+    // log a real user out. No map guard is held here; the sweep needs write access to all.
+    if !has_capacity(state, &state.sessions, state.config.max_sessions)
+        && !state.session_issuer.evict_oldest()
+    {
+        return capacity_reached();
+    }
+
+    state.signature_attempts.remove(credential_id);
+    if let Some(mut stored) = state.temporary_credentials.get_mut(credential_id) {
+        stored.uses += 1;
+    }
+
+    let csrf_token = match state
+        .config
+        .session_cookie
+        .then(|| random_token(&state.rng, 32))
+        .transpose()
+    {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+
+    let claims = SessionClaims {
+        public_key: cred.public_key,
+        credential_id: credential_id.to_string(),
+        csrf_token: csrf_token.clone(),
+        scopes: cred
+            .scopes
+            .unwrap_or_else(|| SESSION_SCOPES.iter().map(|s| s.to_string()).collect()),
+    };
+    let (session_token, session_expires_at) = match state.session_issuer.issue(&claims, SESSION_TTL)
+    {
+        Ok(issued) => issued,
+        Err(e) => return e.into_response(),
+    };
+    state.events.publish(Event::SessionCreated {
+        credential_id: credential_id.to_string(),
+    });
+    let receipt = match &state.response_signer {
+        Some(signer) if state.config.session_receipts => {
+            Some(signer.receipt(credential_id, &session_token, unix_now()))
+        }
+        _ => None,
+    };
+
+    let cookies = match &csrf_token {
+        Some(csrf) => vec![session_cookie(&session_token), csrf_cookie(csrf)],
+        None => Vec::new(),
+    };
+
+    let mut resp = json_ok(
+        StatusCode::OK,
+        EnterSessionResponse {
+            session_token,
+            csrf_token,
+            expires_in_seconds: remaining_secs(session_expires_at),
+            // the flow deadline ends the credential's use early when it comes first
+            credential_expires_in_seconds: remaining_secs(
+                state
+                    .config
+                    .flow_deadline
+                    .map_or(cred.expires_at.instant(), |window| {
+                        cred.expires_at.instant().min(cred.flow_started + window)
+                    }),
+            ),
+            receipt,
+        },
+    );
+
+    for cookie in cookies {
+        if let Ok(v) = HeaderValue::from_str(&cookie) {
+            resp.headers_mut().append(header::SET_COOKIE, v);
+        }
+    }
+
+    resp
+}
+
+// Everything step 3 checks before a session exists. The signature is decoded first, so a
+// malformed one costs the same whether or not the id exists. With POC_CONSTANT_TIME_ENTER
+// a credential that can't be used (unknown, locked, or past the flow deadline) still pays
+// for one verification, against the decoy key.
+async fn authorize_entry(
+    state: &AppState,
+    credential_id: &str,
+    data: SignedData,
+    signature: &str,
+) -> Result<TemporaryCredentialRecord, ApiError> {
+    let signature = decode_signature(signature)?;
+
+    // copied out: no map guard may be held across the verification await
+    let cred = match get_if_live(&state.temporary_credentials, credential_id) {
+        _ if credential_locked(state, credential_id) => Err(ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "credential_locked",
+        )),
+        Some(cred) if flow_expired(state, cred.flow_started) => {
+            Err(ApiError::new(StatusCode::UNAUTHORIZED, "flow_expired"))
+        }
+        Some(cred) => Ok(cred),
+        None => Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid_or_expired_credential",
+        )),
+    };
+    let cred = match cred {
+        Ok(cred) => cred,
+        Err(e) => {
+            if state.config.constant_time_enter {
+                decoy_verification(state, data, signature).await;
+            }
+            return Err(e);
+        }
+    };
+
+    let cache_key = state
+        .verified
+        .as_ref()
+        .map(|_| VerifiedCache::key(&cred.public_key, &data, &signature));
+    let cached = match (&state.verified, &cache_key) {
+        (Some(cache), Some(key)) => cache
+            .lock()
+            .expect("verification cache poisoned")
+            .contains(key, Instant::now()),
+        _ => false,
+    };
+    if !cached {
+        verify_credential_signature(state, credential_id, cred.public_key, data, signature).await?;
+    }
+    if let (Some(cache), Some(key)) = (&state.verified, cache_key) {
+        cache
+            .lock()
+            .expect("verification cache poisoned")
+            .insert(key, Instant::now());
+    }
+
+    // only valid signatures are remembered, so junk can't flush the cache
+    let fresh = state
+        .seen_signatures
+        .lock()
+        .expect("signature cache poisoned")
+        .insert(signature.to_bytes(), Instant::now());
+    if !fresh {
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "signature_replayed",
+        ));
+    }
+    Ok(cred)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        auth::{SignatureAlg, SignedData},
+        config::{
+            MAX_MESSAGE_BYTES, MAX_MULTIPART_PAYLOAD_BYTES, MAX_PREHASHED_PAYLOAD_BYTES,
+            SIGNATURE_FAILURE_THRESHOLD, TEMP_CREDENTIAL_TTL,
+        },
+        parse::canonical_json,
+        payload,
+        session::SessionEviction,
+        state::AppState,
+        store::{Deadline, VerifiedCache},
+        testing::{self, ADMIN_TOKEN, credential, enter_payload, post_json, send, with_bearer},
+    };
+    use axum::{
+        body::Body,
+        extract::Request,
+        http::{StatusCode, header},
+    };
+    use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+    use ed25519_dalek::{Signature, Signer, SigningKey};
+    use rand::rngs::OsRng;
+    use serde_json::Value;
+    use sha2::{Digest, Sha512};
+    use std::time::{Duration, Instant};
+
+    fn signed_enter(key: &SigningKey, message: &str) -> Value {
+        let signature = key.sign(message.as_bytes());
+        serde_json::json!({ "message": message, "signature": URL_SAFE_NO_PAD.encode(signature.to_bytes()) })
+    }
+
+    fn audience_state(audience: &str) -> AppState {
+        let mut config = testing::config();
+        config.audience = Some(audience.into());
+        testing::state_with(config)
+    }
+
+    async fn enter(state: &AppState, credential_id: &str, mut body: Value) -> (StatusCode, Value) {
+        body["credential_id"] = credential_id.into();
+        let (status, _, body) = send(state, post_json("/api/step3/enter", &body)).await;
+        (status, body)
+    }
+
+    // A fresh credential for a new key, then a valid step 3 entry with it.
+    async fn enter_fresh(state: &AppState) -> (StatusCode, Value) {
+        let key = SigningKey::generate(&mut OsRng);
+        let id = credential(state, &key);
+        let message = canonical_json(&enter_payload(&id, None));
+        enter(state, &id, signed_enter(&key, &message)).await
+    }
+
+    #[tokio::test]
+    async fn constant_time_entry_refuses_every_failure_alike() {
+        let mut config = testing::config();
+        config.constant_time_enter = true;
+        config.flow_deadline = Some(Duration::from_secs(60));
+        let state = testing::state_with(config);
+        let key = SigningKey::generate(&mut OsRng);
+        let id = credential(&state, &key);
+        let stalled = credential(&state, &key);
+        state
+            .temporary_credentials
+            .get_mut(&stalled)
+            .unwrap()
+            .flow_started = Instant::now() - Duration::from_secs(61);
+        let stranger = SigningKey::generate(&mut OsRng);
+        let signed = |id: &str, key: &SigningKey| {
+            signed_enter(key, &canonical_json(&enter_payload(id, None)))
+        };
+        let mut malformed = signed(&id, &key);
+        malformed["signature"] = "AAAA".into();
+
+        let valid = signed(&id, &key);
+        assert_eq!(enter(&state, &id, valid.clone()).await.0, StatusCode::OK);
+        for (id, body) in [
+            ("unknown", signed("unknown", &key)),
+            (id.as_str(), signed(&id, &stranger)),
+            (id.as_str(), malformed),
+            (id.as_str(), valid),
+            (stalled.as_str(), signed(&stalled, &key)),
+        ] {
+            let (status, body) = enter(&state, id, body).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{id}");
+            assert_eq!(body["error"], "enter_refused", "{id}");
+        }
+
+        for _ in 0..SIGNATURE_FAILURE_THRESHOLD {
+            enter(&state, &id, signed(&id, &stranger)).await;
+        }
+        // locked: even a fresh, valid signature is refused the same way
+        let mut payload = enter_payload(&id, None);
+        payload["nonce"] = "another".into();
+        let fresh = signed_enter(&key, &canonical_json(&payload));
+        let (status, body) = enter(&state, &id, fresh.clone()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"], "enter_refused");
+        state.signature_attempts.remove(&id);
+        assert_eq!(enter(&state, &id, fresh).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn entry_reports_what_is_left_of_the_credential() {
+        let state = testing::state();
+        let (status, body) = enter_fresh(&state).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body["credential_expires_in_seconds"],
+            TEMP_CREDENTIAL_TTL.as_secs()
+        );
+
+        let key = SigningKey::generate(&mut OsRng);
+        let id = credential(&state, &key);
+        state.temporary_credentials.get_mut(&id).unwrap().expires_at =
+            Deadline::at(Instant::now() + Duration::from_secs(90));
+        let message = canonical_json(&enter_payload(&id, None));
+        let (status, body) = enter(&state, &id, signed_enter(&key, &message)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["credential_expires_in_seconds"], 90);
+        // the credential outlives the session it entered, and stays usable
+        assert!(state.temporary_credentials.contains_key(&id));
+    }
+
+    #[tokio::test]
+    async fn the_flow_deadline_bounds_entry_and_the_reported_lifetime() {
+        let mut config = testing::config();
+        config.flow_deadline = Some(Duration::from_secs(60));
+        let state = testing::state_with(config);
+        let key = SigningKey::generate(&mut OsRng);
+
+        let id = credential(&state, &key);
+        state
+            .temporary_credentials
+            .get_mut(&id)
+            .unwrap()
+            .flow_started = Instant::now() - Duration::from_secs(30);
+        let message = canonical_json(&enter_payload(&id, None));
+        let (status, body) = enter(&state, &id, signed_enter(&key, &message)).await;
+        assert_eq!(status, StatusCode::OK);
+        // the credential would live for minutes, but the flow ends in 30 s
+        assert!(body["credential_expires_in_seconds"].as_u64().unwrap() <= 30);
+
+        let stalled = credential(&state, &key);
+        state
+            .temporary_credentials
+            .get_mut(&stalled)
+            .unwrap()
+            .flow_started = Instant::now() - Duration::from_secs(61);
+        let message = canonical_json(&enter_payload(&stalled, None));
+        let (status, body) = enter(&state, &stalled, signed_enter(&key, &message)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"], "flow_expired");
+    }
+
+    // A guard held across an await would park a worker while the sweep waits for the same
+    // shard; with every worker parked that way, the timeout fires instead of the asserts.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_entries_and_sweeps_on_one_credential_finish() {
+        const ENTRIES: usize = 64;
+        let state = testing::state_with(testing::admin_config());
+        let key = SigningKey::generate(&mut OsRng);
+        let id = credential(&state, &key);
+
+        let mut tasks = Vec::new();
+        for i in 0..ENTRIES {
+            let mut payload = enter_payload(&id, None);
+            payload["nonce"] = format!("nonce-{i}").into();
+            let body = signed_enter(&key, &canonical_json(&payload));
+            let (entering, id) = (state.clone(), id.clone());
+            tasks.push(tokio::spawn(
+                async move { enter(&entering, &id, body).await.0 },
+            ));
+            let sweeping = state.clone();
+            tasks.push(tokio::spawn(async move {
+                let req = post_json("/api/admin/cleanup", &serde_json::json!({}));
+                send(&sweeping, with_bearer(req, ADMIN_TOKEN)).await.0
+            }));
+        }
+        let statuses = tokio::time::timeout(Duration::from_secs(30), async {
+            let mut statuses = Vec::new();
+            for task in tasks {
+                statuses.push(task.await.unwrap());
+            }
+            statuses
+        })
+        .await
+        .expect("handlers deadlocked on a map guard");
+
+        assert!(statuses.iter().all(|s| *s == StatusCode::OK));
+        assert_eq!(
+            state.temporary_credentials.get(&id).unwrap().uses,
+            ENTRIES as u32
+        );
+        assert_eq!(state.sessions.len(), ENTRIES);
+    }
+
+    #[tokio::test]
+    async fn a_full_session_map_evicts_the_least_recently_used_with_lru() {
+        let mut config = testing::config();
+        config.max_sessions = 2;
+        config.session_eviction = SessionEviction::Lru;
+        let state = testing::state_with(config);
+
+        let (_, first) = enter_fresh(&state).await;
+        let (_, second) = enter_fresh(&state).await;
+        let first = first["session_token"].as_str().unwrap();
+        let second = second["session_token"].as_str().unwrap();
+        // touching the first makes the second the oldest
+        assert!(state.session_issuer.validate(first).is_some());
+
+        let (status, _) = enter_fresh(&state).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(state.sessions.len(), 2);
+        assert!(state.session_issuer.validate(first).is_some());
+        assert!(state.session_issuer.validate(second).is_none());
+    }
+
+    #[tokio::test]
+    async fn a_failed_entry_at_the_cap_evicts_nothing() {
+        let mut config = testing::config();
+        config.max_sessions = 2;
+        config.session_eviction = SessionEviction::Lru;
+        let state = testing::state_with(config);
+        let (_, first) = enter_fresh(&state).await;
+        let (_, second) = enter_fresh(&state).await;
+
+        let key = SigningKey::generate(&mut OsRng);
+        let id = credential(&state, &key);
+        let stranger = SigningKey::generate(&mut OsRng);
+        let message = canonical_json(&enter_payload(&id, None));
+        let (status, _) = enter(&state, &id, signed_enter(&stranger, &message)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let message = canonical_json(&enter_payload("unknown", None));
+        let (status, _) = enter(&state, "unknown", signed_enter(&key, &message)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        for session in [&first, &second] {
+            let token = session["session_token"].as_str().unwrap();
+            assert!(state.session_issuer.validate(token).is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn a_full_session_map_refuses_new_sessions_by_default() {
+        let mut config = testing::config();
+        config.max_sessions = 1;
+        let state = testing::state_with(config);
+
+        assert_eq!(enter_fresh(&state).await.0, StatusCode::OK);
+        let (status, body) = enter_fresh(&state).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["error"], "capacity_reached");
+    }
+
+    #[tokio::test]
+    async fn oversized_fields_are_refused_before_decoding() {
+        let state = testing::state();
+        let key = SigningKey::generate(&mut OsRng);
+        let id = credential(&state, &key);
+
+        let message = "a".repeat(MAX_MESSAGE_BYTES + 1);
+        let (status, body) = enter(&state, &id, signed_enter(&key, &message)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error"], "message_too_large");
+
+        let mut body = signed_enter(&key, &canonical_json(&enter_payload(&id, None)));
+        body["signature"] = "A".repeat(1000).into();
+        let (status, body) = enter(&state, &id, body).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error"], "signature_too_large");
+        // neither counts as a failed signature
+        assert!(!state.signature_attempts.contains_key(&id));
+    }
+
+    #[tokio::test]
+    async fn the_signing_context_is_part_of_what_is_signed() {
+        let mut config = testing::config();
+        config.signing_context = "poc-test".into();
+        let state = testing::state_with(config);
+        let key = SigningKey::generate(&mut OsRng);
+        let sign = |bytes: &[u8]| URL_SAFE_NO_PAD.encode(key.sign(bytes).to_bytes());
+
+        let id = credential(&state, &key);
+        let message = canonical_json(&enter_payload(&id, None));
+        let bare = serde_json::json!({ "message": message, "signature": sign(message.as_bytes()) });
+        let (status, body) = enter(&state, &id, bare).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"], "invalid_signature");
+
+        let id = credential(&state, &key);
+        let message = canonical_json(&enter_payload(&id, None));
+        let signed = payload::with_context("poc-test", message.as_bytes());
+        let body = serde_json::json!({ "message": message, "signature": sign(&signed) });
+        assert_eq!(enter(&state, &id, body).await.0, StatusCode::OK);
+
+        // with Ed25519ph the context is RFC 8032's, not a prefix
+        let id = credential(&state, &key);
+        let message = canonical_json(&enter_payload(&id, None));
+        let signature = key
+            .sign_prehashed(
+                Sha512::new().chain_update(message.as_bytes()),
+                Some(b"poc-test"),
+            )
+            .unwrap();
+        let body = serde_json::json!({
+            "message": message,
+            "signature": URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+            "alg": "ed25519ph",
+        });
+        assert_eq!(enter(&state, &id, body).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn a_payload_for_another_audience_is_refused() {
+        let state = audience_state("server-a");
+        let key = SigningKey::generate(&mut OsRng);
+        let id = credential(&state, &key);
+
+        let message = canonical_json(&enter_payload(&id, Some("server-b")));
+        let (status, body) = enter(&state, &id, signed_enter(&key, &message)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "audience_mismatch");
+
+        let message = canonical_json(&enter_payload(&id, Some("server-a")));
+        let (status, _) = enter(&state, &id, signed_enter(&key, &message)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn without_an_audience_a_payload_naming_one_is_refused() {
+        let state = testing::state();
+        let key = SigningKey::generate(&mut OsRng);
+        let id = credential(&state, &key);
+
+        let message = canonical_json(&enter_payload(&id, Some("server-a")));
+        let (status, body) = enter(&state, &id, signed_enter(&key, &message)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "audience_mismatch");
+    }
+
+    #[tokio::test]
+    async fn strict_mode_refuses_a_free_text_jws() {
+        let mut config = testing::config();
+        config.strict_messages = true;
+        let state = testing::state_with(config);
+        let key = SigningKey::generate(&mut OsRng);
+        let id = credential(&state, &key);
+
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"EdDSA"}"#),
+            URL_SAFE_NO_PAD.encode("cafe\u{301}")
+        );
+        let signature = URL_SAFE_NO_PAD.encode(key.sign(signing_input.as_bytes()).to_bytes());
+        let body = serde_json::json!({ "jws": format!("{signing_input}.{signature}") });
+        let (status, body) = enter(&state, &id, body).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "free_text_message_refused");
+    }
+
+    fn cache_state(replay_cache: usize) -> AppState {
+        let mut config = testing::config();
+        config.verify_cache_ttl = Duration::from_secs(30);
+        config.signature_cache_size = replay_cache;
+        testing::state_with(config)
+    }
+
+    #[tokio::test]
+    async fn a_cache_hit_skips_the_signature_check() {
+        let state = cache_state(0);
+        let key = SigningKey::generate(&mut OsRng);
+        let id = credential(&state, &key);
+
+        // a retry of an accepted signature is served from the cache
+        let body = signed_enter(&key, "hello");
+        assert_eq!(enter(&state, &id, body.clone()).await.0, StatusCode::OK);
+        assert_eq!(enter(&state, &id, body).await.0, StatusCode::OK);
+
+        // proof that the hit skips Ed25519: an entry for a signature that doesn't verify
+        let bogus = Signature::from_bytes(&[7; 64]);
+        let data = SignedData::new(SignatureAlg::Ed25519, "", b"hello");
+        let entry = VerifiedCache::key(&key.verifying_key(), &data, &bogus);
+        let cache = state.verified.as_ref().unwrap();
+        cache.lock().unwrap().insert(entry, Instant::now());
+        let body = serde_json::json!({
+            "message": "hello",
+            "signature": URL_SAFE_NO_PAD.encode(bogus.to_bytes()),
+        });
+        assert_eq!(enter(&state, &id, body).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn a_rotated_key_misses_the_cache() {
+        let state = cache_state(0);
+        let old = SigningKey::generate(&mut OsRng);
+        let id = credential(&state, &old);
+        let body = signed_enter(&old, "hello");
+        assert_eq!(enter(&state, &id, body.clone()).await.0, StatusCode::OK);
+
+        let new = SigningKey::generate(&mut OsRng);
+        state.temporary_credentials.get_mut(&id).unwrap().public_key = new.verifying_key();
+        let (status, body) = enter(&state, &id, body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"], "invalid_signature");
+    }
+
+    #[tokio::test]
+    async fn with_the_replay_cache_on_a_repeat_is_refused_and_nothing_is_cached() {
+        let state = cache_state(100);
+        assert!(state.verified.is_none());
+        let key = SigningKey::generate(&mut OsRng);
+        let id = credential(&state, &key);
+
+        let body = signed_enter(&key, "hello");
+        assert_eq!(enter(&state, &id, body.clone()).await.0, StatusCode::OK);
+        let (status, body) = enter(&state, &id, body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"], "signature_replayed");
+    }
+
+    fn multipart(
+        credential_id: &str,
+        signature: &str,
+        alg: Option<&str>,
+        payload: &[u8],
+    ) -> Request {
+        const BOUNDARY: &str = "test-boundary";
+        let mut body = Vec::new();
+        let alg = alg.map(|alg| ("alg", alg));
+        for (name, value) in [("credential_id", credential_id), ("signature", signature)]
+            .into_iter()
+            .chain(alg)
+        {
+            body.extend_from_slice(
+                format!(
+                    "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+                )
+                .as_bytes(),
+            );
+        }
+        body.extend_from_slice(
+            format!(
+                "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"payload\"; filename=\"f\"\r\n\r\n"
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(payload);
+        body.extend_from_slice(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+        Request::post("/api/step3/enter-multipart")
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={BOUNDARY}"),
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn multipart_entry_is_refused_when_an_audience_is_set() {
+        let payload = b"file contents";
+        for (state, expected) in [
+            (testing::state(), StatusCode::OK),
+            (audience_state("server-a"), StatusCode::UNPROCESSABLE_ENTITY),
+        ] {
+            let key = SigningKey::generate(&mut OsRng);
+            let id = credential(&state, &key);
+            let signature = URL_SAFE_NO_PAD.encode(key.sign(payload).to_bytes());
+            let (status, _, body) = send(&state, multipart(&id, &signature, None, payload)).await;
+            assert_eq!(status, expected);
+            if expected != StatusCode::OK {
+                assert_eq!(body["error"], "audience_mismatch");
+            }
+        }
+    }
+
+    fn prehashed_signature(key: &SigningKey, payload: &[u8]) -> String {
+        let signature = key
+            .sign_prehashed(Sha512::new().chain_update(payload), None)
+            .unwrap();
+        URL_SAFE_NO_PAD.encode(signature.to_bytes())
+    }
+
+    #[tokio::test]
+    async fn a_prehashed_upload_may_exceed_the_buffered_limit() {
+        let state = testing::state();
+        let key = SigningKey::generate(&mut OsRng);
+        let payload = vec![0x5a; 4 * MAX_MULTIPART_PAYLOAD_BYTES];
+        let signature = prehashed_signature(&key, &payload);
+
+        let id = credential(&state, &key);
+        let req = multipart(&id, &signature, Some("ed25519ph"), &payload);
+        let (status, _, body) = send(&state, req).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        // the same file signed plainly has to be buffered, so it is over the limit
+        let id = credential(&state, &key);
+        let signature = URL_SAFE_NO_PAD.encode(key.sign(&payload).to_bytes());
+        let (status, _, body) = send(&state, multipart(&id, &signature, None, &payload)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error"], "payload_too_large");
+    }
+
+    #[tokio::test]
+    async fn a_prehashed_upload_over_its_own_limit_is_refused() {
+        let state = testing::state();
+        let key = SigningKey::generate(&mut OsRng);
+        let id = credential(&state, &key);
+        // refused while streaming, before the signature is looked at
+        let payload = vec![0x5a; MAX_PREHASHED_PAYLOAD_BYTES + 1];
+        let req = multipart(&id, "unchecked", Some("ed25519ph"), &payload);
+        let (status, _, body) = send(&state, req).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error"], "payload_too_large");
+    }
+}
@@ -0,0 +1,241 @@
+// Session holders: scoped tokens and preferences.
+
+use crate::{
+    auth::{authenticated_session, require_scope},
+    config::{PREFERENCES_SIGNATURE_HEADER, SCOPED_TOKEN_TTL},
+    dto::{ScopedTokenRequest, ScopedTokenResponse},
+    error::{ApiJson, capacity_reached, json_error, json_error_field, json_ok},
+    events::Event,
+    parse::{canonical_json, out_of_range_number, validate_preferences},
+    session::{PREFERENCES_WRITE, SessionClaims},
+    state::AppState,
+    store::{has_capacity, remaining_secs},
+};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use ed25519_dalek::{Signature, Verifier};
+use serde_json::Value;
+
+// Trades the caller's session for a short-lived token limited to the requested
+// scopes. Only scopes the session itself holds can be granted, so a scoped token
+// can be narrowed further but never widened.
+pub(super) async fn issue_scoped_token(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ApiJson(req): ApiJson<ScopedTokenRequest>,
+) -> Response {
+    let session = match authenticated_session(&state, &headers) {
+        Ok(session) => session,
+        Err(e) => return e.into_response(),
+    };
+
+    let mut scopes: Vec<String> = Vec::new();
+    for scope in req.scope.split_whitespace() {
+        if !session.scopes.iter().any(|s| s == scope) {
+            return json_error(StatusCode::FORBIDDEN, "scope_not_allowed");
+        }
+        if !scopes.iter().any(|s| s == scope) {
+            scopes.push(scope.to_string());
+        }
+    }
+    if scopes.is_empty() {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, "scope_required");
+    }
+
+    if !has_capacity(&state, &state.sessions, state.config.max_sessions)
+        && !state.session_issuer.evict_oldest()
+    {
+        return capacity_reached();
+    }
+
+    // bearer only: no CSRF token, so it is never accepted from a cookie
+    let claims = SessionClaims {
+        public_key: session.public_key,
+        credential_id: session.credential_id,
+        csrf_token: None,
+        scopes,
+    };
+    let (access_token, expires_at) = match state.session_issuer.issue(&claims, SCOPED_TOKEN_TTL) {
+        Ok(issued) => issued,
+        Err(e) => return e.into_response(),
+    };
+    state.events.publish(Event::ScopedTokenIssued {
+        scope: claims.scopes.join(" "),
+    });
+
+    json_ok(
+        StatusCode::OK,
+        ScopedTokenResponse {
+            access_token,
+            token_type: "Bearer",
+            scope: claims.scopes.join(" "),
+            expires_in_seconds: remaining_secs(expires_at),
+        },
+    )
+}
+
+// Every write needs a session or scoped token holding preferences:write. The signature
+// header is an extra proof on top of that, never a substitute for it.
+pub(super) async fn submit_user_preferences(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ApiJson(obj): ApiJson<Value>,
+) -> Response {
+    let session = match authenticated_session(&state, &headers) {
+        Ok(session) => session,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = require_scope(&session, PREFERENCES_WRITE) {
+        return e.into_response();
+    }
+
+    if let Err(code) = validate_preferences(&obj) {
+        return json_error(StatusCode::UNPROCESSABLE_ENTITY, code);
+    }
+    if let Some(key) = out_of_range_number(&obj, state.config.preferences_max_number) {
+        return json_error_field(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_preference_value",
+            key,
+        );
+    }
+
+    if let Some(sig) = headers.get(PREFERENCES_SIGNATURE_HEADER) {
+        let public_key = session.public_key;
+
+        let signature = sig
+            .to_str()
+            .ok()
+            .and_then(|s| URL_SAFE_NO_PAD.decode(s).ok())
+            .and_then(|b| Signature::from_slice(&b).ok());
+
+        let valid = signature.is_some_and(|signature| {
+            public_key
+                .verify(canonical_json(&obj).as_bytes(), &signature)
+                .is_ok()
+        });
+        if !valid {
+            return json_error(StatusCode::UNAUTHORIZED, "preferences_signature_invalid");
+        }
+    }
+
+    json_ok(
+        StatusCode::OK,
+        serde_json::json!({
+            "ok": true,
+            "prefernce": obj
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::{PREFERENCES_SIGNATURE_HEADER, SESSION_TTL},
+        parse::canonical_json,
+        session::{self, PREFERENCES_WRITE, SESSION_SCOPES, SessionClaims},
+        state::AppState,
+        testing::{self, post_json, send, with_bearer},
+    };
+    use axum::{
+        body::Body,
+        extract::Request,
+        http::{HeaderValue, StatusCode, header},
+    };
+    use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+    use ed25519_dalek::{Signer, SigningKey};
+    use rand::rngs::OsRng;
+    use serde_json::Value;
+
+    fn session_token(state: &AppState, key: &SigningKey, scopes: &[&str]) -> String {
+        let claims = SessionClaims {
+            public_key: key.verifying_key(),
+            credential_id: "credential".into(),
+            csrf_token: None,
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+        };
+        state.session_issuer.issue(&claims, SESSION_TTL).unwrap().0
+    }
+
+    fn preferences() -> Value {
+        serde_json::json!({ "theme": "dark" })
+    }
+
+    #[tokio::test]
+    async fn writing_preferences_needs_a_session() {
+        let state = testing::state();
+        let (status, _, body) =
+            send(&state, post_json("/api/user/preferences", &preferences())).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"], "session_required");
+    }
+
+    #[tokio::test]
+    async fn a_read_only_token_cannot_write_even_without_a_signature() {
+        let state = testing::state();
+        let key = SigningKey::generate(&mut OsRng);
+        let token = session_token(&state, &key, &[session::PREFERENCES_READ]);
+        let req = with_bearer(post_json("/api/user/preferences", &preferences()), &token);
+        let (status, _, body) = send(&state, req).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"], "insufficient_scope");
+        assert_eq!(body["scope"], PREFERENCES_WRITE);
+    }
+
+    #[tokio::test]
+    async fn oversized_or_unrepresentable_numbers_are_refused() {
+        let state = testing::state();
+        let key = SigningKey::generate(&mut OsRng);
+        let token = session_token(&state, &key, SESSION_SCOPES);
+
+        let prefs = serde_json::json!({ "theme": "dark", "volume": 9_007_199_254_740_992u64 });
+        let req = with_bearer(post_json("/api/user/preferences", &prefs), &token);
+        let (status, _, body) = send(&state, req).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "invalid_preference_value");
+        assert_eq!(body["field"], "volume");
+
+        // JSON has no NaN or Infinity; a float past f64 fails to parse at all
+        let req = Request::post("/api/user/preferences")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"volume": 1e400}"#))
+            .unwrap();
+        let (status, _, _) = send(&state, with_bearer(req, &token)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn a_write_token_writes_and_a_signature_is_checked_on_top() {
+        let state = testing::state();
+        let key = SigningKey::generate(&mut OsRng);
+        let token = session_token(&state, &key, SESSION_SCOPES);
+
+        let req = with_bearer(post_json("/api/user/preferences", &preferences()), &token);
+        let (status, _, _) = send(&state, req).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let signature = key.sign(canonical_json(&preferences()).as_bytes());
+        let mut req = with_bearer(post_json("/api/user/preferences", &preferences()), &token);
+        req.headers_mut().insert(
+            PREFERENCES_SIGNATURE_HEADER,
+            HeaderValue::from_str(&URL_SAFE_NO_PAD.encode(signature.to_bytes())).unwrap(),
+        );
+        let (status, _, _) = send(&state, req).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let other =
+            SigningKey::generate(&mut OsRng).sign(canonical_json(&preferences()).as_bytes());
+        let mut req = with_bearer(post_json("/api/user/preferences", &preferences()), &token);
+        req.headers_mut().insert(
+            PREFERENCES_SIGNATURE_HEADER,
+            HeaderValue::from_str(&URL_SAFE_NO_PAD.encode(other.to_bytes())).unwrap(),
+        );
+        let (status, _, body) = send(&state, req).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"], "preferences_signature_invalid");
+    }
+}
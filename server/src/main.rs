@@ -1,362 +1,113 @@
-use axum::{
-    Json, Router,
-    extract::State,
-    http::{Method, StatusCode},
-    response::{IntoResponse, Response},
-    routing::post,
-};
-use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
-use dashmap::DashMap;
-use ed25519_dalek::{Signature, SigningKey, Verifier, VerifyingKey};
-use rand::{RngCore, rngs::OsRng};
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::{
-    sync::Arc,
-    time::{Duration, Instant},
-};
-use tower_http::cors::{Any, CorsLayer};
+mod auth;
+mod bodysig;
+mod config;
+mod dto;
+mod error;
+mod events;
+mod faults;
+mod handlers;
+mod messages;
+mod notifier;
+mod parse;
+mod payload;
+mod profile;
+mod ratelimit;
+mod request_log;
+mod secrets;
+mod session;
+mod snapshot;
+mod state;
+mod store;
+#[cfg(feature = "otel")]
+mod telemetry;
+#[cfg(test)]
+mod testing;
+mod ui;
+
+use config::env_flag;
+use handlers::{build_app, self_check};
+use state::{AppState, build_state};
+use std::{net::SocketAddr, sync::atomic::Ordering};
+use store::cleanup_expired_state;
+use tokio_util::sync::CancellationToken;
 
-// --------------
-// POC - config
-// --------------
-
-const HARCODED_CODE: &str = "123456";
-const VERIFICATION_TTL: Duration = Duration::from_secs(300); // 5 minutes
-const TEMP_CREDENTIAL_TTL: Duration = Duration::from_secs(300);
-const SESSION_TTL: Duration = Duration::from_secs(1800); // 30 minutes
-
-// -------------
-// In-memory state
-// -------------
-
-#[derive(Clone)]
-struct AppState {
-    verification_tokens: Arc<DashMap<String, VerificationTokenRecord>>,
-    temporary_credentials: Arc<DashMap<String, TemporaryCredentialRecord>>,
-    sessions: Arc<DashMap<String, SessionRecord>>,
-}
-
-#[derive(Clone)]
-struct VerificationTokenRecord {
-    expires_at: Instant,
-}
-
-#[derive(Clone)]
-struct TemporaryCredentialRecord {
-    public_key: VerifyingKey,
-    expires_at: Instant,
-}
-
-#[derive(Clone)]
-struct SessionRecord {
-    expires_at: Instant,
-}
-
-// -------------
-// DTO
-// -------------
-
-#[derive(Deserialize)]
-struct VerifyUseRequest {
-    username: String,
-    code: String,
-}
-
-#[derive(Serialize)]
-struct VerifyUserResponse {
-    verification_token: String,
-    expires_in_seconds: u64,
-}
-
-#[derive(Deserialize)]
-struct IssueTemporaryCredentialsRequest {
-    verification_token: String,
-}
-
-#[derive(Serialize)]
-struct IssueTemporaryCredentialsResponse {
-    credential_id: String,
-    credential_private: String,
-    expires_in_seconds: u64,
-}
-
-#[derive(Deserialize)]
-struct EnterSessionRequest {
-    credential_id: String,
-    message: String,
-    signature: String,
-}
-
-#[derive(Serialize)]
-struct EnterSessionResponse {
-    session_token: String,
-    expires_in_seconds: u64,
-}
-
-#[derive(Serialize)]
-struct ErrorResponse {
-    error: String,
-}
-
-// ------------
-// Utils
-// ------------
-
-fn random_token(bytes: usize) -> String {
-    let mut buf = vec![0u8; bytes];
-    OsRng.fill_bytes(&mut buf);
-    URL_SAFE_NO_PAD.encode(buf)
-}
-
-fn deadline(ttl: Duration) -> Instant {
-    Instant::now() + ttl
-}
-
-fn expired(t: Instant) -> bool {
-    Instant::now() > t
-}
-
-fn json_error(status: StatusCode, msg: &str) -> Response {
-    (status, Json(ErrorResponse { error: msg.into() })).into_response()
-}
-
-fn json_ok<T: Serialize>(status: StatusCode, body: T) -> Response {
-    (status, Json(body)).into_response()
-}
+#[tokio::main]
+async fn main() {
+    messages::load();
+    #[cfg(feature = "otel")]
+    let tracer_provider = telemetry::init();
 
-// ------------
-// Real
-// ------------
+    let state = build_state().await;
 
-async fn verify_user(State(state): State<AppState>, Json(req): Json<VerifyUseRequest>) -> Response {
-    let username = req.username.trim().to_string();
-    if username.is_empty() {
-        return json_error(StatusCode::BAD_REQUEST, "username_required");
+    let addr = state.config.bind;
+    profile::check(&state);
+    if let Err(e) = self_check(&state) {
+        panic!("startup self-check failed: {e}");
     }
+    state.ready.store(true, Ordering::Relaxed);
+    println!("startup self-check passed");
 
-    if req.code != HARCODED_CODE {
-        return json_error(StatusCode::UNAUTHORIZED, "invalid code");
+    let shutdown = CancellationToken::new();
+    let cleanup = tokio::spawn(cleanup_expired_state(state.clone(), shutdown.clone()));
+    if env_flag(events::LOG_EVENTS_ENV) {
+        tokio::spawn(events::log_events(state.events.subscribe()));
     }
 
-    let token = random_token(32);
+    let app = build_app(&state);
 
-    state.verification_tokens.insert(
-        token.clone(),
-        VerificationTokenRecord {
-            expires_at: deadline(VERIFICATION_TTL),
-        },
+    println!(
+        "Rust Cryptograph POC running on http://{addr}{}",
+        state.config.path_prefix
     );
 
-    json_ok(
-        StatusCode::OK,
-        VerifyUserResponse {
-            verification_token: token,
-            expires_in_seconds: VERIFICATION_TTL.as_secs(),
-        },
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
     )
-}
-
-async fn issue_temporary_credentials(
-    State(state): State<AppState>,
-    Json(req): Json<IssueTemporaryCredentialsRequest>,
-) -> Response {
-    let token = req.verification_token.trim();
-    if token.is_empty() {
-        return json_error(StatusCode::BAD_REQUEST, "verification_token_required");
-    }
-
-    let rec = match state.verification_tokens.get(token) {
-        Some(v) => v,
-        None => {
-            return json_error(
-                StatusCode::UNAUTHORIZED,
-                "invalid_or_expired_verification_token",
-            );
-        }
-    };
+    .with_graceful_shutdown(drain_on_shutdown(state, shutdown))
+    .await
+    .unwrap();
 
-    if expired(rec.expires_at) {
-        state.verification_tokens.remove(token);
-        return json_error(
-            StatusCode::UNAUTHORIZED,
-            "invalid_or_expired_verification_token",
-        );
+    if let Err(e) = cleanup.await {
+        println!("cleanup task failed: {e}");
     }
 
-    // Generation Ed25519
-    let signing_key = SigningKey::generate(&mut OsRng);
-    let verifying_key = signing_key.verifying_key();
-
-    // Identificator record on server
-    let credential_id = random_token(24);
-
-    // Private key client
-    let private_seed = signing_key.to_bytes();
-    let private_b64 = URL_SAFE_NO_PAD.encode(private_seed);
-
-    state.temporary_credentials.insert(
-        credential_id.clone(),
-        TemporaryCredentialRecord {
-            public_key: verifying_key,
-            expires_at: deadline(TEMP_CREDENTIAL_TTL),
-        },
-    );
-
-    json_ok(
-        StatusCode::OK,
-        IssueTemporaryCredentialsResponse {
-            credential_id,
-            credential_private: private_b64,
-            expires_in_seconds: TEMP_CREDENTIAL_TTL.as_secs(),
-        },
-    )
+    #[cfg(feature = "otel")]
+    let _ = tracer_provider
+        .shutdown()
+        .inspect_err(|e| println!("failed to flush traces: {e}"));
 }
 
-async fn enter_session_with_credential(
-    State(state): State<AppState>,
-    Json(req): Json<EnterSessionRequest>,
-) -> Response {
-    let credential_id = req.credential_id.trim();
-    if credential_id.is_empty() {
-        return json_error(StatusCode::BAD_REQUEST, "credential_id_required");
-    }
-    if req.message.is_empty() {
-        return json_error(StatusCode::BAD_REQUEST, "message_required");
-    }
-    if req.signature.is_empty() {
-        return json_error(StatusCode::BAD_REQUEST, "signature_required");
-    }
-
-    let cred = match state.temporary_credentials.get(credential_id) {
-        Some(v) => v,
-        None => {
-            return json_error(StatusCode::UNAUTHORIZED, "invalid_or_expired_credential");
-        }
-    };
-
-    if expired(cred.expires_at) {
-        state.temporary_credentials.remove(credential_id);
-        return json_error(StatusCode::UNAUTHORIZED, "invalid_or_expired_credential");
-    }
-
-    let sig_bytes = match URL_SAFE_NO_PAD.decode(req.signature.as_bytes()) {
-        Ok(b) => b,
-        Err(_) => {
-            return json_error(StatusCode::BAD_REQUEST, "signature_not_base64url");
-        }
+// Resolves once the drain window after SIGINT/SIGTERM has passed; the listener then
+// stops accepting and in-flight requests finish. `shutdown` is cancelled as soon as the
+// signal arrives, which stops the cleanup task.
+async fn drain_on_shutdown(state: AppState, shutdown: CancellationToken) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
     };
-
-    let signature = match Signature::from_slice(&sig_bytes) {
-        Ok(s) => s,
-        Err(_) => {
-            return json_error(StatusCode::BAD_REQUEST, "signature_invalid_format");
-        }
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
     };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    if cred
-        .public_key
-        .verify(req.message.as_bytes(), &signature)
-        .is_err()
-    {
-        return json_error(StatusCode::UNAUTHORIZED, "invalid_signature");
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
     }
 
-    let session_token = random_token(32);
-    state.sessions.insert(
-        session_token.clone(),
-        SessionRecord {
-            expires_at: deadline(SESSION_TTL),
-        },
+    state.draining.store(true, Ordering::Relaxed);
+    shutdown.cancel();
+    println!(
+        "shutdown requested: draining for {}s",
+        state.config.drain_window.as_secs()
     );
-
-    json_ok(
-        StatusCode::OK,
-        EnterSessionResponse {
-            session_token,
-            expires_in_seconds: SESSION_TTL.as_secs(),
-        },
-    )
-}
-
-async fn submit_user_preferences(Json(obj): Json<Value>) -> Response {
-    let map = match obj.as_object() {
-        Some(m) => m,
-        None => {
-            return json_error(StatusCode::BAD_REQUEST, "preferences_must_be_object");
-        }
-    };
-
-    if map.is_empty() {
-        return json_error(StatusCode::BAD_REQUEST, "preferences_empty");
-    }
-
-    for k in map.keys() {
-        if k.trim().is_empty() {
-            return json_error(StatusCode::BAD_REQUEST, "invalid_preference_key");
-        }
-    }
-
-    json_ok(
-        StatusCode::OK,
-        serde_json::json!({
-            "ok": true,
-            "prefernce": obj
-        }),
-    )
-}
-
-// ------------
-// Clear expired state
-// ------------
-
-async fn cleanup_expired_state(state: AppState) {
-    let mut interval = tokio::time::interval(Duration::from_secs(30));
-    loop {
-        interval.tick().await;
-        let now = Instant::now();
-
-        state.verification_tokens.retain(|_, v| v.expires_at > now);
-        state
-            .temporary_credentials
-            .retain(|_, v| v.expires_at > now);
-        state.sessions.retain(|_, v| v.expires_at > now);
-    }
-}
-
-// --------------
-// Main
-// --------------
-
-#[tokio::main]
-async fn main() {
-    let state = AppState {
-        verification_tokens: Arc::new(DashMap::new()),
-        temporary_credentials: Arc::new(DashMap::new()),
-        sessions: Arc::new(DashMap::new()),
-    };
-
-    tokio::spawn(cleanup_expired_state(state.clone()));
-
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods([Method::POST])
-        .allow_headers(Any);
-
-    let app = Router::new()
-        .route("/api/step1/verify", post(verify_user))
-        .route(
-            "/api/step2/issue-credentials",
-            post(issue_temporary_credentials),
-        )
-        .route("/api/step3/enter", post(enter_session_with_credential))
-        .route("/api/user/preferences", post(submit_user_preferences))
-        .layer(cors)
-        .with_state(state);
-
-    let addr = "0.0.0.0:8080";
-    println!("Rust Cryptograph POC running on http://{addr}");
-
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    tokio::time::sleep(state.config.drain_window).await;
 }
@@ -0,0 +1,602 @@
+// Human-readable error messages per locale.
+// Only the `message` field is localized; the machine `error` code never changes.
+
+use std::{collections::HashMap, sync::OnceLock};
+
+pub const DEFAULT_LOCALE: &str = "en";
+
+type Catalog = HashMap<&'static str, HashMap<&'static str, &'static str>>;
+
+static CATALOG: OnceLock<Catalog> = OnceLock::new();
+
+tokio::task_local! {
+    // Set per request from Accept-Language by the localization middleware.
+    pub static REQUEST_LOCALE: &'static str;
+}
+
+const EN: &[(&str, &str)] = &[
+    (
+        "admin_disabled",
+        "Admin endpoints are disabled on this server.",
+    ),
+    (
+        "admin_token_invalid",
+        "The admin token is missing or invalid.",
+    ),
+    (
+        "alg_unsupported",
+        "The signature algorithm must be ed25519 or ed25519ph.",
+    ),
+    (
+        "audience_mismatch",
+        "The signed payload was made for a different server.",
+    ),
+    ("batch_too_many_lines", "The batch has too many records."),
+    ("body_read_failed", "The request body could not be read."),
+    (
+        "capacity_reached",
+        "The server is at capacity. Try again later.",
+    ),
+    (
+        "confirmation_required",
+        "Set \"confirm\": true to run this operation.",
+    ),
+    (
+        "content_length_invalid",
+        "The request has more than one Content-Length header.",
+    ),
+    ("credential_id_required", "A credential id is required."),
+    (
+        "credential_not_found",
+        "The credential is unknown or has expired.",
+    ),
+    (
+        "delegation_exceeds_parent",
+        "A delegated credential cannot have more scopes or a longer lifetime than its parent.",
+    ),
+    (
+        "delegation_invalid",
+        "The delegation needs a positive ttl_seconds and, if given, at least one scope.",
+    ),
+    (
+        "draining",
+        "The server is shutting down and not accepting new logins.",
+    ),
+    ("empty_body", "The request body is empty."),
+    (
+        "enter_refused",
+        "The credential or its signature was not accepted.",
+    ),
+    (
+        "csrf_failed",
+        "The CSRF token is missing or does not match.",
+    ),
+    (
+        "credential_locked",
+        "Too many failed signatures. Try again later.",
+    ),
+    (
+        "fault_injected",
+        "Injected failure for testing (POC_FAULT_ENABLE). Retry the request.",
+    ),
+    (
+        "field_invalid",
+        "A field in the request body has the wrong type or value.",
+    ),
+    (
+        "field_required",
+        "A required field is missing from the request body.",
+    ),
+    (
+        "flow_expired",
+        "Too much time has passed since verification. Start again from step 1.",
+    ),
+    ("format_unsupported", "The key format must be raw or pem."),
+    (
+        "free_text_message_refused",
+        "Free-text messages are not accepted; send a v1 payload or base64url bytes.",
+    ),
+    (
+        "headers_too_large",
+        "The request has too many or too large headers.",
+    ),
+    (
+        "insufficient_scope",
+        "The token does not grant the scope this endpoint needs.",
+    ),
+    ("invalid code", "The verification code is incorrect."),
+    ("invalid_json", "The request body is not valid JSON."),
+    (
+        "invalid_preference_value",
+        "A preference holds a number that is not finite or too large.",
+    ),
+    ("invalid_query", "The query string is invalid."),
+    (
+        "invalid_payload",
+        "The signed payload is missing fields or malformed.",
+    ),
+    (
+        "invalid_or_expired_credential",
+        "The credential is unknown or has expired.",
+    ),
+    (
+        "invalid_or_expired_session",
+        "The session is unknown or has expired.",
+    ),
+    (
+        "invalid_or_expired_verification_token",
+        "The verification token is unknown or has expired.",
+    ),
+    (
+        "invalid_preference_key",
+        "Preference keys must not be blank.",
+    ),
+    (
+        "invalid_signature",
+        "The signature does not match the credential.",
+    ),
+    (
+        "jws_alg_unsupported",
+        "The JWS must use the EdDSA algorithm.",
+    ),
+    ("jws_invalid", "The JWS is malformed."),
+    (
+        "jws_with_signature",
+        "Send either a JWS or a message with a signature, not both.",
+    ),
+    (
+        "label_invalid",
+        "The label contains control or formatting characters.",
+    ),
+    ("label_too_long", "The label is too long."),
+    (
+        "key_encoding_failed",
+        "The server could not encode the key.",
+    ),
+    (
+        "message_encoding_unsupported",
+        "The message encoding must be utf8 or base64url.",
+    ),
+    (
+        "message_not_base64url",
+        "The message is not valid unpadded base64url.",
+    ),
+    ("message_required", "A message to sign is required."),
+    ("message_too_large", "The message to sign is too large."),
+    (
+        "multipart_invalid",
+        "The multipart form could not be parsed.",
+    ),
+    (
+        "new_public_key_invalid",
+        "The new public key is not a valid Ed25519 key.",
+    ),
+    ("new_public_key_required", "A new public key is required."),
+    ("not_ready", "The server has not finished starting up."),
+    (
+        "one_time_code_disabled",
+        "This server does not send one-time codes.",
+    ),
+    (
+        "overloaded",
+        "The server is handling too many requests. Retry shortly.",
+    ),
+    ("payload_expired", "The signed payload has expired."),
+    (
+        "pow_disabled",
+        "This server does not require proof-of-work.",
+    ),
+    (
+        "pow_invalid",
+        "The proof-of-work is wrong, expired or already used.",
+    ),
+    (
+        "pow_required",
+        "A proof-of-work challenge and nonce are required.",
+    ),
+    (
+        "payload_mismatch",
+        "The signed payload is for another purpose or credential.",
+    ),
+    ("payload_required", "A payload file part is required."),
+    (
+        "payload_too_large",
+        "The request body or uploaded file is too large.",
+    ),
+    (
+        "preferences_empty",
+        "Preferences must contain at least one entry.",
+    ),
+    (
+        "preferences_must_be_object",
+        "Preferences must be a JSON object.",
+    ),
+    (
+        "preferences_signature_invalid",
+        "The preferences signature is invalid.",
+    ),
+    (
+        "public_key_invalid",
+        "The public key must be a base64url 32-byte Ed25519 key.",
+    ),
+    ("public_key_required", "A public key is required."),
+    (
+        "rate_limited",
+        "Too many requests from this address. Retry after the time in Retry-After.",
+    ),
+    (
+        "response_signing_failed",
+        "The server could not sign the response.",
+    ),
+    (
+        "rng_unavailable",
+        "The server could not generate secure random data.",
+    ),
+    (
+        "scope_not_allowed",
+        "The session is not entitled to the requested scope.",
+    ),
+    ("scope_required", "At least one scope is required."),
+    (
+        "service_unavailable",
+        "The request took too long and was cancelled. Retry shortly.",
+    ),
+    ("session_required", "A session token is required."),
+    (
+        "snapshot_invalid",
+        "The snapshot contains an empty id, an invalid public key or an impossible lifetime.",
+    ),
+    (
+        "snapshot_version_unsupported",
+        "The snapshot version is not supported by this server.",
+    ),
+    (
+        "signature_invalid_format",
+        "The signature is not a valid Ed25519 signature.",
+    ),
+    (
+        "signature_not_base64url",
+        "The signature must be base64url encoded.",
+    ),
+    (
+        "signature_replayed",
+        "This signature has already been used.",
+    ),
+    ("signature_required", "A signature is required."),
+    (
+        "signature_too_large",
+        "The signature is longer than an Ed25519 signature.",
+    ),
+    (
+        "unsupported_media_type",
+        "The request must have Content-Type: application/json.",
+    ),
+    (
+        "unsupported_payload_version",
+        "This payload version is not supported.",
+    ),
+    (
+        "transfer_encoding_invalid",
+        "The Transfer-Encoding header is not supported.",
+    ),
+    ("unknown_field", "The request contains an unexpected field."),
+    ("username_required", "A username is required."),
+    (
+        "verification_token_required",
+        "A verification token is required.",
+    ),
+];
+
+const RU: &[(&str, &str)] = &[
+    (
+        "admin_disabled",
+        "Административные методы отключены на этом сервере.",
+    ),
+    (
+        "admin_token_invalid",
+        "Токен администратора отсутствует или неверен.",
+    ),
+    (
+        "alg_unsupported",
+        "Алгоритм подписи должен быть ed25519 или ed25519ph.",
+    ),
+    (
+        "audience_mismatch",
+        "Подписанные данные предназначены для другого сервера.",
+    ),
+    ("batch_too_many_lines", "Слишком много записей в пакете."),
+    ("body_read_failed", "Не удалось прочитать тело запроса."),
+    ("capacity_reached", "Сервер перегружен. Повторите позже."),
+    (
+        "confirmation_required",
+        "Укажите \"confirm\": true, чтобы выполнить операцию.",
+    ),
+    (
+        "content_length_invalid",
+        "Запрос содержит более одного заголовка Content-Length.",
+    ),
+    (
+        "credential_id_required",
+        "Требуется идентификатор учётных данных.",
+    ),
+    (
+        "credential_not_found",
+        "Учётные данные не найдены или истекли.",
+    ),
+    (
+        "delegation_exceeds_parent",
+        "Делегированные учётные данные не могут иметь больше прав или жить дольше родительских.",
+    ),
+    (
+        "delegation_invalid",
+        "Для делегирования нужен положительный ttl_seconds и, если указаны, хотя бы одна область.",
+    ),
+    ("csrf_failed", "CSRF-токен отсутствует или не совпадает."),
+    (
+        "draining",
+        "Сервер завершает работу и не принимает новые входы.",
+    ),
+    ("empty_body", "Тело запроса пустое."),
+    ("enter_refused", "Учётные данные или подпись не приняты."),
+    (
+        "credential_locked",
+        "Слишком много неверных подписей. Повторите позже.",
+    ),
+    (
+        "fault_injected",
+        "Тестовый сбой (POC_FAULT_ENABLE). Повторите запрос.",
+    ),
+    (
+        "field_invalid",
+        "Поле в теле запроса имеет неверный тип или значение.",
+    ),
+    (
+        "field_required",
+        "В теле запроса отсутствует обязательное поле.",
+    ),
+    (
+        "flow_expired",
+        "С момента проверки прошло слишком много времени. Начните заново с шага 1.",
+    ),
+    (
+        "format_unsupported",
+        "Формат ключа должен быть raw или pem.",
+    ),
+    (
+        "free_text_message_refused",
+        "Произвольный текст не принимается; отправьте данные v1 или байты в base64url.",
+    ),
+    (
+        "headers_too_large",
+        "Запрос содержит слишком много заголовков или они слишком велики.",
+    ),
+    (
+        "insufficient_scope",
+        "Токен не предоставляет права, необходимые для этого метода.",
+    ),
+    ("invalid code", "Неверный код подтверждения."),
+    ("invalid_json", "Тело запроса не является корректным JSON."),
+    (
+        "invalid_preference_value",
+        "Настройка содержит бесконечное или слишком большое число.",
+    ),
+    ("invalid_query", "Некорректная строка запроса."),
+    (
+        "invalid_payload",
+        "Подписанные данные неполны или некорректны.",
+    ),
+    (
+        "invalid_or_expired_credential",
+        "Учётные данные не найдены или истекли.",
+    ),
+    (
+        "invalid_or_expired_session",
+        "Сессия не найдена или истекла.",
+    ),
+    (
+        "invalid_or_expired_verification_token",
+        "Токен подтверждения не найден или истёк.",
+    ),
+    (
+        "invalid_preference_key",
+        "Ключи настроек не могут быть пустыми.",
+    ),
+    (
+        "invalid_signature",
+        "Подпись не соответствует учётным данным.",
+    ),
+    (
+        "jws_alg_unsupported",
+        "JWS должен использовать алгоритм EdDSA.",
+    ),
+    ("jws_invalid", "JWS имеет неверный формат."),
+    (
+        "jws_with_signature",
+        "Передайте либо JWS, либо сообщение с подписью, но не оба.",
+    ),
+    (
+        "label_invalid",
+        "Метка содержит управляющие символы или символы форматирования.",
+    ),
+    ("label_too_long", "Метка слишком длинная."),
+    (
+        "key_encoding_failed",
+        "Серверу не удалось закодировать ключ.",
+    ),
+    (
+        "message_encoding_unsupported",
+        "Кодировка сообщения должна быть utf8 или base64url.",
+    ),
+    (
+        "message_not_base64url",
+        "Сообщение не является корректным base64url без дополнения.",
+    ),
+    ("message_required", "Требуется сообщение для подписи."),
+    (
+        "message_too_large",
+        "Сообщение для подписи слишком большое.",
+    ),
+    ("multipart_invalid", "Не удалось разобрать multipart-форму."),
+    (
+        "new_public_key_invalid",
+        "Новый открытый ключ не является ключом Ed25519.",
+    ),
+    ("new_public_key_required", "Требуется новый открытый ключ."),
+    ("not_ready", "Сервер ещё не завершил запуск."),
+    (
+        "one_time_code_disabled",
+        "Сервер не отправляет одноразовые коды.",
+    ),
+    (
+        "overloaded",
+        "Сервер перегружен запросами. Повторите попытку позже.",
+    ),
+    ("payload_expired", "Срок действия подписанных данных истёк."),
+    ("pow_disabled", "Сервер не требует доказательства работы."),
+    (
+        "pow_invalid",
+        "Доказательство работы неверно, истекло или уже использовано.",
+    ),
+    (
+        "pow_required",
+        "Требуются задача доказательства работы и её решение.",
+    ),
+    (
+        "payload_mismatch",
+        "Подписанные данные относятся к другой операции или учётным данным.",
+    ),
+    ("payload_required", "Требуется файл payload."),
+    (
+        "payload_too_large",
+        "Тело запроса или загруженный файл слишком велики.",
+    ),
+    (
+        "preferences_empty",
+        "Настройки должны содержать хотя бы одно значение.",
+    ),
+    (
+        "preferences_must_be_object",
+        "Настройки должны быть JSON-объектом.",
+    ),
+    ("preferences_signature_invalid", "Подпись настроек неверна."),
+    (
+        "public_key_invalid",
+        "Открытый ключ должен быть 32-байтовым ключом Ed25519 в base64url.",
+    ),
+    ("public_key_required", "Требуется открытый ключ."),
+    (
+        "rate_limited",
+        "Слишком много запросов с этого адреса. Повторите через время из Retry-After.",
+    ),
+    (
+        "response_signing_failed",
+        "Серверу не удалось подписать ответ.",
+    ),
+    (
+        "rng_unavailable",
+        "Серверу не удалось получить случайные данные.",
+    ),
+    (
+        "scope_not_allowed",
+        "Сессия не имеет права на запрошенную область доступа.",
+    ),
+    ("scope_required", "Требуется хотя бы одна область доступа."),
+    (
+        "service_unavailable",
+        "Запрос выполнялся слишком долго и был прерван. Повторите попытку позже.",
+    ),
+    ("session_required", "Требуется токен сессии."),
+    (
+        "snapshot_invalid",
+        "Снимок содержит пустой идентификатор, неверный открытый ключ или невозможный срок действия.",
+    ),
+    (
+        "snapshot_version_unsupported",
+        "Эта версия снимка не поддерживается сервером.",
+    ),
+    (
+        "signature_invalid_format",
+        "Подпись не является подписью Ed25519.",
+    ),
+    (
+        "signature_not_base64url",
+        "Подпись должна быть в кодировке base64url.",
+    ),
+    ("signature_replayed", "Эта подпись уже использовалась."),
+    ("signature_required", "Требуется подпись."),
+    ("signature_too_large", "Подпись длиннее подписи Ed25519."),
+    (
+        "unsupported_media_type",
+        "Запрос должен иметь Content-Type: application/json.",
+    ),
+    (
+        "unsupported_payload_version",
+        "Эта версия подписанных данных не поддерживается.",
+    ),
+    (
+        "transfer_encoding_invalid",
+        "Заголовок Transfer-Encoding не поддерживается.",
+    ),
+    ("unknown_field", "Запрос содержит неожиданное поле."),
+    ("username_required", "Требуется имя пользователя."),
+    (
+        "verification_token_required",
+        "Требуется токен подтверждения.",
+    ),
+];
+
+fn build() -> Catalog {
+    [("en", EN), ("ru", RU)]
+        .into_iter()
+        .map(|(locale, entries)| (locale, entries.iter().copied().collect()))
+        .collect()
+}
+
+fn catalog() -> &'static Catalog {
+    CATALOG.get_or_init(build)
+}
+
+// Called once in main so the catalog is built before the first request.
+pub fn load() {
+    catalog();
+}
+
+// Picks the supported locale with the highest q-value, e.g. "ru-RU,ru;q=0.9,en;q=0.8" -> "ru".
+pub fn negotiate(accept_language: &str) -> &'static str {
+    let mut best: Option<(&'static str, f32)> = None;
+
+    for part in accept_language.split(',') {
+        let mut pieces = part.trim().split(';');
+        let tag = pieces.next().unwrap_or("").trim();
+        let q = pieces
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let primary = tag.split('-').next().unwrap_or("").to_ascii_lowercase();
+
+        if let Some((&locale, _)) = catalog().get_key_value(primary.as_str())
+            && q > 0.0
+            && best.is_none_or(|(_, best_q)| q > best_q)
+        {
+            best = Some((locale, q));
+        }
+    }
+
+    best.map(|(locale, _)| locale).unwrap_or(DEFAULT_LOCALE)
+}
+
+pub fn current_locale() -> &'static str {
+    REQUEST_LOCALE
+        .try_with(|locale| *locale)
+        .unwrap_or(DEFAULT_LOCALE)
+}
+
+// Falls back to English when the locale lacks a translation.
+pub fn message(locale: &str, code: &str) -> Option<&'static str> {
+    let catalog = catalog();
+    catalog
+        .get(locale)
+        .and_then(|m| m.get(code))
+        .or_else(|| catalog.get(DEFAULT_LOCALE)?.get(code))
+        .copied()
+}
@@ -0,0 +1,39 @@
+// Out-of-band delivery of one-time codes (SMS, email, ...).
+// The POC only logs them; real deployments implement `CodeNotifier` themselves.
+
+use axum::async_trait;
+use std::sync::Arc;
+
+pub const CODE_NOTIFIER_ENV: &str = "POC_CODE_NOTIFIER";
+
+#[async_trait]
+pub trait CodeNotifier: Send + Sync {
+    async fn send(&self, username: &str, code: &str);
+}
+
+// Default: prints the code to stdout so the demo can be followed by hand.
+pub struct LogNotifier;
+
+#[async_trait]
+impl CodeNotifier for LogNotifier {
+    async fn send(&self, username: &str, code: &str) {
+        println!("one-time code for {username}: {code}");
+    }
+}
+
+// Drops codes; useful when something else delivers them or in load tests.
+pub struct NoopNotifier;
+
+#[async_trait]
+impl CodeNotifier for NoopNotifier {
+    async fn send(&self, _username: &str, _code: &str) {}
+}
+
+pub fn notifier_from_env() -> Arc<dyn CodeNotifier> {
+    let kind = std::env::var(CODE_NOTIFIER_ENV).unwrap_or_else(|_| "log".into());
+    match kind.trim() {
+        "log" => Arc::new(LogNotifier),
+        "none" | "noop" => Arc::new(NoopNotifier),
+        other => panic!("{CODE_NOTIFIER_ENV}: unsupported notifier {other:?}"),
+    }
+}
@@ -0,0 +1,195 @@
+// Pure decoding and validation of request fields.
+// No axum or AppState in here: the fuzz targets in server/fuzz include this file as-is.
+
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde_json::{Number, Value};
+
+// Sorted keys, normalized numbers, no whitespace. Both the client and the server
+// sign/verify these bytes, so the client keeps an identical copy.
+pub fn canonical_json(value: &Value) -> String {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            let fields: Vec<String> = keys
+                .into_iter()
+                .map(|k| format!("{}:{}", Value::String(k.clone()), canonical_json(&map[k])))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        }
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(canonical_json).collect();
+            format!("[{}]", items.join(","))
+        }
+        Value::Number(n) => canonical_number(n),
+        other => other.to_string(),
+    }
+}
+
+// Equal numbers print the same: 1, 1.0 and 1e0 all become "1" (and -0.0 becomes "0").
+fn canonical_number(n: &Number) -> String {
+    match n.as_f64() {
+        // exactly representable integers; larger ones keep their own digits
+        Some(f) if n.is_f64() && f.fract() == 0.0 && f.abs() < 9_007_199_254_740_992.0 => {
+            (f as i64).to_string()
+        }
+        Some(f) if n.is_f64() => f.to_string(),
+        _ => n.to_string(),
+    }
+}
+
+// base64url (no padding) of a 64-byte Ed25519 signature
+pub const SIGNATURE_B64_LEN: usize = 86;
+
+// Err is the API error code. Anything longer than a signature is refused before decoding.
+pub fn decode_signature(encoded: &str) -> Result<Signature, &'static str> {
+    if encoded.len() > SIGNATURE_B64_LEN {
+        return Err("signature_too_large");
+    }
+    let sig_bytes = URL_SAFE_NO_PAD
+        .decode(encoded.as_bytes())
+        .map_err(|_| "signature_not_base64url")?;
+
+    Signature::from_slice(&sig_bytes).map_err(|_| "signature_invalid_format")
+}
+
+pub fn decode_public_key(encoded: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = URL_SAFE_NO_PAD
+        .decode(encoded.as_bytes())
+        .ok()?
+        .try_into()
+        .ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+// Longest credential label, in characters.
+pub const MAX_LABEL_CHARS: usize = 64;
+
+// A credential display name such as "laptop", shown as-is by account UIs. Control
+// characters and bidi/zero-width formatting characters are refused, so a label can't
+// break lines or reorder the text around it. Trimmed; empty means no label.
+pub fn validate_label(raw: &str) -> Result<Option<String>, &'static str> {
+    let label = raw.trim();
+    if label.chars().count() > MAX_LABEL_CHARS {
+        return Err("label_too_long");
+    }
+    let formatting = |c: char| matches!(c, '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}');
+    if label.chars().any(|c| c.is_control() || formatting(c)) {
+        return Err("label_invalid");
+    }
+    Ok((!label.is_empty()).then(|| label.to_string()))
+}
+
+// Default bound on numbers in preferences: 2^53 - 1, the largest integer a consumer
+// that reads JSON numbers as doubles (JavaScript) still gets back exactly.
+pub const DEFAULT_MAX_PREFERENCE_NUMBER: u64 = 9_007_199_254_740_991;
+
+// The top-level key whose value holds, at any depth, a number that is not finite or
+// whose magnitude exceeds `max`.
+pub fn out_of_range_number(obj: &Value, max: u64) -> Option<&str> {
+    obj.as_object()?
+        .iter()
+        .find(|(_, value)| !numbers_in_range(value, max))
+        .map(|(key, _)| key.as_str())
+}
+
+fn numbers_in_range(value: &Value, max: u64) -> bool {
+    match value {
+        Value::Number(n) => match (n.as_u64(), n.as_i64(), n.as_f64()) {
+            (Some(u), _, _) => u <= max,
+            (_, Some(i), _) => i.unsigned_abs() <= max,
+            (_, _, Some(f)) => f.is_finite() && f.abs() <= max as f64,
+            _ => false,
+        },
+        Value::Array(items) => items.iter().all(|v| numbers_in_range(v, max)),
+        Value::Object(map) => map.values().all(|v| numbers_in_range(v, max)),
+        _ => true,
+    }
+}
+
+pub fn validate_preferences(obj: &Value) -> Result<(), &'static str> {
+    let map = obj.as_object().ok_or("preferences_must_be_object")?;
+
+    if map.is_empty() {
+        return Err("preferences_empty");
+    }
+
+    if map.keys().any(|k| k.trim().is_empty()) {
+        return Err("invalid_preference_key");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn canonical_json_sorts_keys_at_every_depth() {
+        let value = json!({ "b": [{ "z": 1, "a": 2 }], "a": { "y": null, "x": "s" } });
+        assert_eq!(
+            canonical_json(&value),
+            r#"{"a":{"x":"s","y":null},"b":[{"a":2,"z":1}]}"#
+        );
+    }
+
+    #[test]
+    fn equal_numbers_print_the_same() {
+        for (raw, expected) in [
+            ("1", "1"),
+            ("1.0", "1"),
+            ("1e0", "1"),
+            ("-0.0", "0"),
+            ("2.5", "2.5"),
+            ("18446744073709551615", "18446744073709551615"),
+        ] {
+            let value: Value = serde_json::from_str(raw).unwrap();
+            assert_eq!(canonical_json(&value), expected, "{raw}");
+        }
+    }
+
+    #[test]
+    fn canonical_json_is_stable_across_a_round_trip() {
+        let value = json!({ "text": "line\n\"quoted\" é", "n": 1.5, "list": [3, 2, 1] });
+        let once = canonical_json(&value);
+        let reparsed: Value = serde_json::from_str(&once).unwrap();
+        assert_eq!(reparsed, value);
+        assert_eq!(canonical_json(&reparsed), once);
+    }
+
+    #[test]
+    fn numbers_past_the_bound_name_their_top_level_key() {
+        let max = DEFAULT_MAX_PREFERENCE_NUMBER;
+        let within = json!({ "a": max, "b": -(max as i64), "c": 0.5, "d": [1, { "e": 2 }] });
+        assert_eq!(out_of_range_number(&within, max), None);
+
+        for (value, key) in [
+            (json!({ "ok": 1, "big": max + 1 }), "big"),
+            (json!({ "neg": i64::MIN }), "neg"),
+            (json!({ "float": 1e300 }), "float"),
+            (json!({ "deep": { "list": [0, u64::MAX] } }), "deep"),
+        ] {
+            assert_eq!(out_of_range_number(&value, max), Some(key), "{value}");
+        }
+        assert_eq!(out_of_range_number(&json!({ "n": 11 }), 10), Some("n"));
+    }
+
+    #[test]
+    fn an_oversized_signature_is_refused_before_decoding() {
+        // valid base64url, but one character longer than any signature
+        let long = "A".repeat(SIGNATURE_B64_LEN + 1);
+        assert_eq!(decode_signature(&long).unwrap_err(), "signature_too_large");
+        assert_eq!(
+            decode_signature(&"!".repeat(SIGNATURE_B64_LEN)).unwrap_err(),
+            "signature_not_base64url"
+        );
+        assert_eq!(
+            decode_signature("AAAA").unwrap_err(),
+            "signature_invalid_format"
+        );
+        assert!(decode_signature(&"A".repeat(SIGNATURE_B64_LEN)).is_ok());
+    }
+}
@@ -0,0 +1,265 @@
+// Versioned, structured messages for proof-of-possession.
+// A JSON `message` must be one of these; the signature covers its canonical JSON
+// (see `canonical_json`), so key order and whitespace in the request don't matter.
+
+use crate::{parse::canonical_json, session::unix_now};
+use axum::http::StatusCode;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use serde::Deserialize;
+use serde_json::Value;
+
+pub(crate) const PAYLOAD_VERSION: u64 = 1;
+
+// {"v":1,"purpose":"enter","credential_id":"...","nonce":"...","exp":1700000000}
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PayloadV1 {
+    // already checked against PAYLOAD_VERSION
+    #[serde(rename = "v")]
+    _version: u64,
+    purpose: String,
+    credential_id: String,
+    nonce: String,
+    // unix seconds
+    exp: u64,
+    // the server the signature is meant for (POC_AUDIENCE)
+    #[serde(default)]
+    aud: Option<String>,
+}
+
+// How a step 3 `message` becomes the bytes that are signed (`message_encoding`):
+// - "utf8" (default): a v1 payload, signed as its canonical JSON, or free text, signed as
+//   its UTF-8 bytes exactly as received. The server never normalizes Unicode, so "é" as
+//   U+00E9 and as "e" + U+0301 are different messages: a client that lets a user type the
+//   text must sign the same code points it sends.
+// - "base64url": arbitrary bytes, unpadded base64url; the decoded bytes are signed.
+// With `strict` (POC_STRICT_MESSAGES), free text is refused: only payloads and base64url.
+pub(crate) fn message_bytes(
+    message: &str,
+    encoding: Option<&str>,
+    strict: bool,
+    purpose: &str,
+    credential_id: &str,
+    audience: Option<&str>,
+) -> Result<Vec<u8>, (StatusCode, &'static str)> {
+    match encoding.unwrap_or("utf8") {
+        "utf8" => {
+            refuse_free_text(message, strict)?;
+            signed_bytes(message, purpose, credential_id, audience)
+        }
+        // opaque bytes can't carry `aud`, like free text
+        "base64url" if audience.is_some() => {
+            Err((StatusCode::UNPROCESSABLE_ENTITY, "audience_mismatch"))
+        }
+        "base64url" => URL_SAFE_NO_PAD
+            .decode(message)
+            .map_err(|_| (StatusCode::UNPROCESSABLE_ENTITY, "message_not_base64url")),
+        _ => Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "message_encoding_unsupported",
+        )),
+    }
+}
+
+// POC_STRICT_MESSAGES: anything that isn't a JSON object would be signed as free text.
+fn refuse_free_text(message: &str, strict: bool) -> Result<(), (StatusCode, &'static str)> {
+    if strict && !matches!(serde_json::from_str::<Value>(message), Ok(Value::Object(_))) {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "free_text_message_refused",
+        ));
+    }
+    Ok(())
+}
+
+// Returns the bytes the signature must cover. Free-text (non-JSON) messages are still
+// accepted as-is for older clients; JSON objects must be a valid payload for `purpose`.
+// With an `audience` configured, only payloads whose `aud` equals it are accepted, so
+// free text is refused; without one, a payload naming any `aud` is refused, since it
+// was meant for some other server.
+pub(crate) fn signed_bytes(
+    message: &str,
+    purpose: &str,
+    credential_id: &str,
+    audience: Option<&str>,
+) -> Result<Vec<u8>, (StatusCode, &'static str)> {
+    let value = match serde_json::from_str::<Value>(message) {
+        Ok(v @ Value::Object(_)) => v,
+        _ if audience.is_some() => {
+            return Err((StatusCode::UNPROCESSABLE_ENTITY, "audience_mismatch"));
+        }
+        _ => return Ok(message.as_bytes().to_vec()),
+    };
+
+    // check the version first: later versions may carry fields v1 doesn't know
+    match value.get("v").and_then(Value::as_u64) {
+        Some(PAYLOAD_VERSION) => {}
+        Some(_) => {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "unsupported_payload_version",
+            ));
+        }
+        None => return Err((StatusCode::UNPROCESSABLE_ENTITY, "invalid_payload")),
+    }
+
+    let payload: PayloadV1 = serde_json::from_value(value.clone())
+        .map_err(|_| (StatusCode::UNPROCESSABLE_ENTITY, "invalid_payload"))?;
+    if payload.nonce.trim().is_empty() {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, "invalid_payload"));
+    }
+    if payload.purpose != purpose || payload.credential_id != credential_id {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, "payload_mismatch"));
+    }
+    if payload.aud.as_deref() != audience {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, "audience_mismatch"));
+    }
+    if payload.exp <= unix_now() {
+        return Err((StatusCode::UNAUTHORIZED, "payload_expired"));
+    }
+
+    Ok(canonical_json(&value).into_bytes())
+}
+
+// Compact JWS (RFC 7515) as an alternative to message + signature, for JOSE clients.
+// Only `"alg":"EdDSA"` is accepted. An empty payload segment means detached content
+// (appendix F): the payload is then `detached`, the request's `message`. The payload
+// must pass the same checks as `message`, `strict` included. Returns the JWS signing input
+// (`header.payload`, which is what the signature covers) and the signature segment.
+pub(crate) fn jws_signing_input(
+    jws: &str,
+    detached: Option<&str>,
+    strict: bool,
+    credential_id: &str,
+    audience: Option<&str>,
+) -> Result<(Vec<u8>, String), (StatusCode, &'static str)> {
+    let invalid = (StatusCode::UNPROCESSABLE_ENTITY, "jws_invalid");
+    let mut parts = jws.trim().split('.');
+    let (Some(header_b64), Some(payload_b64), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid);
+    };
+
+    let header: Value = URL_SAFE_NO_PAD
+        .decode(header_b64)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or(invalid)?;
+    let header = header.as_object().ok_or(invalid)?;
+    if header.get("alg").and_then(Value::as_str) != Some("EdDSA") {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, "jws_alg_unsupported"));
+    }
+    // no header extensions are understood, so a critical one can't be honoured
+    if header.contains_key("crit") {
+        return Err(invalid);
+    }
+
+    let payload_b64 = match (payload_b64, detached) {
+        ("", Some(message)) => URL_SAFE_NO_PAD.encode(message),
+        (attached, None) if !attached.is_empty() => attached.to_string(),
+        _ => return Err(invalid),
+    };
+    let payload = URL_SAFE_NO_PAD
+        .decode(&payload_b64)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or(invalid)?;
+    refuse_free_text(&payload, strict)?;
+    signed_bytes(&payload, "enter", credential_id, audience)?;
+
+    Ok((
+        format!("{header_b64}.{payload_b64}").into_bytes(),
+        signature.to_string(),
+    ))
+}
+
+// Domain separation (POC_SIGNING_CONTEXT): `context || 0x00 || message`, so a signature
+// made for this service doesn't verify anywhere that signs the bare message.
+// An empty context leaves the message unchanged.
+pub(crate) fn with_context(context: &str, message: &[u8]) -> Vec<u8> {
+    if context.is_empty() {
+        return message.to_vec();
+    }
+    let mut out = Vec::with_capacity(context.len() + 1 + message.len());
+    out.extend_from_slice(context.as_bytes());
+    out.push(0);
+    out.extend_from_slice(message);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "café" typed two ways: precomposed U+00E9 and "e" + combining U+0301
+    const NFC: &str = "caf\u{e9}";
+    const NFD: &str = "cafe\u{301}";
+
+    fn jws(payload: &str) -> String {
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"EdDSA"}"#);
+        format!("{header}.{}.sig", URL_SAFE_NO_PAD.encode(payload))
+    }
+
+    #[test]
+    fn the_context_is_prefixed_with_a_separator() {
+        assert_eq!(with_context("", b"message"), b"message");
+        assert_eq!(with_context("poc", b"message"), b"poc\0message");
+        // the separator keeps "ab" + "c" apart from "a" + "bc"
+        assert_ne!(with_context("ab", b"c"), with_context("a", b"bc"));
+    }
+
+    #[test]
+    fn free_text_is_signed_as_sent_without_normalization() {
+        let nfc = message_bytes(NFC, None, false, "enter", "c", None).unwrap();
+        let nfd = message_bytes(NFD, None, false, "enter", "c", None).unwrap();
+        assert_eq!(nfc, NFC.as_bytes());
+        assert_eq!(nfd, NFD.as_bytes());
+        assert_ne!(nfc, nfd);
+    }
+
+    #[test]
+    fn base64url_carries_the_exact_bytes() {
+        let decoded = message_bytes("ZcyB", Some("base64url"), true, "enter", "c", None).unwrap();
+        assert_eq!(decoded, "e\u{301}".as_bytes());
+    }
+
+    #[test]
+    fn strict_mode_refuses_free_text() {
+        for text in [NFC, NFD, "\"quoted\"", "42"] {
+            assert_eq!(
+                message_bytes(text, None, true, "enter", "c", None)
+                    .unwrap_err()
+                    .1,
+                "free_text_message_refused"
+            );
+        }
+    }
+
+    #[test]
+    fn strict_mode_refuses_a_free_text_jws() {
+        assert!(jws_signing_input(&jws(NFD), None, false, "c", None).is_ok());
+        assert_eq!(
+            jws_signing_input(&jws(NFD), None, true, "c", None)
+                .unwrap_err()
+                .1,
+            "free_text_message_refused"
+        );
+        // detached content is held to the same rule
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"EdDSA"}"#);
+        assert_eq!(
+            jws_signing_input(&format!("{header}..sig"), Some(NFC), true, "c", None)
+                .unwrap_err()
+                .1,
+            "free_text_message_refused"
+        );
+    }
+
+    #[test]
+    fn strict_mode_accepts_a_v1_payload_jws() {
+        let payload = serde_json::json!({
+            "v": 1, "purpose": "enter", "credential_id": "c", "nonce": "n", "exp": unix_now() + 60,
+        });
+        assert!(jws_signing_input(&jws(&payload.to_string()), None, true, "c", None).is_ok());
+    }
+}
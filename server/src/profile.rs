@@ -0,0 +1,230 @@
+// Deployment profile (POC_ENV). The defaults are chosen so the demo runs with no setup,
+// which makes them unsafe to deploy: in `production` the server refuses to start while
+// any of them is still in effect, in `development` (the default) it only warns.
+
+use crate::{
+    auth,
+    config::{
+        CORS_ORIGINS_ENV, ENABLED_ROUTES_ENV, HSTS_ENV, PUBLIC_KEY_LOOKUP_OPEN_ENV,
+        TEST_RNG_SEED_ENV,
+    },
+    faults::{FAULT_ENABLE_ENV, Faults},
+    state::AppState,
+};
+
+pub(crate) const PROFILE_ENV: &str = "POC_ENV";
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Profile {
+    Development,
+    Production,
+}
+
+impl Profile {
+    pub(crate) fn from_env() -> Self {
+        match std::env::var(PROFILE_ENV).as_deref().map(str::trim) {
+            Err(_) | Ok("development") => Profile::Development,
+            Ok("production") => Profile::Production,
+            Ok(other) => panic!("{PROFILE_ENV}: unknown profile {other:?}"),
+        }
+    }
+}
+
+// Every unsafe default still in effect, each naming the setting that fixes it.
+pub(crate) fn unsafe_settings(state: &AppState) -> Vec<String> {
+    let config = &state.config;
+    let addr = config.bind;
+    let mut unsafe_settings = Vec::new();
+    if config.cors_origins.is_empty() {
+        unsafe_settings.push(format!(
+            "CORS allows any origin; list the allowed ones in {CORS_ORIGINS_ENV}"
+        ));
+    }
+    if state.auth.mode() == "static_code" {
+        unsafe_settings.push(format!(
+            "{} is static_code: every user verifies with the same hardcoded code",
+            auth::AUTH_MODE_ENV
+        ));
+    }
+    // the server never terminates TLS itself; POC_HSTS is the operator saying a TLS
+    // proxy is in front
+    if !addr.ip().is_loopback() && !config.hsts {
+        unsafe_settings.push(format!(
+            "plain HTTP on {addr} with no TLS proxy declared; put one in front and set {HSTS_ENV}"
+        ));
+    }
+    if config.route_enabled("issue") {
+        unsafe_settings.push(format!(
+            "credential issuance generates private keys on the server; leave `issue` out of {ENABLED_ROUTES_ENV}"
+        ));
+    }
+    // the test-only switches below must never reach a real deployment
+    if state.rng.is_seeded() {
+        unsafe_settings.push(format!(
+            "{TEST_RNG_SEED_ENV} is set: every token and key is predictable; unset it"
+        ));
+    }
+    if Faults::from_env().is_some() {
+        unsafe_settings.push(format!(
+            "{FAULT_ENABLE_ENV} is on: requests fail or stall on purpose; unset the POC_FAULT_* variables"
+        ));
+    }
+    if config.public_key_lookup_open {
+        unsafe_settings.push(format!(
+            "{PUBLIC_KEY_LOOKUP_OPEN_ENV} lets anyone test which credential ids exist; unset it"
+        ));
+    }
+    unsafe_settings
+}
+
+// Runs before the self-check; panics in production with the whole list, so one restart
+// shows everything that still needs changing.
+pub(crate) fn check(state: &AppState) {
+    let found = unsafe_settings(state);
+    if found.is_empty() {
+        return;
+    }
+    let list = found
+        .iter()
+        .map(|s| format!("  - {s}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    match state.config.profile {
+        Profile::Production => {
+            panic!("{PROFILE_ENV}=production refuses these unsafe settings:\n{list}")
+        }
+        Profile::Development => {
+            println!(
+                "WARNING: unsafe for production ({PROFILE_ENV}=production would refuse):\n{list}"
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        state::{EntropySource, state_from},
+        testing,
+    };
+    use axum::http::HeaderValue;
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::Arc,
+    };
+
+    fn found(state: &AppState, setting: &str) -> bool {
+        unsafe_settings(state).iter().any(|s| s.contains(setting))
+    }
+
+    fn bound_to(addr: &str) -> Config {
+        let mut config = testing::config();
+        config.bind = addr.parse().unwrap();
+        config
+    }
+
+    #[test]
+    fn plain_http_is_only_flagged_off_loopback() {
+        assert!(!found(
+            &testing::state_with(bound_to("127.0.0.1:8080")),
+            HSTS_ENV
+        ));
+        assert!(found(
+            &testing::state_with(bound_to("0.0.0.0:8080")),
+            HSTS_ENV
+        ));
+
+        let mut config = bound_to("0.0.0.0:8080");
+        config.hsts = true;
+        assert!(!found(&testing::state_with(config), HSTS_ENV));
+    }
+
+    #[test]
+    fn test_only_switches_are_flagged() {
+        let state = testing::state();
+        assert!(!found(&state, TEST_RNG_SEED_ENV));
+        assert!(!found(&state, PUBLIC_KEY_LOOKUP_OPEN_ENV));
+        assert!(!found(&state, FAULT_ENABLE_ENV));
+
+        let seeded = state_from(
+            testing::config(),
+            Arc::new(EntropySource::seeded(1)),
+            None,
+            None,
+        );
+        assert!(found(&seeded, TEST_RNG_SEED_ENV));
+
+        let mut config = testing::config();
+        config.public_key_lookup_open = true;
+        assert!(found(
+            &testing::state_with(config),
+            PUBLIC_KEY_LOOKUP_OPEN_ENV
+        ));
+    }
+
+    // Production with every unsafe default in the config fixed.
+    fn hardened() -> Config {
+        let mut config = testing::config();
+        config.profile = Profile::Production;
+        config.cors_origins = vec![HeaderValue::from_static("https://app.example")];
+        config.hsts = true;
+        config.enabled_routes.retain(|g| *g != "issue");
+        config
+    }
+
+    // static_code is the state's default backend, not a config field
+    fn without_static_code(config: Config) -> AppState {
+        let mut state = testing::state_with(config);
+        state.auth = Arc::new(auth::OneTimeCodeBackend::new(state.pending_codes.clone()));
+        state
+    }
+
+    fn refuses(state: &AppState) -> bool {
+        panic::catch_unwind(AssertUnwindSafe(|| check(state))).is_err()
+    }
+
+    #[test]
+    fn production_refuses_to_start_with_unsafe_settings() {
+        let safe = without_static_code(hardened());
+        assert!(unsafe_settings(&safe).is_empty());
+        assert!(!refuses(&safe));
+
+        type Undo = fn(&mut Config);
+        let toggles: [(&str, Undo); 4] = [
+            (CORS_ORIGINS_ENV, |c| c.cors_origins.clear()),
+            (HSTS_ENV, |c| {
+                c.bind = "0.0.0.0:8080".parse().unwrap();
+                c.hsts = false;
+            }),
+            (ENABLED_ROUTES_ENV, |c| c.enabled_routes.push("issue")),
+            (PUBLIC_KEY_LOOKUP_OPEN_ENV, |c| {
+                c.public_key_lookup_open = true
+            }),
+        ];
+        for (setting, toggle) in toggles {
+            let mut config = hardened();
+            toggle(&mut config);
+            let state = without_static_code(config);
+            assert_eq!(unsafe_settings(&state).len(), 1, "{setting}");
+            assert!(found(&state, setting), "{setting}");
+            assert!(refuses(&state), "{setting}");
+        }
+
+        let state = testing::state_with(hardened());
+        assert_eq!(unsafe_settings(&state).len(), 1);
+        assert!(found(&state, auth::AUTH_MODE_ENV));
+        assert!(refuses(&state));
+    }
+
+    #[test]
+    fn development_only_warns() {
+        let mut config = testing::config();
+        config.public_key_lookup_open = true;
+        let state = testing::state_with(config);
+        assert!(state.config.profile == Profile::Development);
+        assert!(found(&state, PUBLIC_KEY_LOOKUP_OPEN_ENV));
+        assert!(!refuses(&state));
+    }
+}
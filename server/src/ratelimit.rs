@@ -0,0 +1,183 @@
+// Per-IP request rate limit across all routes (POC_RATE_LIMIT_PER_SEC), a token bucket
+// per peer address. Complements the targeted limits (code attempts, signature lockout,
+// PoW): those protect one secret, this one keeps a single client from flooding the server.
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::Response,
+};
+use dashmap::DashMap;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+// requests per second per IP; unset or 0 = off
+pub const RATE_LIMIT_ENV: &str = "POC_RATE_LIMIT_PER_SEC";
+// requests a client may send at once before the rate applies; default 2 s worth
+pub const RATE_LIMIT_BURST_ENV: &str = "POC_RATE_LIMIT_BURST";
+
+// Probes must keep answering while a client is throttled.
+const EXEMPT_PATHS: &[&str] = &["/api/ready", "/api/version"];
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: DashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    // None when off.
+    pub fn from_env() -> Option<Arc<Self>> {
+        let rate = env_f64(RATE_LIMIT_ENV).unwrap_or(0.0);
+        if rate == 0.0 {
+            return None;
+        }
+        let burst = env_f64(RATE_LIMIT_BURST_ENV).unwrap_or(rate * 2.0);
+        Some(Arc::new(RateLimiter::new(rate, burst)))
+    }
+
+    // `rate` must be positive; a burst below one request is raised to one.
+    pub fn new(rate: f64, burst: f64) -> Self {
+        RateLimiter {
+            rate,
+            burst: burst.max(1.0),
+            buckets: DashMap::new(),
+        }
+    }
+
+    // Takes one token, or says how long until one is available.
+    fn acquire(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    // Drops buckets that have refilled completely; they'd be recreated full anyway.
+    pub fn sweep(&self, now: Instant) -> usize {
+        let full_after = Duration::from_secs_f64(self.burst / self.rate);
+        let before = self.buckets.len();
+        self.buckets
+            .retain(|_, b| now.saturating_duration_since(b.updated) < full_after);
+        before.saturating_sub(self.buckets.len())
+    }
+}
+
+fn env_f64(name: &str) -> Option<f64> {
+    let raw = std::env::var(name).ok()?;
+    let value = raw
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite() && *v >= 0.0)
+        .unwrap_or_else(|| panic!("{name} must be a non-negative number"));
+    Some(value)
+}
+
+// 429 rate_limited with Retry-After in whole seconds (at least 1).
+pub async fn limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if EXEMPT_PATHS.contains(&req.uri().path()) {
+        return next.run(req).await;
+    }
+    if let Err(wait) = limiter.acquire(peer.ip(), Instant::now()) {
+        let mut resp = crate::error::json_error(StatusCode::TOO_MANY_REQUESTS, "rate_limited");
+        let secs = wait.as_secs_f64().ceil().max(1.0) as u64;
+        resp.headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        return resp;
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use crate::testing::{self, send};
+    use axum::{
+        body::Body,
+        extract::Request,
+        http::{StatusCode, header},
+    };
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    const PEER: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn the_bucket_allows_a_burst_then_refills_at_the_rate() {
+        let limiter = RateLimiter::new(2.0, 3.0);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.acquire(PEER, start).is_ok());
+        }
+        assert_eq!(
+            limiter.acquire(PEER, start),
+            Err(Duration::from_millis(500))
+        );
+        // another client has its own bucket
+        assert!(
+            limiter
+                .acquire(IpAddr::V4(Ipv4Addr::BROADCAST), start)
+                .is_ok()
+        );
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.acquire(PEER, later).is_ok());
+        assert!(limiter.acquire(PEER, later).is_err());
+
+        // a bucket that refilled completely is swept
+        assert_eq!(limiter.sweep(start + Duration::from_millis(1400)), 0);
+        assert_eq!(limiter.sweep(later + Duration::from_millis(1500)), 2);
+    }
+
+    fn get(path: &str) -> Request {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rapid_requests_get_429_but_probes_still_answer() {
+        let mut state = testing::state();
+        state.rate_limiter = Some(Arc::new(RateLimiter::new(0.5, 2.0)));
+
+        assert_eq!(
+            send(&state, get("/api/capabilities")).await.0,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(&state, get("/api/capabilities")).await.0,
+            StatusCode::OK
+        );
+        let (status, headers, body) = send(&state, get("/api/capabilities")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["error"], "rate_limited");
+        assert_eq!(headers[header::RETRY_AFTER], "2");
+
+        assert_eq!(send(&state, get("/api/version")).await.0, StatusCode::OK);
+    }
+}
@@ -0,0 +1,232 @@
+// Middleware every request passes through: request ids, logging, limits and headers.
+
+use crate::{
+    config::{CONTENT_SECURITY_POLICY, HSTS_VALUE, MAX_REQUEST_ID_LEN, REQUEST_ID_HEADER},
+    error::json_error,
+    messages,
+    state::{AppState, random_token},
+};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{net::SocketAddr, time::Duration};
+
+// Logs the peer address so keep-alive reuse is visible: same port = same connection.
+pub(crate) async fn log_peer(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    println!(
+        "{} {} from {peer} [{}]",
+        req.method(),
+        req.uri().path(),
+        current_request_id().unwrap_or_default()
+    );
+    next.run(req).await
+}
+
+// Sheds load instead of queueing it: with every permit taken the request is refused
+// at once. The timeout bounds how long one request can keep its permit.
+pub(crate) async fn limit_concurrency(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let _permit = match &state.request_permits {
+        Some(permits) => match permits.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => return json_error(StatusCode::SERVICE_UNAVAILABLE, "overloaded"),
+        },
+        None => None,
+    };
+    match state.config.request_timeout {
+        Some(limit) => tokio::time::timeout(limit, next.run(req))
+            .await
+            .unwrap_or_else(|_| json_error(StatusCode::SERVICE_UNAVAILABLE, "service_unavailable")),
+        None => next.run(req).await,
+    }
+}
+
+// POC_AUTH_MIN_LATENCY_MS: holds the response until `floor` has passed since the
+// request arrived. Slower answers are sent as they are.
+pub(crate) async fn pad_latency(
+    State(floor): State<Duration>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let started = tokio::time::Instant::now();
+    let resp = next.run(req).await;
+    tokio::time::sleep_until(started + floor).await;
+    resp
+}
+
+// Refuses requests a proxy in front might frame differently than we do (request
+// smuggling), and oversized header sets. Hyper already rejects conflicting lengths;
+// this also catches repeated ones and gives each case an API error code.
+pub(crate) async fn reject_dangerous_headers(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let headers = req.headers();
+
+    let size: usize = headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+    if headers.len() > state.config.max_request_headers || size > state.config.max_header_bytes {
+        return json_error(
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            "headers_too_large",
+        );
+    }
+
+    if headers.get_all(header::CONTENT_LENGTH).iter().count() > 1 {
+        return json_error(StatusCode::BAD_REQUEST, "content_length_invalid");
+    }
+
+    // only a single plain "chunked" is understood; anything else (other codings,
+    // obfuscated spellings, several headers) or TE together with Content-Length is refused
+    let mut transfer_encodings = headers.get_all(header::TRANSFER_ENCODING).iter();
+    if let Some(te) = transfer_encodings.next() {
+        let chunked = te.as_bytes().eq_ignore_ascii_case(b"chunked");
+        if !chunked
+            || transfer_encodings.next().is_some()
+            || headers.contains_key(header::CONTENT_LENGTH)
+        {
+            return json_error(StatusCode::BAD_REQUEST, "transfer_encoding_invalid");
+        }
+    }
+
+    next.run(req).await
+}
+
+// Browser hardening headers; handlers never set these, so they are only added.
+pub(crate) async fn security_headers(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let mut resp = next.run(req).await;
+    let headers = resp.headers_mut();
+    if state.config.security_headers {
+        headers.insert(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        );
+        headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+        // the demo UI sets its own
+        if !headers.contains_key(header::CONTENT_SECURITY_POLICY) {
+            headers.insert(
+                header::CONTENT_SECURITY_POLICY,
+                HeaderValue::from_static(CONTENT_SECURITY_POLICY),
+            );
+        }
+    }
+    if state.config.hsts {
+        headers.insert(
+            header::STRICT_TRANSPORT_SECURITY,
+            HeaderValue::from_static(HSTS_VALUE),
+        );
+    }
+    resp
+}
+
+tokio::task_local! {
+    // Set per request by `assign_request_id`.
+    static REQUEST_ID: String;
+}
+
+pub(crate) fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+// Keeps a well-formed incoming X-Request-Id (anything else is replaced, so logs can't be
+// spoofed with arbitrary bytes) and echoes it in the response header and error bodies.
+pub(crate) async fn assign_request_id(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let incoming = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| valid_request_id(id))
+        .map(str::to_string);
+    let id = match incoming {
+        Some(id) => id,
+        None => match random_token(&state.rng, 16) {
+            Ok(id) => id,
+            Err(e) => return e.into_response(),
+        },
+    };
+
+    #[cfg(feature = "otel")]
+    tracing::Span::current().record("request_id", id.as_str());
+
+    let mut resp = REQUEST_ID.scope(id.clone(), next.run(req)).await;
+    if let Ok(v) = HeaderValue::from_str(&id) {
+        resp.headers_mut().insert(REQUEST_ID_HEADER, v);
+    }
+    resp
+}
+
+// Error messages in json_error follow the request's Accept-Language.
+pub(crate) async fn localize_errors(req: Request, next: Next) -> Response {
+    let locale = req
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|v| v.to_str().ok())
+        .map(messages::negotiate)
+        .unwrap_or(messages::DEFAULT_LOCALE);
+
+    messages::REQUEST_LOCALE.scope(locale, next.run(req)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, post_json, send};
+    use axum::{body::Body, extract::Request, http::StatusCode};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn a_request_past_the_timeout_is_cancelled_with_a_503() {
+        let mut config = testing::config();
+        config.auth_min_latency = Duration::from_secs(5);
+        config.request_timeout = Some(Duration::from_millis(20));
+        let state = testing::state_with(config);
+        let req = post_json("/api/step1/verify", &serde_json::json!({}));
+        let (status, _, body) = send(&state, req).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["error"], "service_unavailable");
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_limit_are_shed_not_queued() {
+        let mut config = testing::config();
+        config.max_concurrent_requests = 1;
+        let state = testing::state_with(config);
+        let version = || Request::get("/api/version").body(Body::empty()).unwrap();
+
+        let busy = state.request_permits.clone().unwrap().try_acquire_owned();
+        let (status, _, body) = send(&state, version()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["error"], "overloaded");
+
+        drop(busy);
+        assert_eq!(send(&state, version()).await.0, StatusCode::OK);
+    }
+}
@@ -0,0 +1,124 @@
+// Where operator secrets (admin token, JWT and response signing keys) come from.
+// Read once at startup through `SecretSource`; POC_SECRET_SOURCE picks the backend.
+
+use axum::async_trait;
+use serde_json::Value;
+use std::{sync::Arc, time::Duration};
+
+pub const SECRET_SOURCE_ENV: &str = "POC_SECRET_SOURCE";
+pub const SECRET_URL_ENV: &str = "POC_SECRET_URL";
+pub const SECRET_TOKEN_ENV: &str = "POC_SECRET_TOKEN";
+
+// A secret store that doesn't answer within this is treated as down.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct SecretError(String);
+
+impl std::fmt::Display for SecretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[async_trait]
+pub trait SecretSource: Send + Sync {
+    // Ok(None): the secret is not configured. Err: the source itself failed.
+    async fn get(&self, name: &str) -> Result<Option<String>, SecretError>;
+}
+
+// Default: `admin_token` is read from POC_ADMIN_TOKEN, or from the file named by
+// POC_ADMIN_TOKEN_FILE (e.g. a mounted Kubernetes/Docker secret).
+pub struct EnvFileSource;
+
+#[async_trait]
+impl SecretSource for EnvFileSource {
+    async fn get(&self, name: &str) -> Result<Option<String>, SecretError> {
+        let var = format!("POC_{}", name.to_ascii_uppercase());
+        if let Ok(value) = std::env::var(&var) {
+            return Ok(Some(value));
+        }
+        match std::env::var(format!("{var}_FILE")) {
+            Ok(path) => std::fs::read_to_string(&path)
+                .map(|s| Some(s.trim_end().to_string()))
+                .map_err(|e| SecretError(format!("{var}_FILE={path}: {e}"))),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+// Vault-style KV over HTTP: GET {POC_SECRET_URL}/{name} with `X-Vault-Token`.
+// The value is read from `data.data.value` (KV v2) or `data.value` (KV v1); 404 means unset.
+pub struct HttpSecretSource {
+    http: reqwest::Client,
+    base_url: String,
+    token: Option<String>,
+}
+
+impl HttpSecretSource {
+    pub fn new(base_url: String, token: Option<String>) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .build()
+            .expect("failed to build secret store client");
+        HttpSecretSource {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
+}
+
+#[async_trait]
+impl SecretSource for HttpSecretSource {
+    async fn get(&self, name: &str) -> Result<Option<String>, SecretError> {
+        let url = format!("{}/{name}", self.base_url);
+        let mut req = self.http.get(&url);
+        if let Some(token) = &self.token {
+            req = req.header("X-Vault-Token", token);
+        }
+
+        let resp = req
+            .send()
+            .await
+            .map_err(|e| SecretError(format!("{url}: {e}")))?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            return Err(SecretError(format!("{url}: status {}", resp.status())));
+        }
+
+        let body: Value = resp
+            .json()
+            .await
+            .map_err(|e| SecretError(format!("{url}: {e}")))?;
+        body.pointer("/data/data/value")
+            .or_else(|| body.pointer("/data/value"))
+            .and_then(Value::as_str)
+            .map(|v| Some(v.to_string()))
+            .ok_or_else(|| SecretError(format!("{url}: no data.value in response")))
+    }
+}
+
+pub fn source_from_env() -> Arc<dyn SecretSource> {
+    let kind = std::env::var(SECRET_SOURCE_ENV).unwrap_or_else(|_| "env".into());
+    match kind.trim() {
+        "env" => Arc::new(EnvFileSource),
+        "http" => {
+            let url = std::env::var(SECRET_URL_ENV)
+                .unwrap_or_else(|_| panic!("{SECRET_SOURCE_ENV}=http needs {SECRET_URL_ENV}"));
+            let token = std::env::var(SECRET_TOKEN_ENV).ok();
+            Arc::new(HttpSecretSource::new(url, token))
+        }
+        other => panic!("{SECRET_SOURCE_ENV}: unsupported secret source {other:?}"),
+    }
+}
+
+// Startup helper: an unreachable store stops the server instead of running without secrets.
+pub async fn load(source: &dyn SecretSource, name: &str) -> Option<String> {
+    match source.get(name).await {
+        Ok(value) => value.filter(|v| !v.trim().is_empty()),
+        Err(e) => panic!("secret {name:?} unavailable: {e}"),
+    }
+}
@@ -0,0 +1,469 @@
+// Session token strategy. Step 3 and the session-authenticated endpoints only see
+// `SessionIssuer`; whether the token is an opaque handle or a self-contained JWT
+// is picked at startup with POC_SESSION_FORMAT.
+
+use crate::state::{EntropySource, RngUnavailable, random_token};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use dashmap::DashMap;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde_json::{Value, json};
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub(crate) const SESSION_FORMAT_ENV: &str = "POC_SESSION_FORMAT";
+pub(crate) const SESSION_EVICTION_ENV: &str = "POC_SESSION_EVICTION";
+
+// What to do when the session cap is reached.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum SessionEviction {
+    // refuse the new session (503 capacity_reached)
+    Reject,
+    // drop the least-recently-validated session; its user is silently logged out
+    Lru,
+}
+
+impl SessionEviction {
+    pub(crate) fn from_env() -> Self {
+        let raw = std::env::var(SESSION_EVICTION_ENV).unwrap_or_else(|_| "reject".into());
+        match raw.trim() {
+            "reject" => SessionEviction::Reject,
+            "lru" => SessionEviction::Lru,
+            other => panic!("{SESSION_EVICTION_ENV}: expected lru or reject, got {other:?}"),
+        }
+    }
+}
+
+// Everything a full step 3 session may do. Scoped tokens carry a subset.
+pub(crate) const PREFERENCES_READ: &str = "preferences:read";
+pub(crate) const PREFERENCES_WRITE: &str = "preferences:write";
+pub(crate) const SESSION_SCOPES: &[&str] = &[PREFERENCES_READ, PREFERENCES_WRITE];
+
+// What a session proves: possession of this credential key (plus the CSRF token in cookie mode),
+// and what it may be used for.
+#[derive(Clone)]
+pub(crate) struct SessionClaims {
+    pub(crate) public_key: VerifyingKey,
+    // the step 2 credential the session was entered with, for revocation
+    pub(crate) credential_id: String,
+    pub(crate) csrf_token: Option<String>,
+    pub(crate) scopes: Vec<String>,
+}
+
+pub(crate) trait SessionIssuer: Send + Sync {
+    fn issue(
+        &self,
+        session: &SessionClaims,
+        ttl: Duration,
+    ) -> Result<(String, Instant), RngUnavailable>;
+    fn validate(&self, token: &str) -> Option<SessionClaims>;
+
+    // Drops stored sessions that expired before `now`; returns how many.
+    fn sweep(&self, _now: Instant) -> usize {
+        0
+    }
+
+    // Drops every session entered with this credential; returns how many. Stateless
+    // issuers (JWT) can't revoke and return 0.
+    fn revoke_credential(&self, _credential_id: &str) -> usize {
+        0
+    }
+
+    // Frees one slot for a new session, if the issuer keeps state and evicts (LRU mode).
+    fn evict_oldest(&self) -> bool {
+        false
+    }
+
+    // Puts back a session from an admin snapshot. False when the issuer stores nothing.
+    fn restore(&self, _token: String, _record: SessionRecord) -> bool {
+        false
+    }
+}
+
+// -------------
+// Opaque (default)
+// -------------
+
+#[derive(Clone)]
+pub(crate) struct SessionRecord {
+    pub(crate) claims: SessionClaims,
+    pub(crate) expires_at: Instant,
+    // last issue/validate, for LRU eviction
+    pub(crate) last_access: Instant,
+}
+
+// Random token, claims kept server-side. Can be revoked by removing the record.
+pub(crate) struct OpaqueSessionIssuer {
+    sessions: Arc<DashMap<String, SessionRecord>>,
+    rng: Arc<EntropySource>,
+    // LRU mode only: (last_access, token) ordered oldest first. Lock order is always
+    // index then map; a map guard is never held while taking the index lock.
+    lru: Option<Mutex<BTreeSet<(Instant, String)>>>,
+}
+
+impl OpaqueSessionIssuer {
+    pub(crate) fn new(
+        sessions: Arc<DashMap<String, SessionRecord>>,
+        rng: Arc<EntropySource>,
+        eviction: SessionEviction,
+    ) -> Self {
+        OpaqueSessionIssuer {
+            sessions,
+            rng,
+            lru: (eviction == SessionEviction::Lru).then(|| Mutex::new(BTreeSet::new())),
+        }
+    }
+
+    fn touch(&self, previous: Option<Instant>, now: Instant, token: &str) {
+        if let Some(lru) = &self.lru {
+            let mut lru = lru.lock().expect("lru mutex poisoned");
+            if let Some(previous) = previous {
+                lru.remove(&(previous, token.to_string()));
+            }
+            lru.insert((now, token.to_string()));
+        }
+    }
+}
+
+impl SessionIssuer for OpaqueSessionIssuer {
+    fn issue(
+        &self,
+        session: &SessionClaims,
+        ttl: Duration,
+    ) -> Result<(String, Instant), RngUnavailable> {
+        let token = random_token(&self.rng, 32)?;
+        let now = Instant::now();
+        let expires_at = now + ttl;
+        self.sessions.insert(
+            token.clone(),
+            SessionRecord {
+                claims: session.clone(),
+                expires_at,
+                last_access: now,
+            },
+        );
+        self.touch(None, now, &token);
+        Ok((token, expires_at))
+    }
+
+    fn validate(&self, token: &str) -> Option<SessionClaims> {
+        let now = Instant::now();
+        let live = {
+            let mut rec = self.sessions.get_mut(token)?;
+            (now <= rec.expires_at).then(|| {
+                let previous = rec.last_access;
+                rec.last_access = now;
+                (rec.claims.clone(), previous)
+            })
+        };
+        let Some((claims, previous)) = live else {
+            // removed on the spot, like get_if_live, rather than left for the sweep
+            let removed = self.sessions.remove_if(token, |_, r| now > r.expires_at);
+            if let (Some((_, rec)), Some(lru)) = (removed, &self.lru) {
+                let mut lru = lru.lock().expect("lru mutex poisoned");
+                lru.remove(&(rec.last_access, token.to_string()));
+            }
+            return None;
+        };
+        self.touch(Some(previous), now, token);
+        Some(claims)
+    }
+
+    fn sweep(&self, now: Instant) -> usize {
+        let before = self.sessions.len();
+        self.sessions.retain(|_, v| v.expires_at > now);
+        let reaped = before.saturating_sub(self.sessions.len());
+        if let Some(lru) = &self.lru {
+            let mut lru = lru.lock().expect("lru mutex poisoned");
+            lru.retain(|(_, token)| self.sessions.contains_key(token));
+        }
+        reaped
+    }
+
+    fn revoke_credential(&self, credential_id: &str) -> usize {
+        let before = self.sessions.len();
+        self.sessions
+            .retain(|_, v| v.claims.credential_id != credential_id);
+        let revoked = before.saturating_sub(self.sessions.len());
+        if let Some(lru) = &self.lru {
+            let mut lru = lru.lock().expect("lru mutex poisoned");
+            lru.retain(|(_, token)| self.sessions.contains_key(token));
+        }
+        revoked
+    }
+
+    fn restore(&self, token: String, record: SessionRecord) -> bool {
+        let now = record.last_access;
+        let previous = self
+            .sessions
+            .insert(token.clone(), record)
+            .map(|old| old.last_access);
+        self.touch(previous, now, &token);
+        true
+    }
+
+    fn evict_oldest(&self) -> bool {
+        let Some(lru) = &self.lru else {
+            return false;
+        };
+        loop {
+            let oldest = lru.lock().expect("lru mutex poisoned").pop_first();
+            let Some((last_access, token)) = oldest else {
+                return false;
+            };
+            // skip entries that were touched or swept since they were indexed
+            if self
+                .sessions
+                .remove_if(&token, |_, r| r.last_access == last_access)
+                .is_some()
+            {
+                println!("session evicted (lru): its user is logged out");
+                return true;
+            }
+        }
+    }
+}
+
+// -------------
+// JWT
+// -------------
+
+// EdDSA-signed JWT carrying the claims, nothing stored server-side. The credential
+// key travels as a `cnf` JWK. Tokens can't be revoked before `exp`. The signing key
+// comes from the `jwt_signing_key` secret when set; otherwise it is generated at
+// startup, and a restart invalidates every outstanding token.
+pub(crate) struct JwtSessionIssuer {
+    signing_key: SigningKey,
+    verifying_key: VerifyingKey,
+    rng: Arc<EntropySource>,
+}
+
+impl JwtSessionIssuer {
+    // `signing_key`: from the secret source, so tokens survive restarts; else a fresh one.
+    pub(crate) fn new(rng: Arc<EntropySource>, signing_key: Option<SigningKey>) -> Self {
+        let signing_key = signing_key.unwrap_or_else(|| {
+            rng.signing_key()
+                .expect("entropy source unavailable at startup")
+        });
+        JwtSessionIssuer {
+            verifying_key: signing_key.verifying_key(),
+            signing_key,
+            rng,
+        }
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn b64_json(value: &Value) -> String {
+    URL_SAFE_NO_PAD.encode(value.to_string())
+}
+
+fn decode_b64_json(part: &str) -> Option<Value> {
+    let bytes = URL_SAFE_NO_PAD.decode(part).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+impl SessionIssuer for JwtSessionIssuer {
+    fn issue(
+        &self,
+        session: &SessionClaims,
+        ttl: Duration,
+    ) -> Result<(String, Instant), RngUnavailable> {
+        let now = unix_now();
+        let header = json!({ "alg": "EdDSA", "typ": "JWT" });
+        let payload = json!({
+            "jti": random_token(&self.rng, 16)?,
+            "sub": session.credential_id,
+            "iat": now,
+            "exp": now + ttl.as_secs(),
+            "cnf": {
+                "jwk": {
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "x": URL_SAFE_NO_PAD.encode(session.public_key.as_bytes()),
+                }
+            },
+            "csrf": session.csrf_token,
+            // space-separated, as in OAuth 2.0
+            "scope": session.scopes.join(" "),
+        });
+
+        let signing_input = format!("{}.{}", b64_json(&header), b64_json(&payload));
+        let signature = self.signing_key.sign(signing_input.as_bytes());
+        let token = format!(
+            "{signing_input}.{}",
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        );
+
+        Ok((token, Instant::now() + ttl))
+    }
+
+    fn validate(&self, token: &str) -> Option<SessionClaims> {
+        let (signing_input, sig_b64) = token.rsplit_once('.')?;
+        let (header_b64, payload_b64) = signing_input.split_once('.')?;
+
+        let header = decode_b64_json(header_b64)?;
+        if header.get("alg")?.as_str()? != "EdDSA" {
+            return None;
+        }
+
+        let sig_bytes = URL_SAFE_NO_PAD.decode(sig_b64).ok()?;
+        let signature = Signature::from_slice(&sig_bytes).ok()?;
+        self.verifying_key
+            .verify(signing_input.as_bytes(), &signature)
+            .ok()?;
+
+        let payload = decode_b64_json(payload_b64)?;
+        if payload.get("exp")?.as_u64()? <= unix_now() {
+            return None;
+        }
+
+        let x = payload.pointer("/cnf/jwk/x")?.as_str()?;
+        let key_bytes: [u8; 32] = URL_SAFE_NO_PAD.decode(x).ok()?.try_into().ok()?;
+        let public_key = VerifyingKey::from_bytes(&key_bytes).ok()?;
+
+        Some(SessionClaims {
+            public_key,
+            credential_id: payload.get("sub")?.as_str()?.to_string(),
+            csrf_token: payload
+                .get("csrf")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            scopes: payload
+                .get("scope")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+}
+
+pub(crate) fn issuer_from_env(
+    sessions: Arc<DashMap<String, SessionRecord>>,
+    rng: Arc<EntropySource>,
+    eviction: SessionEviction,
+    jwt_key: Option<SigningKey>,
+) -> Arc<dyn SessionIssuer> {
+    let format = std::env::var(SESSION_FORMAT_ENV).unwrap_or_else(|_| "opaque".into());
+    match format.trim() {
+        "opaque" => Arc::new(OpaqueSessionIssuer::new(sessions, rng, eviction)),
+        "jwt" => Arc::new(JwtSessionIssuer::new(rng, jwt_key)),
+        other => panic!("{SESSION_FORMAT_ENV}: unsupported session format {other:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    fn claims() -> SessionClaims {
+        SessionClaims {
+            public_key: SigningKey::generate(&mut OsRng).verifying_key(),
+            credential_id: "credential".into(),
+            csrf_token: None,
+            scopes: vec![PREFERENCES_WRITE.into()],
+        }
+    }
+
+    fn issuers() -> [Box<dyn SessionIssuer>; 2] {
+        let rng = Arc::new(EntropySource::Os);
+        [
+            Box::new(OpaqueSessionIssuer::new(
+                Arc::new(DashMap::new()),
+                rng.clone(),
+                SessionEviction::Reject,
+            )),
+            Box::new(JwtSessionIssuer::new(rng, None)),
+        ]
+    }
+
+    fn same(a: &SessionClaims, b: &SessionClaims) -> bool {
+        a.public_key == b.public_key
+            && a.credential_id == b.credential_id
+            && a.csrf_token == b.csrf_token
+            && a.scopes == b.scopes
+    }
+
+    #[test]
+    fn every_issuer_hands_back_the_claims_it_issued() {
+        let mut session = claims();
+        session.csrf_token = Some("csrf".into());
+        session.scopes = SESSION_SCOPES.iter().map(|s| s.to_string()).collect();
+        for issuer in issuers() {
+            let (token, expires_at) = issuer.issue(&session, Duration::from_secs(60)).unwrap();
+            assert!(expires_at > Instant::now());
+            assert!(same(&issuer.validate(&token).unwrap(), &session));
+            assert!(issuer.validate("unknown").is_none());
+        }
+    }
+
+    #[test]
+    fn a_jwt_is_only_accepted_as_signed_and_before_exp() {
+        let issuer = JwtSessionIssuer::new(Arc::new(EntropySource::Os), None);
+        let (token, _) = issuer.issue(&claims(), Duration::from_secs(60)).unwrap();
+        let parts: Vec<&str> = token.split('.').collect();
+
+        let mut payload = decode_b64_json(parts[1]).unwrap();
+        payload["sub"] = "someone-else".into();
+        let forged = format!("{}.{}.{}", parts[0], b64_json(&payload), parts[2]);
+        assert!(issuer.validate(&forged).is_none());
+
+        let unsigned = format!("{}.{}.", b64_json(&json!({ "alg": "none" })), parts[1]);
+        assert!(issuer.validate(&unsigned).is_none());
+
+        let other = JwtSessionIssuer::new(Arc::new(EntropySource::Os), None);
+        assert!(other.validate(&token).is_none());
+
+        let (expired, _) = issuer.issue(&claims(), Duration::ZERO).unwrap();
+        assert!(issuer.validate(&expired).is_none());
+    }
+
+    #[test]
+    fn revoking_a_credential_ends_its_opaque_sessions() {
+        let issuer = OpaqueSessionIssuer::new(
+            Arc::new(DashMap::new()),
+            Arc::new(EntropySource::Os),
+            SessionEviction::Reject,
+        );
+        let mut other = claims();
+        other.credential_id = "other".into();
+        let (a, _) = issuer.issue(&claims(), Duration::from_secs(60)).unwrap();
+        let (b, _) = issuer.issue(&claims(), Duration::from_secs(60)).unwrap();
+        let (kept, _) = issuer.issue(&other, Duration::from_secs(60)).unwrap();
+
+        assert_eq!(issuer.revoke_credential("credential"), 2);
+        assert!(issuer.validate(&a).is_none());
+        assert!(issuer.validate(&b).is_none());
+        assert!(issuer.validate(&kept).is_some());
+    }
+
+    #[test]
+    fn validating_an_expired_session_removes_it() {
+        let sessions = Arc::new(DashMap::new());
+        let issuer = OpaqueSessionIssuer::new(
+            sessions.clone(),
+            Arc::new(EntropySource::Os),
+            SessionEviction::Lru,
+        );
+        let (live, _) = issuer.issue(&claims(), Duration::from_secs(60)).unwrap();
+        let (stale, _) = issuer.issue(&claims(), Duration::ZERO).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+
+        assert!(issuer.validate(&stale).is_none());
+        assert!(!sessions.contains_key(&stale));
+        assert!(issuer.validate(&live).is_some());
+        // the stale token left the LRU index too, so only the live one can be evicted
+        assert_eq!(issuer.lru.as_ref().unwrap().lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,432 @@
+// Admin snapshot of the in-memory state: every live verification token, credential and
+// stored session as JSON, and the reverse, for reproducing a reported bug or moving
+// between store backends. Expiry travels as time remaining, since an Instant means
+// nothing outside this process. Private keys are never held, but verification and
+// session tokens are bearer secrets, so a snapshot must be handled like one.
+//
+// Not included: code attempts, pending one-time codes, PoW challenges and the replay
+// cache. They are short-lived and rebuild themselves.
+
+use crate::{
+    auth::key_id,
+    config::{SESSION_TTL, TEMP_CREDENTIAL_TTL, VERIFICATION_TTL},
+    error::ApiError,
+    parse::decode_public_key,
+    session::{SessionClaims, SessionRecord},
+    state::AppState,
+    store::{Deadline, TemporaryCredentialRecord, VerificationTokenRecord},
+};
+use axum::http::StatusCode;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Snapshot {
+    pub version: u32,
+    pub verification_tokens: Vec<TokenEntry>,
+    pub temporary_credentials: Vec<CredentialEntry>,
+    // empty with JWT sessions, which are not stored
+    pub sessions: Vec<SessionEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenEntry {
+    pub token: String,
+    pub username: String,
+    pub scopes: Option<Vec<String>>,
+    pub expires_in_ms: u64,
+    // time since verify_user, for POC_FLOW_DEADLINE_SECS
+    #[serde(default)]
+    pub flow_elapsed_ms: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CredentialEntry {
+    pub credential_id: String,
+    // base64url, 32 bytes
+    pub public_key: String,
+    pub scopes: Option<Vec<String>>,
+    pub verification_token: String,
+    pub label: Option<String>,
+    pub username: String,
+    pub issued_at: u64,
+    pub uses: u32,
+    pub expires_in_ms: u64,
+    #[serde(default)]
+    pub flow_elapsed_ms: u64,
+    // delegated credentials: the delegating credential's id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_credential_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionEntry {
+    pub token: String,
+    pub public_key: String,
+    pub credential_id: String,
+    pub csrf_token: Option<String>,
+    pub scopes: Vec<String>,
+    pub expires_in_ms: u64,
+}
+
+#[derive(Serialize, Default)]
+pub struct ImportSummary {
+    pub verification_tokens: usize,
+    pub temporary_credentials: usize,
+    pub sessions: usize,
+    // already expired in the snapshot, or sessions the issuer doesn't store (JWT)
+    pub skipped: usize,
+}
+
+fn remaining_ms(expires_at: impl Into<Instant>, now: Instant) -> u64 {
+    expires_at.into().saturating_duration_since(now).as_millis() as u64
+}
+
+fn elapsed_ms(since: Instant, now: Instant) -> u64 {
+    now.saturating_duration_since(since).as_millis() as u64
+}
+
+// Older than this process can represent: treat the flow as starting now.
+fn started(elapsed_ms: u64, now: Instant) -> Instant {
+    now.checked_sub(Duration::from_millis(elapsed_ms))
+        .unwrap_or(now)
+}
+
+pub fn export(state: &AppState, now: Instant) -> Snapshot {
+    let verification_tokens = state
+        .verification_tokens
+        .iter()
+        .filter(|t| t.expires_at.instant() > now)
+        .map(|t| TokenEntry {
+            token: t.key().clone(),
+            username: t.username.clone(),
+            scopes: t.scopes.clone(),
+            expires_in_ms: remaining_ms(t.expires_at, now),
+            flow_elapsed_ms: elapsed_ms(t.flow_started, now),
+        })
+        .collect();
+    let temporary_credentials = state
+        .temporary_credentials
+        .iter()
+        .filter(|c| c.expires_at.instant() > now)
+        .map(|c| CredentialEntry {
+            credential_id: c.key().clone(),
+            public_key: URL_SAFE_NO_PAD.encode(c.public_key.as_bytes()),
+            scopes: c.scopes.clone(),
+            verification_token: c.verification_token.clone(),
+            label: c.label.clone(),
+            username: c.username.clone(),
+            issued_at: c.issued_at,
+            uses: c.uses,
+            expires_in_ms: remaining_ms(c.expires_at, now),
+            flow_elapsed_ms: elapsed_ms(c.flow_started, now),
+            parent_credential_id: c.parent.clone(),
+        })
+        .collect();
+    let sessions = state
+        .sessions
+        .iter()
+        .filter(|s| s.expires_at > now)
+        .map(|s| SessionEntry {
+            token: s.key().clone(),
+            public_key: URL_SAFE_NO_PAD.encode(s.claims.public_key.as_bytes()),
+            credential_id: s.claims.credential_id.clone(),
+            csrf_token: s.claims.csrf_token.clone(),
+            scopes: s.claims.scopes.clone(),
+            expires_in_ms: remaining_ms(s.expires_at, now),
+        })
+        .collect();
+
+    Snapshot {
+        version: SNAPSHOT_VERSION,
+        verification_tokens,
+        temporary_credentials,
+        sessions,
+    }
+}
+
+// All or nothing: every entry is checked (and the caps) before the first insert.
+// Entries with the same key as existing ones replace them.
+pub fn import(
+    state: &AppState,
+    snapshot: Snapshot,
+    now: Instant,
+) -> Result<ImportSummary, ApiError> {
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "snapshot_version_unsupported",
+        ));
+    }
+    let invalid = || ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "snapshot_invalid");
+
+    let credential_keys = snapshot
+        .temporary_credentials
+        .iter()
+        .map(|c| decode_public_key(&c.public_key).ok_or_else(invalid))
+        .collect::<Result<Vec<_>, _>>()?;
+    let session_keys = snapshot
+        .sessions
+        .iter()
+        .map(|s| decode_public_key(&s.public_key).ok_or_else(invalid))
+        .collect::<Result<Vec<_>, _>>()?;
+    if snapshot
+        .verification_tokens
+        .iter()
+        .any(|t| t.token.is_empty())
+        || snapshot
+            .temporary_credentials
+            .iter()
+            .any(|c| c.credential_id.is_empty())
+        || snapshot.sessions.iter().any(|s| s.token.is_empty())
+    {
+        return Err(invalid());
+    }
+    // no entry may come back with more time than its kind is ever issued with
+    let outlives = |ms: u64, ttl: Duration| Duration::from_millis(ms) > ttl;
+    if snapshot
+        .verification_tokens
+        .iter()
+        .any(|t| outlives(t.expires_in_ms, VERIFICATION_TTL))
+        || snapshot
+            .temporary_credentials
+            .iter()
+            .any(|c| outlives(c.expires_in_ms, TEMP_CREDENTIAL_TTL))
+        || snapshot
+            .sessions
+            .iter()
+            .any(|s| outlives(s.expires_in_ms, SESSION_TTL))
+    {
+        return Err(invalid());
+    }
+
+    let config = &state.config;
+    if state.verification_tokens.len() + snapshot.verification_tokens.len()
+        > config.max_verification_tokens
+        || state.temporary_credentials.len() + snapshot.temporary_credentials.len()
+            > config.max_temporary_credentials
+        || state.sessions.len() + snapshot.sessions.len() > config.max_sessions
+    {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "capacity_reached",
+        ));
+    }
+
+    let expiry = |ms: u64| (ms > 0).then(|| now + Duration::from_millis(ms));
+    let mut summary = ImportSummary::default();
+
+    for entry in snapshot.verification_tokens {
+        let Some(expires_at) = expiry(entry.expires_in_ms) else {
+            summary.skipped += 1;
+            continue;
+        };
+        state.verification_tokens.insert(
+            entry.token,
+            VerificationTokenRecord {
+                expires_at: Deadline::at(expires_at),
+                username: entry.username,
+                scopes: entry.scopes,
+                flow_started: started(entry.flow_elapsed_ms, now),
+            },
+        );
+        summary.verification_tokens += 1;
+    }
+
+    for (entry, public_key) in snapshot
+        .temporary_credentials
+        .into_iter()
+        .zip(credential_keys)
+    {
+        let Some(expires_at) = expiry(entry.expires_in_ms) else {
+            summary.skipped += 1;
+            continue;
+        };
+        state.temporary_credentials.insert(
+            entry.credential_id,
+            TemporaryCredentialRecord {
+                kid: key_id(&public_key),
+                public_key,
+                expires_at: Deadline::at(expires_at),
+                scopes: entry.scopes,
+                verification_token: entry.verification_token,
+                label: entry.label,
+                username: entry.username,
+                flow_started: started(entry.flow_elapsed_ms, now),
+                issued_at: entry.issued_at,
+                uses: entry.uses,
+                parent: entry.parent_credential_id,
+            },
+        );
+        summary.temporary_credentials += 1;
+    }
+
+    for (entry, public_key) in snapshot.sessions.into_iter().zip(session_keys) {
+        let Some(expires_at) = expiry(entry.expires_in_ms) else {
+            summary.skipped += 1;
+            continue;
+        };
+        let record = SessionRecord {
+            claims: SessionClaims {
+                public_key,
+                credential_id: entry.credential_id,
+                csrf_token: entry.csrf_token,
+                scopes: entry.scopes,
+            },
+            expires_at,
+            last_access: now,
+        };
+        if state.session_issuer.restore(entry.token, record) {
+            summary.sessions += 1;
+        } else {
+            summary.skipped += 1;
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        store::Deadline,
+        testing::{self, ADMIN_TOKEN, credential, post_json, send, session, with_bearer},
+    };
+    use axum::{extract::Request, http::StatusCode};
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+    use serde_json::{Value, json};
+    use std::time::Instant;
+
+    fn admin_post(path: &str, body: &Value) -> Request {
+        with_bearer(post_json(path, body), ADMIN_TOKEN)
+    }
+
+    #[tokio::test]
+    async fn an_export_imports_into_a_fresh_state() {
+        let source = testing::state_with(testing::admin_config());
+        let token = testing::verification(&source);
+        let key = SigningKey::generate(&mut OsRng);
+        let id = credential(&source, &key);
+        source.temporary_credentials.get_mut(&id).unwrap().label = Some("laptop".into());
+        let stale = credential(&source, &key);
+        source
+            .temporary_credentials
+            .get_mut(&stale)
+            .unwrap()
+            .expires_at = Deadline::at(Instant::now());
+        let bearer = session(&source, &key, &id);
+
+        let req = admin_post("/api/admin/state/export", &json!({}));
+        let (status, _, body) = send(&source, req).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "confirmation_required");
+
+        let req = admin_post("/api/admin/state/export", &json!({ "confirm": true }));
+        let (status, _, snapshot) = send(&source, req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            snapshot["temporary_credentials"].as_array().unwrap().len(),
+            1
+        );
+
+        let target = testing::state_with(testing::admin_config());
+        let req = admin_post(
+            "/api/admin/state/import",
+            &json!({ "confirm": true, "snapshot": snapshot }),
+        );
+        let (status, _, body) = send(&target, req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({
+                "verification_tokens": 1,
+                "temporary_credentials": 1,
+                "sessions": 1,
+                "skipped": 0,
+            })
+        );
+
+        assert_eq!(
+            target.verification_tokens.get(&token).unwrap().username,
+            "alice"
+        );
+        let cred = target.temporary_credentials.get(&id).unwrap().clone();
+        assert_eq!(cred.public_key, key.verifying_key());
+        assert_eq!(cred.label.as_deref(), Some("laptop"));
+        assert!(!target.temporary_credentials.contains_key(&stale));
+        let claims = target.session_issuer.validate(&bearer).unwrap();
+        assert_eq!(claims.credential_id, id);
+
+        // and back out unchanged, give or take the time that passed
+        let req = admin_post("/api/admin/state/export", &json!({ "confirm": true }));
+        let (_, _, again) = send(&target, req).await;
+        assert_eq!(
+            again["temporary_credentials"][0]["public_key"],
+            snapshot["temporary_credentials"][0]["public_key"]
+        );
+        assert_eq!(again["sessions"][0]["token"], bearer.as_str());
+    }
+
+    #[tokio::test]
+    async fn a_bad_snapshot_imports_nothing() {
+        let state = testing::state_with(testing::admin_config());
+        let mut snapshot = json!({
+            "version": 1,
+            "verification_tokens": [{
+                "token": "verification",
+                "username": "alice",
+                "scopes": null,
+                "expires_in_ms": 60_000,
+            }],
+            "temporary_credentials": [{
+                "credential_id": "credential",
+                "public_key": "not-a-key",
+                "scopes": null,
+                "verification_token": "verification",
+                "label": null,
+                "username": "alice",
+                "issued_at": 0,
+                "uses": 0,
+                "expires_in_ms": 60_000,
+            }],
+            "sessions": [],
+        });
+        let req = admin_post(
+            "/api/admin/state/import",
+            &json!({ "confirm": true, "snapshot": snapshot }),
+        );
+        let (status, _, body) = send(&state, req).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "snapshot_invalid");
+        assert!(state.verification_tokens.is_empty());
+
+        snapshot["version"] = 2.into();
+        let req = admin_post(
+            "/api/admin/state/import",
+            &json!({ "confirm": true, "snapshot": snapshot }),
+        );
+        let (_, _, body) = send(&state, req).await;
+        assert_eq!(body["error"], "snapshot_version_unsupported");
+
+        // keys that decode, but a token with more time left than it was ever issued
+        snapshot["version"] = 1.into();
+        snapshot["temporary_credentials"] = json!([]);
+        snapshot["verification_tokens"][0]["expires_in_ms"] = 300_001.into();
+        let req = admin_post(
+            "/api/admin/state/import",
+            &json!({ "confirm": true, "snapshot": snapshot }),
+        );
+        let (status, _, body) = send(&state, req).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "snapshot_invalid");
+        assert!(state.verification_tokens.is_empty());
+    }
+}
@@ -0,0 +1,349 @@
+// Shared state handed to every handler, and how it is built at startup.
+
+use crate::{
+    auth::{self, AuthBackend, PendingCodeRecord},
+    bodysig,
+    config::{
+        Config, HARCODED_CODE, SIGNATURE_CACHE_SIZE_ENV, TEST_RNG_SEED_ENV, VERIFY_CACHE_SECS_ENV,
+    },
+    error::{json_error, set_pretty_json},
+    events::{self, EventBus},
+    notifier::{self, CodeNotifier},
+    ratelimit, secrets,
+    session::{self, SessionIssuer, SessionRecord},
+    store::{
+        SignatureAttemptRecord, SignatureReplayCache, TemporaryCredentialRecord,
+        VerificationTokenRecord, VerifiedCache,
+    },
+};
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use dashmap::DashMap;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+#[cfg(any(test, feature = "test-rng"))]
+use rand::SeedableRng;
+use rand::{RngCore, rngs::OsRng};
+#[cfg(any(test, feature = "test-rng"))]
+use rand_chacha::ChaCha20Rng;
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+use tokio::sync::Semaphore;
+
+#[derive(Clone)]
+pub(crate) struct AppState {
+    pub(crate) verification_tokens: Arc<DashMap<String, VerificationTokenRecord>>,
+    pub(crate) temporary_credentials: Arc<DashMap<String, TemporaryCredentialRecord>>,
+    pub(crate) sessions: Arc<DashMap<String, SessionRecord>>,
+    pub(crate) signature_attempts: Arc<DashMap<String, SignatureAttemptRecord>>,
+    // one_time_code mode: username -> code sent out of band
+    pub(crate) pending_codes: Arc<DashMap<String, PendingCodeRecord>>,
+    // POC_POW: outstanding challenge -> expiry; each is consumed by one verify attempt
+    pub(crate) pow_challenges: Arc<DashMap<String, Instant>>,
+    pub(crate) rng: Arc<EntropySource>,
+    pub(crate) config: Arc<Config>,
+    pub(crate) auth: Arc<dyn AuthBackend>,
+    pub(crate) session_issuer: Arc<dyn SessionIssuer>,
+    pub(crate) notifier: Arc<dyn CodeNotifier>,
+    // throwaway key for constant-work verification of unknown credentials
+    pub(crate) decoy_key: VerifyingKey,
+    // set on shutdown: no new codes, credentials or sessions; existing sessions keep working
+    pub(crate) draining: Arc<AtomicBool>,
+    // set once the startup self-check passed; GET /api/ready reports it
+    pub(crate) ready: Arc<AtomicBool>,
+    pub(crate) seen_signatures: Arc<Mutex<SignatureReplayCache>>,
+    // POC_VERIFY_CACHE_SECS; None when off or when the replay cache is on
+    pub(crate) verified: Option<Arc<Mutex<VerifiedCache>>>,
+    pub(crate) reaped: Arc<ReapedCounts>,
+    // when has_capacity last swept a full map; None until the first time
+    pub(crate) capacity_swept: Arc<Mutex<Option<Instant>>>,
+    pub(crate) events: EventBus,
+    // one permit per verification in flight on the blocking pool (Config::verify_workers)
+    pub(crate) verify_permits: Arc<Semaphore>,
+    // POC_RATE_LIMIT_PER_SEC; None when off
+    pub(crate) rate_limiter: Option<Arc<ratelimit::RateLimiter>>,
+    // one per request in flight (POC_MAX_CONCURRENT_REQUESTS); None when unlimited
+    pub(crate) request_permits: Option<Arc<Semaphore>>,
+    // POC_SIGN_RESPONSES or POC_SESSION_RECEIPTS; its key is listed by GET /api/jwks
+    pub(crate) response_signer: Option<Arc<bodysig::ResponseSigner>>,
+}
+
+// Entries removed by sweep_expired since startup, for /api/admin/stats.
+#[derive(Default)]
+pub(crate) struct ReapedCounts {
+    pub(crate) verification_tokens: AtomicU64,
+    pub(crate) temporary_credentials: AtomicU64,
+    pub(crate) sessions: AtomicU64,
+}
+
+// OsRng in production; a seeded ChaCha20 only for reproducible tests. The seeded
+// variant exists only in test builds and with the `test-rng` feature, so a release
+// build can't be made deterministic by an environment variable.
+pub(crate) enum EntropySource {
+    Os,
+    #[cfg(any(test, feature = "test-rng"))]
+    Seeded(Box<Mutex<ChaCha20Rng>>),
+}
+
+impl EntropySource {
+    pub(crate) fn from_env() -> Self {
+        match std::env::var(TEST_RNG_SEED_ENV) {
+            Ok(raw) => Self::seeded_from(&raw),
+            Err(_) => EntropySource::Os,
+        }
+    }
+
+    #[cfg(any(test, feature = "test-rng"))]
+    fn seeded_from(raw: &str) -> Self {
+        let seed: u64 = raw
+            .trim()
+            .parse()
+            .unwrap_or_else(|_| panic!("{TEST_RNG_SEED_ENV} must be a u64"));
+        println!("WARNING: {TEST_RNG_SEED_ENV} is set, credentials are deterministic");
+        Self::seeded(seed)
+    }
+
+    // set by mistake on a normal build: refuse rather than quietly use OsRng
+    #[cfg(not(any(test, feature = "test-rng")))]
+    fn seeded_from(_raw: &str) -> Self {
+        panic!("{TEST_RNG_SEED_ENV} needs a build with the test-rng feature")
+    }
+
+    #[cfg(any(test, feature = "test-rng"))]
+    pub(crate) fn seeded(seed: u64) -> Self {
+        EntropySource::Seeded(Box::new(Mutex::new(ChaCha20Rng::seed_from_u64(seed))))
+    }
+
+    pub(crate) fn is_seeded(&self) -> bool {
+        match self {
+            EntropySource::Os => false,
+            #[cfg(any(test, feature = "test-rng"))]
+            EntropySource::Seeded(_) => true,
+        }
+    }
+
+    // All entropy goes through here. OsRng can fail on some platforms; callers get
+    // RngUnavailable (500 rng_unavailable) instead of a panic, and nothing is issued.
+    fn fill_bytes(&self, buf: &mut [u8]) -> Result<(), RngUnavailable> {
+        let result = match self {
+            EntropySource::Os => OsRng.try_fill_bytes(buf),
+            #[cfg(any(test, feature = "test-rng"))]
+            EntropySource::Seeded(rng) => {
+                rng.lock().expect("rng mutex poisoned").try_fill_bytes(buf)
+            }
+        };
+        result.map_err(|e| {
+            println!("ERROR: entropy source failed: {e}");
+            RngUnavailable
+        })
+    }
+
+    // Same as SigningKey::generate: the secret key is just 32 random bytes.
+    pub(crate) fn signing_key(&self) -> Result<SigningKey, RngUnavailable> {
+        let mut seed = [0u8; 32];
+        self.fill_bytes(&mut seed)?;
+        Ok(SigningKey::from_bytes(&seed))
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RngUnavailable;
+
+impl IntoResponse for RngUnavailable {
+    fn into_response(self) -> Response {
+        json_error(StatusCode::INTERNAL_SERVER_ERROR, "rng_unavailable")
+    }
+}
+
+pub(crate) fn random_token(rng: &EntropySource, bytes: usize) -> Result<String, RngUnavailable> {
+    let mut buf = vec![0u8; bytes];
+    rng.fill_bytes(&mut buf)?;
+    Ok(URL_SAFE_NO_PAD.encode(buf))
+}
+
+// Uniform 6-digit code; rejection sampling avoids modulo bias.
+pub(crate) fn random_code(rng: &EntropySource) -> Result<String, RngUnavailable> {
+    const LIMIT: u32 = u32::MAX - u32::MAX % 1_000_000;
+    loop {
+        let mut buf = [0u8; 4];
+        rng.fill_bytes(&mut buf)?;
+        let n = u32::from_le_bytes(buf);
+        if n < LIMIT {
+            return Ok(format!("{:06}", n % 1_000_000));
+        }
+    }
+}
+
+pub(crate) fn is_draining(state: &AppState) -> bool {
+    state.draining.load(Ordering::Relaxed)
+}
+
+// An Ed25519 key stored as a base64url 32-byte seed; None when the secret is unset.
+async fn load_signing_key(source: &dyn secrets::SecretSource, name: &str) -> Option<SigningKey> {
+    secrets::load(source, name).await.map(|seed| {
+        URL_SAFE_NO_PAD
+            .decode(seed.trim())
+            .ok()
+            .and_then(|b| <[u8; 32]>::try_from(b).ok())
+            .map(|seed| SigningKey::from_bytes(&seed))
+            .unwrap_or_else(|| panic!("{name} must be a base64url 32-byte seed"))
+    })
+}
+
+// Secrets, config and every map the handlers share. Panics on invalid configuration.
+pub(crate) async fn build_state() -> AppState {
+    let rng = Arc::new(EntropySource::from_env());
+    let secret_source = secrets::source_from_env();
+    let mut config = Config::from_env();
+    config.admin_token = secrets::load(&*secret_source, "admin_token").await;
+    let jwt_key = load_signing_key(&*secret_source, "jwt_signing_key").await;
+    let response_signer = if config.sign_responses || config.session_receipts {
+        let key = match load_signing_key(&*secret_source, "response_signing_key").await {
+            Some(key) => key,
+            None => rng
+                .signing_key()
+                .expect("entropy source unavailable at startup"),
+        };
+        Some(Arc::new(bodysig::ResponseSigner::new(key)))
+    } else {
+        None
+    };
+    set_pretty_json(config.pretty_json);
+    state_from(config, rng, jwt_key, response_signer)
+}
+
+// A hit skips Ed25519 but not the replay check, and with the replay cache on a hit is
+// always a signature it already accepted, so it is refused anyway: the cache would only
+// cost memory. It is built only with the replay cache off (POC_SIGNATURE_CACHE_SIZE=0).
+fn verification_cache(config: &Config) -> Option<Arc<Mutex<VerifiedCache>>> {
+    if config.verify_cache_ttl.is_zero() {
+        return None;
+    }
+    if config.signature_cache_size > 0 {
+        println!(
+            "WARNING: {VERIFY_CACHE_SECS_ENV} is ignored while the replay cache is on \
+             ({SIGNATURE_CACHE_SIZE_ENV}=0 turns it off)"
+        );
+        return None;
+    }
+    Some(Arc::new(Mutex::new(VerifiedCache::new(
+        config.verify_cache_ttl,
+        config.verify_cache_size,
+    ))))
+}
+
+// The maps and services for `config`; everything build_state does after loading secrets.
+pub(crate) fn state_from(
+    config: Config,
+    rng: Arc<EntropySource>,
+    jwt_key: Option<SigningKey>,
+    response_signer: Option<Arc<bodysig::ResponseSigner>>,
+) -> AppState {
+    let pending_codes = Arc::new(DashMap::new());
+    let sessions = Arc::new(DashMap::new());
+    let config = Arc::new(config);
+    let decoy_key = rng
+        .signing_key()
+        .expect("entropy source unavailable at startup")
+        .verifying_key();
+
+    AppState {
+        verification_tokens: Arc::new(DashMap::new()),
+        temporary_credentials: Arc::new(DashMap::new()),
+        sessions: sessions.clone(),
+        signature_attempts: Arc::new(DashMap::new()),
+        pending_codes: pending_codes.clone(),
+        pow_challenges: Arc::new(DashMap::new()),
+        rng: rng.clone(),
+        config: config.clone(),
+        auth: auth::backend_from_env(HARCODED_CODE, pending_codes),
+        notifier: notifier::notifier_from_env(),
+        session_issuer: session::issuer_from_env(sessions, rng, config.session_eviction, jwt_key),
+        decoy_key,
+        draining: Arc::new(AtomicBool::new(false)),
+        ready: Arc::new(AtomicBool::new(false)),
+        verified: verification_cache(&config),
+        seen_signatures: Arc::new(Mutex::new(SignatureReplayCache::new(
+            config.signature_cache_size,
+        ))),
+        reaped: Arc::new(ReapedCounts::default()),
+        capacity_swept: Arc::new(Mutex::new(None)),
+        events: EventBus::new(events::EVENT_BUS_CAPACITY),
+        verify_permits: Arc::new(Semaphore::new(config.verify_workers.max(1))),
+        rate_limiter: ratelimit::RateLimiter::from_env(),
+        request_permits: match config.max_concurrent_requests {
+            0 => None,
+            n => Some(Arc::new(Semaphore::new(n))),
+        },
+        response_signer,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn seeded_sources_repeat_and_os_does_not() {
+        let a = EntropySource::seeded(7);
+        let b = EntropySource::seeded(7);
+        assert_eq!(random_token(&a, 32).unwrap(), random_token(&b, 32).unwrap());
+        assert_eq!(
+            a.signing_key().unwrap().to_bytes(),
+            b.signing_key().unwrap().to_bytes()
+        );
+
+        let os = EntropySource::Os;
+        assert_ne!(
+            random_token(&os, 32).unwrap(),
+            random_token(&os, 32).unwrap()
+        );
+    }
+
+    #[test]
+    fn the_decoy_key_comes_from_the_entropy_source() {
+        let state = |seed| {
+            let rng = Arc::new(EntropySource::seeded(seed));
+            state_from(crate::testing::config(), rng, None, None)
+        };
+        assert_eq!(state(5).decoy_key, state(5).decoy_key);
+        assert_ne!(state(5).decoy_key, state(6).decoy_key);
+    }
+
+    #[test]
+    fn random_codes_are_six_digits() {
+        let rng = EntropySource::seeded(1);
+        for _ in 0..1000 {
+            let code = random_code(&rng).unwrap();
+            assert_eq!(code.len(), 6);
+            assert!(code.bytes().all(|b| b.is_ascii_digit()));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn random_tokens_decode_to_the_requested_length(n in 0usize..=256, seed: u64) {
+            for rng in [EntropySource::seeded(seed), EntropySource::Os] {
+                let token = random_token(&rng, n).unwrap();
+                prop_assert_eq!(URL_SAFE_NO_PAD.decode(&token).unwrap().len(), n);
+            }
+        }
+    }
+
+    #[test]
+    fn random_tokens_do_not_repeat() {
+        let rng = EntropySource::Os;
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..100_000 {
+            assert!(seen.insert(random_token(&rng, 16).unwrap()));
+        }
+    }
+}
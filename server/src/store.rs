@@ -0,0 +1,649 @@
+// What the in-memory maps hold, how their entries expire and the background sweep.
+//
+// A DashMap guard locks its whole shard. Handlers copy what they need out of a map
+// (get_if_live) and never hold a guard across an `.await` or Ed25519 work; a write guard
+// is only taken for the in-place update itself.
+
+use crate::{
+    auth::{PendingCodeRecord, SignedData},
+    config::{PENDING_CODE_TTL, SIGNATURE_LOCK_MAX, TEMP_CREDENTIAL_TTL, VERIFICATION_TTL},
+    events::Event,
+    state::AppState,
+};
+use dashmap::DashMap;
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashSet, VecDeque},
+    marker::PhantomData,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Clone)]
+pub(crate) struct VerificationTokenRecord {
+    pub(crate) expires_at: Deadline<Self>,
+    // as given at step 1; groups the credentials issued from it
+    pub(crate) username: String,
+    // from the auth backend's Grant; None = SESSION_SCOPES
+    pub(crate) scopes: Option<Vec<String>>,
+    // when verify_user succeeded; see Config::flow_deadline
+    pub(crate) flow_started: Instant,
+}
+
+#[derive(Clone)]
+pub(crate) struct TemporaryCredentialRecord {
+    pub(crate) public_key: VerifyingKey,
+    // key_id(public_key), kept in sync on rotation
+    pub(crate) kid: String,
+    pub(crate) expires_at: Deadline<Self>,
+    // copied from the verification token; becomes the session's scopes
+    pub(crate) scopes: Option<Vec<String>>,
+    // the step 1 verification token it was issued for, so revoking that token cascades
+    pub(crate) verification_token: String,
+    // client-chosen display name, already validated
+    pub(crate) label: Option<String>,
+    // from the verification token, for GET /api/step2/credentials
+    pub(crate) username: String,
+    // copied from the verification token
+    pub(crate) flow_started: Instant,
+    // unix seconds
+    pub(crate) issued_at: u64,
+    // sessions entered with it
+    pub(crate) uses: u32,
+    // the credential that delegated this one (POST /api/step2/delegate); revoking the
+    // parent revokes it too, and it never outlives the parent
+    pub(crate) parent: Option<String>,
+}
+
+#[derive(Clone)]
+pub(crate) struct SignatureAttemptRecord {
+    pub(crate) failures: u32,
+    pub(crate) last_failure: Instant,
+    pub(crate) locked_until: Option<Instant>,
+}
+
+// Signatures accepted by step 3, oldest first. A signature is forgotten after
+// TEMP_CREDENTIAL_TTL (its credential has expired by then) or when `cap` newer ones
+// push it out; after that the same bytes would be accepted again.
+pub(crate) struct SignatureReplayCache {
+    pub(crate) cap: usize,
+    seen: HashSet<[u8; 64]>,
+    order: VecDeque<([u8; 64], Instant)>,
+}
+
+impl SignatureReplayCache {
+    pub(crate) fn new(cap: usize) -> Self {
+        SignatureReplayCache {
+            cap,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    // False when the signature was already seen.
+    pub(crate) fn insert(&mut self, signature: [u8; 64], now: Instant) -> bool {
+        if self.cap == 0 {
+            return true;
+        }
+        while let Some((old, at)) = self.order.front() {
+            if self.order.len() < self.cap && now.duration_since(*at) < TEMP_CREDENTIAL_TTL {
+                break;
+            }
+            self.seen.remove(old);
+            self.order.pop_front();
+        }
+        if !self.seen.insert(signature) {
+            return false;
+        }
+        self.order.push_back((signature, now));
+        true
+    }
+}
+
+// Step 3 verifications that succeeded, keyed by VerifiedCache::key, oldest first. Only
+// the Ed25519 check is skipped on a hit: the replay check still runs after it, so a
+// signature that was already used is refused exactly as without the cache. That is also
+// why it only exists with the replay cache off (state::verification_cache).
+pub(crate) struct VerifiedCache {
+    pub(crate) ttl: Duration,
+    pub(crate) cap: usize,
+    seen: HashSet<[u8; 32]>,
+    order: VecDeque<([u8; 32], Instant)>,
+}
+
+impl VerifiedCache {
+    pub(crate) fn new(ttl: Duration, cap: usize) -> Self {
+        VerifiedCache {
+            ttl,
+            cap,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    // Covers the public key, not just the credential id, so a rotated credential can't
+    // hit an entry made with its old key.
+    pub(crate) fn key(
+        public_key: &VerifyingKey,
+        data: &SignedData,
+        signature: &Signature,
+    ) -> [u8; 32] {
+        let mut hasher = Sha256::new()
+            .chain_update(public_key.as_bytes())
+            .chain_update(signature.to_bytes());
+        match data {
+            SignedData::Pure(message) => {
+                hasher.update([0u8]);
+                hasher.update(message);
+            }
+            SignedData::Prehashed { digest, context } => {
+                hasher.update([1, context.len() as u8]);
+                hasher.update(context);
+                hasher.update(digest.clone().finalize());
+            }
+        }
+        hasher.finalize().into()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((old, at)) = self.order.front() {
+            if now.duration_since(*at) < self.ttl {
+                break;
+            }
+            self.seen.remove(old);
+            self.order.pop_front();
+        }
+    }
+
+    pub(crate) fn contains(&mut self, key: &[u8; 32], now: Instant) -> bool {
+        self.expire(now);
+        self.seen.contains(key)
+    }
+
+    pub(crate) fn insert(&mut self, key: [u8; 32], now: Instant) {
+        self.expire(now);
+        while self.order.len() >= self.cap {
+            match self.order.pop_front() {
+                Some((old, _)) => self.seen.remove(&old),
+                None => return,
+            };
+        }
+        if self.seen.insert(key) {
+            self.order.push_back((key, now));
+        }
+    }
+}
+
+// When an R record expires. Records store a Deadline<Self>, and the only way to get one
+// from a TTL is `deadline::<R>()`, which uses R's own: a credential's deadline doesn't
+// type-check as a verification token's, so the wrong TTL can't reach the wrong map.
+pub(crate) struct Deadline<R> {
+    at: Instant,
+    record: PhantomData<fn() -> R>,
+}
+
+impl<R> Deadline<R> {
+    // For deadlines that don't come from R::TTL: a delegated credential capped by its
+    // parent, or one restored from a snapshot.
+    pub(crate) fn at(at: Instant) -> Self {
+        Deadline {
+            at,
+            record: PhantomData,
+        }
+    }
+
+    pub(crate) fn instant(self) -> Instant {
+        self.at
+    }
+}
+
+// not derived: those would require R itself to be Clone/Copy/Debug
+impl<R> Clone for Deadline<R> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<R> Copy for Deadline<R> {}
+
+impl<R> std::fmt::Debug for Deadline<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.at.fmt(f)
+    }
+}
+
+impl<R> From<Deadline<R>> for Instant {
+    fn from(deadline: Deadline<R>) -> Instant {
+        deadline.at
+    }
+}
+
+// A record with a fixed lifetime, R::TTL.
+pub(crate) trait Expiring {
+    const TTL: Duration;
+
+    fn expires_at(&self) -> Instant;
+}
+
+impl Expiring for VerificationTokenRecord {
+    const TTL: Duration = VERIFICATION_TTL;
+
+    fn expires_at(&self) -> Instant {
+        self.expires_at.instant()
+    }
+}
+
+impl Expiring for TemporaryCredentialRecord {
+    const TTL: Duration = TEMP_CREDENTIAL_TTL;
+
+    fn expires_at(&self) -> Instant {
+        self.expires_at.instant()
+    }
+}
+
+impl Expiring for PendingCodeRecord {
+    const TTL: Duration = PENDING_CODE_TTL;
+
+    fn expires_at(&self) -> Instant {
+        self.expires_at.instant()
+    }
+}
+
+pub(crate) fn deadline<R: Expiring>() -> Deadline<R> {
+    Deadline::at(Instant::now() + R::TTL)
+}
+
+// The record under `key` if it hasn't expired, cloned so no map guard escapes. An
+// expired record is removed on the spot, so every read path treats it as gone
+// whether or not the sweep has run yet.
+pub(crate) fn get_if_live<R: Expiring + Clone>(map: &DashMap<String, R>, key: &str) -> Option<R> {
+    get_if_live_at(map, key, Instant::now())
+}
+
+// The `*_at` forms take the clock as `now`, so tests can move it instead of sleeping.
+pub(crate) fn get_if_live_at<R: Expiring + Clone>(
+    map: &DashMap<String, R>,
+    key: &str,
+    now: Instant,
+) -> Option<R> {
+    let rec = map.get(key)?.clone();
+    if expired_at(rec.expires_at(), now) {
+        // a concurrent refresh (rotation) may have extended it meanwhile
+        map.remove_if(key, |_, r| expired_at(r.expires_at(), now));
+        return None;
+    }
+    Some(rec)
+}
+
+pub(crate) fn expired(t: impl Into<Instant>) -> bool {
+    expired_at(t, Instant::now())
+}
+
+pub(crate) fn expired_at(t: impl Into<Instant>, now: Instant) -> bool {
+    now > t.into()
+}
+
+// Whole seconds left, rounded up so a fresh 1800s deadline still reports 1800.
+pub(crate) fn remaining_secs(t: impl Into<Instant>) -> u64 {
+    remaining_secs_at(t, Instant::now())
+}
+
+pub(crate) fn remaining_secs_at(t: impl Into<Instant>, now: Instant) -> u64 {
+    let left = t.into().saturating_duration_since(now);
+    left.as_secs() + u64::from(left.subsec_nanos() > 0)
+}
+
+// Removes these credentials and every credential delegated from them, directly or
+// further down the tree, with their lockout state; with `sessions`, also the sessions
+// entered with any of them. Returns (credentials, sessions) actually removed.
+pub(crate) fn revoke_credentials(
+    state: &AppState,
+    mut pending: Vec<String>,
+    sessions: bool,
+) -> (usize, usize) {
+    let mut credentials_revoked = 0;
+    let mut sessions_revoked = 0;
+    while let Some(credential_id) = pending.pop() {
+        // removed before its children are looked up: a delegation racing with this sees
+        // the parent gone and withdraws its child (see delegate_credential)
+        if state.temporary_credentials.remove(&credential_id).is_some() {
+            credentials_revoked += 1;
+        }
+        state.signature_attempts.remove(&credential_id);
+        if sessions {
+            sessions_revoked += state.session_issuer.revoke_credential(&credential_id);
+        }
+        // collect first: no guard into the map may be held while removing from it
+        pending.extend(
+            state
+                .temporary_credentials
+                .iter()
+                .filter(|c| c.parent.as_deref() == Some(credential_id.as_str()))
+                .map(|c| c.key().clone()),
+        );
+    }
+    (credentials_revoked, sessions_revoked)
+}
+
+// A full map under load would otherwise sweep every map on every refused insert.
+const CAPACITY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// At the cap, sweep expired entries before refusing the insert, at most once per
+// CAPACITY_SWEEP_INTERVAL across all maps.
+pub(crate) fn has_capacity<V>(state: &AppState, map: &DashMap<String, V>, cap: usize) -> bool {
+    if map.len() < cap {
+        return true;
+    }
+    let due = {
+        let mut swept = state.capacity_swept.lock().expect("sweep mutex poisoned");
+        let due = swept.is_none_or(|at| at.elapsed() >= CAPACITY_SWEEP_INTERVAL);
+        if due {
+            *swept = Some(Instant::now());
+        }
+        due
+    };
+    if due {
+        sweep_expired(state);
+    }
+    map.len() < cap
+}
+
+// The whole chain from a successful verify to a session must fit in POC_FLOW_DEADLINE_SECS,
+// however long its verification token and credential would still live on their own.
+pub(crate) fn flow_expired(state: &AppState, flow_started: Instant) -> bool {
+    state
+        .config
+        .flow_deadline
+        .is_some_and(|window| flow_started.elapsed() > window)
+}
+
+// Entries removed by one sweep.
+#[derive(Serialize)]
+pub(crate) struct SweepCounts {
+    verification_tokens: usize,
+    temporary_credentials: usize,
+    sessions: usize,
+}
+
+// Must not be called while holding a guard into any of these maps.
+pub(crate) fn sweep_expired(state: &AppState) -> SweepCounts {
+    let now = Instant::now();
+
+    let before = state.verification_tokens.len();
+    state
+        .verification_tokens
+        .retain(|_, v| v.expires_at.instant() > now);
+    let tokens = before.saturating_sub(state.verification_tokens.len());
+
+    let before = state.temporary_credentials.len();
+    state
+        .temporary_credentials
+        .retain(|_, v| v.expires_at.instant() > now);
+    let credentials = before.saturating_sub(state.temporary_credentials.len());
+
+    let sessions = state.session_issuer.sweep(now);
+    state
+        .pending_codes
+        .retain(|_, v| v.expires_at.instant() > now);
+    state
+        .pow_challenges
+        .retain(|_, expires_at| *expires_at > now);
+    state.signature_attempts.retain(|_, v| {
+        v.last_failure + SIGNATURE_LOCK_MAX > now || v.locked_until.is_some_and(|t| t > now)
+    });
+    if let Some(limiter) = &state.rate_limiter {
+        limiter.sweep(now);
+    }
+
+    // counts are approximate: inserts racing the sweep shift the before/after sizes
+    let reaped = &state.reaped;
+    reaped
+        .verification_tokens
+        .fetch_add(tokens as u64, Ordering::Relaxed);
+    reaped
+        .temporary_credentials
+        .fetch_add(credentials as u64, Ordering::Relaxed);
+    reaped
+        .sessions
+        .fetch_add(sessions as u64, Ordering::Relaxed);
+    if tokens + credentials + sessions > 0 {
+        println!(
+            "cleanup: reaped {tokens} verification tokens, {credentials} credentials, {sessions} sessions"
+        );
+        state.events.publish(Event::Expired {
+            verification_tokens: tokens,
+            temporary_credentials: credentials,
+            sessions,
+        });
+    }
+
+    SweepCounts {
+        verification_tokens: tokens,
+        temporary_credentials: credentials,
+        sessions,
+    }
+}
+
+// Sweeps every 30 seconds until `shutdown` is cancelled. A sweep is synchronous, so
+// cancellation only lands between sweeps, never halfway through one.
+pub(crate) async fn cleanup_expired_state(state: AppState, shutdown: CancellationToken) {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                sweep_expired(&state);
+            }
+            _ = shutdown.cancelled() => break,
+        }
+    }
+    // one last pass, so the reaped counters, the cleanup log line and the Expired event
+    // also account for what expired since the previous tick; the maps themselves die with
+    // the process
+    sweep_expired(&state);
+    println!("cleanup task stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{session::SessionClaims, state::random_token, testing};
+    use ed25519_dalek::SigningKey;
+    use proptest::prelude::*;
+    use rand::rngs::OsRng;
+
+    // Times in ms up to two days; the clock is moved by hand, so no case sleeps.
+    proptest! {
+        #[test]
+        fn a_deadline_is_live_until_the_clock_passes_it(
+            ttl_ms in 1u64..=86_400_000,
+            past_ms in 1u64..=86_400_000,
+        ) {
+            let now = Instant::now();
+            let ttl = Duration::from_millis(ttl_ms);
+            let deadline = Deadline::<TemporaryCredentialRecord>::at(now + ttl);
+            prop_assert!(!expired_at(deadline, now));
+            prop_assert!(!expired_at(deadline, now + ttl));
+            prop_assert_eq!(remaining_secs_at(deadline, now), ttl_ms.div_ceil(1000));
+
+            let later = now + ttl + Duration::from_millis(past_ms);
+            prop_assert!(expired_at(deadline, later));
+            prop_assert_eq!(remaining_secs_at(deadline, later), 0);
+        }
+
+        #[test]
+        fn get_if_live_drops_a_record_once_the_clock_passes_it(
+            ttl_ms in 1u64..=86_400_000,
+            elapsed_ms in 0u64..=172_800_000,
+        ) {
+            let state = testing::state();
+            let id = testing::credential(&state, &SigningKey::generate(&mut OsRng));
+            let now = Instant::now();
+            state.temporary_credentials.get_mut(&id).unwrap().expires_at =
+                Deadline::at(now + Duration::from_millis(ttl_ms));
+
+            let live = elapsed_ms <= ttl_ms;
+            let then = now + Duration::from_millis(elapsed_ms);
+            let got = get_if_live_at(&state.temporary_credentials, &id, then);
+            prop_assert_eq!(got.is_some(), live);
+            prop_assert_eq!(state.temporary_credentials.contains_key(&id), live);
+        }
+    }
+
+    #[test]
+    fn a_replayed_signature_is_refused_until_newer_ones_push_it_out() {
+        let now = Instant::now();
+        let mut cache = SignatureReplayCache::new(2);
+        assert!(cache.insert([1; 64], now));
+        assert!(!cache.insert([1; 64], now));
+        assert!(cache.insert([2; 64], now));
+        assert!(cache.insert([3; 64], now));
+        assert!(cache.insert([1; 64], now), "evicted by the cap");
+    }
+
+    #[test]
+    fn a_signature_is_forgotten_once_its_credential_would_have_expired() {
+        let now = Instant::now();
+        let mut cache = SignatureReplayCache::new(10);
+        assert!(cache.insert([1; 64], now));
+        assert!(!cache.insert([1; 64], now + TEMP_CREDENTIAL_TTL / 2));
+        assert!(cache.insert([1; 64], now + TEMP_CREDENTIAL_TTL));
+    }
+
+    #[test]
+    fn a_zero_sized_replay_cache_accepts_everything() {
+        let mut cache = SignatureReplayCache::new(0);
+        assert!(cache.insert([1; 64], Instant::now()));
+        assert!(cache.insert([1; 64], Instant::now()));
+    }
+
+    #[test]
+    fn each_deadline_comes_from_its_records_own_ttl() {
+        assert_eq!(
+            remaining_secs(deadline::<VerificationTokenRecord>()),
+            VERIFICATION_TTL.as_secs()
+        );
+        assert_eq!(
+            remaining_secs(deadline::<TemporaryCredentialRecord>()),
+            TEMP_CREDENTIAL_TTL.as_secs()
+        );
+        assert_eq!(
+            remaining_secs(deadline::<PendingCodeRecord>()),
+            PENDING_CODE_TTL.as_secs()
+        );
+    }
+
+    // One live and one expired entry in each of the reported maps.
+    fn half_expired(state: &AppState) {
+        let key = SigningKey::generate(&mut OsRng);
+        for at in [Instant::now() + VERIFICATION_TTL, Instant::now()] {
+            let token = random_token(&state.rng, 32).unwrap();
+            state.verification_tokens.insert(
+                token,
+                VerificationTokenRecord {
+                    expires_at: Deadline::at(at),
+                    username: "alice".into(),
+                    scopes: None,
+                    flow_started: Instant::now(),
+                },
+            );
+            let id = testing::credential(state, &key);
+            state.temporary_credentials.get_mut(&id).unwrap().expires_at = Deadline::at(at);
+        }
+        let claims = SessionClaims {
+            public_key: key.verifying_key(),
+            credential_id: "credential".into(),
+            csrf_token: None,
+            scopes: Vec::new(),
+        };
+        for ttl in [Duration::from_secs(60), Duration::ZERO] {
+            state.session_issuer.issue(&claims, ttl).unwrap();
+        }
+    }
+
+    #[test]
+    fn a_sweep_reports_and_accumulates_what_it_reaped() {
+        let state = testing::state();
+        let mut events = state.events.subscribe();
+        half_expired(&state);
+        std::thread::sleep(Duration::from_millis(2));
+
+        let counts = sweep_expired(&state);
+        assert_eq!(
+            (
+                counts.verification_tokens,
+                counts.temporary_credentials,
+                counts.sessions
+            ),
+            (1, 1, 1)
+        );
+        assert_eq!(state.verification_tokens.len(), 1);
+        assert_eq!(state.temporary_credentials.len(), 1);
+        assert_eq!(state.sessions.len(), 1);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::Expired {
+                verification_tokens: 1,
+                temporary_credentials: 1,
+                sessions: 1,
+            })
+        ));
+
+        // nothing left to reap: zero counts, no event, totals unchanged
+        let counts = sweep_expired(&state);
+        assert_eq!(counts.verification_tokens + counts.temporary_credentials, 0);
+        assert!(events.try_recv().is_err());
+        half_expired(&state);
+        std::thread::sleep(Duration::from_millis(2));
+        sweep_expired(&state);
+        assert_eq!(state.reaped.verification_tokens.load(Ordering::Relaxed), 2);
+        assert_eq!(
+            state.reaped.temporary_credentials.load(Ordering::Relaxed),
+            2
+        );
+        assert_eq!(state.reaped.sessions.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn a_full_map_is_swept_at_most_once_per_interval() {
+        let state = testing::state();
+        let key = SigningKey::generate(&mut OsRng);
+        let map = &state.temporary_credentials;
+        let expire = |id: &str| {
+            map.get_mut(id).unwrap().expires_at = Deadline::at(Instant::now());
+        };
+
+        expire(&testing::credential(&state, &key));
+        assert!(has_capacity(&state, map, 1), "the first check sweeps");
+        assert!(map.is_empty());
+
+        expire(&testing::credential(&state, &key));
+        assert!(!has_capacity(&state, map, 1), "too soon for another sweep");
+        assert_eq!(map.len(), 1);
+
+        *state.capacity_swept.lock().unwrap() = Instant::now().checked_sub(CAPACITY_SWEEP_INTERVAL);
+        assert!(has_capacity(&state, map, 1));
+    }
+
+    #[tokio::test]
+    async fn cancelling_the_cleanup_stops_it_after_a_final_sweep() {
+        let state = testing::state();
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(cleanup_expired_state(state.clone(), shutdown.clone()));
+        // let the first tick's sweep run, so only the final one can reap what follows
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!task.is_finished());
+
+        half_expired(&state);
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("the cleanup loop ignored cancellation")
+            .unwrap();
+        assert_eq!(state.verification_tokens.len(), 1);
+        assert_eq!(state.temporary_credentials.len(), 1);
+        assert_eq!(state.sessions.len(), 1);
+    }
+}
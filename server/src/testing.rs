@@ -0,0 +1,144 @@
+// Test helpers: an AppState without secrets or a listener, and requests driven through
+// the full router, middleware included.
+
+use crate::{
+    auth::key_id,
+    config::Config,
+    handlers::build_app,
+    session::{SessionClaims, unix_now},
+    state::{AppState, EntropySource, random_token, state_from},
+    store::{TemporaryCredentialRecord, VerificationTokenRecord, deadline},
+};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+};
+use ed25519_dalek::SigningKey;
+use serde_json::Value;
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tower::ServiceExt;
+
+// Config from an empty environment, for tests to adjust before building state.
+pub(crate) fn config() -> Config {
+    Config::from_env()
+}
+
+pub(crate) fn state_with(config: Config) -> AppState {
+    state_from(config, Arc::new(EntropySource::Os), None, None)
+}
+
+pub(crate) fn state() -> AppState {
+    state_with(config())
+}
+
+pub(crate) const ADMIN_TOKEN: &str = "test-admin-token";
+
+pub(crate) fn admin_config() -> Config {
+    let mut config = config();
+    config.admin_token = Some(ADMIN_TOKEN.into());
+    config
+}
+
+// The status and the body parsed as JSON (Null when it isn't JSON).
+pub(crate) async fn send(state: &AppState, mut req: Request) -> (StatusCode, HeaderMap, Value) {
+    req.extensions_mut()
+        .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+    let resp = build_app(state).oneshot(req).await.unwrap();
+    let status = resp.status();
+    let headers = resp.headers().clone();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (
+        status,
+        headers,
+        serde_json::from_slice(&body).unwrap_or(Value::Null),
+    )
+}
+
+pub(crate) fn post_json(path: &str, body: &Value) -> Request {
+    Request::post(path)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+// A live step 1 verification token for alice, the one `credential` records as the origin.
+pub(crate) fn verification(state: &AppState) -> String {
+    let token = String::from("verification");
+    state.verification_tokens.insert(
+        token.clone(),
+        VerificationTokenRecord {
+            expires_at: deadline::<VerificationTokenRecord>(),
+            username: "alice".into(),
+            scopes: None,
+            flow_started: Instant::now(),
+        },
+    );
+    token
+}
+
+// A live temporary credential for `key`, as step 2 would issue it; returns its id.
+pub(crate) fn credential(state: &AppState, key: &SigningKey) -> String {
+    let credential_id = random_token(&state.rng, 32).unwrap();
+    state.temporary_credentials.insert(
+        credential_id.clone(),
+        TemporaryCredentialRecord {
+            public_key: key.verifying_key(),
+            kid: key_id(&key.verifying_key()),
+            expires_at: deadline::<TemporaryCredentialRecord>(),
+            scopes: None,
+            verification_token: "verification".into(),
+            label: None,
+            username: "alice".into(),
+            flow_started: Instant::now(),
+            issued_at: unix_now(),
+            uses: 0,
+            parent: None,
+        },
+    );
+    credential_id
+}
+
+// A bearer session entered with `credential_id`, as step 3 would issue it.
+pub(crate) fn session(state: &AppState, key: &SigningKey, credential_id: &str) -> String {
+    let claims = SessionClaims {
+        public_key: key.verifying_key(),
+        credential_id: credential_id.into(),
+        csrf_token: None,
+        scopes: Vec::new(),
+    };
+    let (token, _) = state
+        .session_issuer
+        .issue(&claims, Duration::from_secs(60))
+        .unwrap();
+    token
+}
+
+// A v1 step 3 payload for `credential_id`, valid for a minute.
+pub(crate) fn enter_payload(credential_id: &str, aud: Option<&str>) -> Value {
+    let mut payload = serde_json::json!({
+        "v": 1,
+        "purpose": "enter",
+        "credential_id": credential_id,
+        "nonce": "nonce",
+        "exp": unix_now() + 60,
+    });
+    if let Some(aud) = aud {
+        payload["aud"] = aud.into();
+    }
+    payload
+}
+
+pub(crate) fn with_bearer(mut req: Request, token: &str) -> Request {
+    req.headers_mut().insert(
+        header::AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {token}")).unwrap(),
+    );
+    req
+}
@@ -0,0 +1,66 @@
+// Browser demo (POC_SERVE_UI=true): a static page at `/` that runs verify → issue →
+// sign (WebCrypto) → enter → preferences against this server's own endpoints. The
+// assets are embedded at build time. The page is same-origin with the API, so CORS
+// doesn't apply; it gets its own CSP allowing only its script, style and fetches.
+
+use crate::state::AppState;
+use axum::{
+    extract::State,
+    http::{HeaderValue, header},
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+
+pub const SERVE_UI_ENV: &str = "POC_SERVE_UI";
+
+// Replaces the API's `default-src 'none'` on these responses (see security_headers).
+pub const UI_CONTENT_SECURITY_POLICY: &str = "default-src 'none'; script-src 'self'; \
+     style-src 'self'; connect-src 'self'; base-uri 'none'; form-action 'none'; \
+     frame-ancestors 'none'";
+
+const INDEX_HTML: &str = include_str!("../ui/index.html");
+const APP_JS: &str = include_str!("../ui/app.js");
+const STYLE_CSS: &str = include_str!("../ui/style.css");
+
+fn asset(content_type: &'static str, body: String) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static(content_type)),
+            (
+                header::CONTENT_SECURITY_POLICY,
+                HeaderValue::from_static(UI_CONTENT_SECURITY_POLICY),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+fn escape_html(raw: &str) -> String {
+    raw.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// The page needs the path prefix to find the API, and the signing context to sign what
+// step 3 verifies.
+pub async fn index(State(state): State<AppState>) -> Response {
+    let config = json!({
+        "path_prefix": state.config.path_prefix,
+        "signing_context": state.config.signing_context,
+    });
+    let html = INDEX_HTML
+        .replace("{{CONFIG}}", &escape_html(&config.to_string()))
+        .replace("{{PREFIX}}", &escape_html(&state.config.path_prefix));
+    asset("text/html; charset=utf-8", html)
+}
+
+pub async fn script() -> Response {
+    asset("text/javascript; charset=utf-8", APP_JS.to_string())
+}
+
+pub async fn style() -> Response {
+    asset("text/css; charset=utf-8", STYLE_CSS.to_string())
+}
@@ -0,0 +1,168 @@
+"use strict";
+
+// Walks the staged-access flow against the server that served this page. The server
+// injects its path prefix and signing context into <meta name="poc-config">.
+
+const config = JSON.parse(document.querySelector('meta[name="poc-config"]').content);
+const PAYLOAD_VERSION = 1;
+const PAYLOAD_TTL_SECONDS = 60;
+
+const form = document.getElementById("flow");
+const statusLine = document.getElementById("status");
+const logList = document.getElementById("log");
+const requestCodeButton = document.getElementById("request-code");
+
+function base64urlEncode(bytes) {
+  let binary = "";
+  for (const b of bytes) binary += String.fromCharCode(b);
+  return btoa(binary).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+// Sorted keys, no whitespace: the form the server (and the Rust client) sign and verify.
+function canonicalJson(value) {
+  if (Array.isArray(value)) {
+    return `[${value.map(canonicalJson).join(",")}]`;
+  }
+  if (value !== null && typeof value === "object") {
+    const fields = Object.keys(value)
+      .sort()
+      .map((k) => `${JSON.stringify(k)}:${canonicalJson(value[k])}`);
+    return `{${fields.join(",")}}`;
+  }
+  return JSON.stringify(value);
+}
+
+// Step 3 signs `context || 0x00 || message` when POC_SIGNING_CONTEXT is set.
+function withSigningContext(message) {
+  const encoder = new TextEncoder();
+  const body = encoder.encode(message);
+  if (!config.signing_context) return body;
+  const context = encoder.encode(config.signing_context);
+  const out = new Uint8Array(context.length + 1 + body.length);
+  out.set(context, 0);
+  out.set(body, context.length + 1);
+  return out;
+}
+
+// The credential is requested as PEM, since WebCrypto imports Ed25519 private keys as
+// PKCS#8 but not as a bare seed.
+async function importPrivateKey(pem) {
+  const base64 = pem.replace(/-----[^-]+-----/g, "").replace(/\s+/g, "");
+  const der = Uint8Array.from(atob(base64), (c) => c.charCodeAt(0));
+  return crypto.subtle.importKey("pkcs8", der, { name: "Ed25519" }, false, ["sign"]);
+}
+
+async function sign(key, bytes) {
+  return new Uint8Array(await crypto.subtle.sign({ name: "Ed25519" }, key, bytes));
+}
+
+function log(title, data) {
+  const item = document.createElement("li");
+  const heading = document.createElement("strong");
+  heading.textContent = title;
+  const pre = document.createElement("pre");
+  pre.textContent = JSON.stringify(data, null, 2);
+  item.append(heading, pre);
+  logList.append(item);
+}
+
+function setStatus(text, isError = false) {
+  statusLine.textContent = text;
+  statusLine.classList.toggle("error", isError);
+}
+
+async function call(method, path, body, headers = {}) {
+  const init = { method, headers: { ...headers } };
+  if (body !== undefined) {
+    init.headers["Content-Type"] = "application/json";
+    init.body = typeof body === "string" ? body : JSON.stringify(body);
+  }
+  const resp = await fetch(config.path_prefix + path, init);
+  const json = await resp.json().catch(() => null);
+  log(`${method} ${path} → ${resp.status}`, json);
+  if (!resp.ok) {
+    throw new Error(json?.message ?? json?.error ?? `HTTP ${resp.status}`);
+  }
+  return json;
+}
+
+function enterPayload(credentialId, audience) {
+  const nonce = crypto.getRandomValues(new Uint8Array(16));
+  const payload = {
+    v: PAYLOAD_VERSION,
+    purpose: "enter",
+    credential_id: credentialId,
+    nonce: base64urlEncode(nonce),
+    exp: Math.floor(Date.now() / 1000) + PAYLOAD_TTL_SECONDS,
+  };
+  if (audience) payload.aud = audience;
+  return payload;
+}
+
+async function runFlow(username, code, capabilities) {
+  setStatus("1) verifying user…");
+  const verified = await call("POST", "/api/step1/verify", { username, code });
+
+  setStatus("2) issuing temporary credential…");
+  const credential = await call("POST", "/api/step2/issue-credentials", {
+    verification_token: verified.verification_token,
+    label: "browser demo",
+    format: "pem",
+  });
+  const key = await importPrivateKey(credential.credential_private);
+
+  setStatus("3) signing in the browser and entering a session…");
+  const message = canonicalJson(enterPayload(credential.credential_id, capabilities.audience));
+  const signature = await sign(key, withSigningContext(message));
+  log("signed locally", { message, signature: base64urlEncode(signature) });
+  const session = await call("POST", "/api/step3/enter", {
+    credential_id: credential.credential_id,
+    message,
+    signature: base64urlEncode(signature),
+  });
+
+  setStatus("4) sending signed preferences…");
+  const preferences = canonicalJson({ theme: "dark", notifications: true });
+  const preferencesSignature = await sign(key, new TextEncoder().encode(preferences));
+  await call("POST", "/api/user/preferences", preferences, {
+    Authorization: `Bearer ${session.session_token}`,
+    "X-Preferences-Signature": base64urlEncode(preferencesSignature),
+  });
+
+  setStatus("Flow complete ✅");
+}
+
+async function main() {
+  let capabilities;
+  try {
+    capabilities = await call("GET", "/api/capabilities");
+  } catch (e) {
+    setStatus(`Server unavailable: ${e.message}`, true);
+    return;
+  }
+  if (capabilities.auth_modes.includes("one_time_code")) {
+    requestCodeButton.hidden = false;
+    form.elements.code.value = "";
+  }
+
+  requestCodeButton.addEventListener("click", async () => {
+    try {
+      await call("POST", "/api/step1/request-code", { username: form.elements.username.value });
+      setStatus("Code sent; enter it above.");
+    } catch (e) {
+      setStatus(e.message, true);
+    }
+  });
+
+  form.addEventListener("submit", async (event) => {
+    event.preventDefault();
+    logList.replaceChildren();
+    try {
+      await runFlow(form.elements.username.value, form.elements.code.value, capabilities);
+    } catch (e) {
+      setStatus(e.message, true);
+    }
+  });
+}
+
+main();